@@ -1,4 +1,4 @@
-use crate::types::primitives::{bigint_to_fr, fr_to_bigint, u32_to_fr, Fr};
+use crate::types::primitives::{bigint_to_fr, fr_str, fr_to_bigint, u32_to_fr, Fr};
 use anyhow::Result;
 use arrayref::array_ref;
 use babyjubjub_rs::{self, decompress_point, Point, PrivateKey};
@@ -32,17 +32,22 @@ use ff::Field;
 use lazy_static::lazy_static;
 use num_bigint::BigInt;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
 /// Derault derivation path.
 /// Copied from https://github.com/gakonst/ethers-rs/blob/01cc80769c291fc80f5b1e9173b7b580ae6b6413/ethers-signers/src/wallet/mnemonic.rs#L16
 const DEFAULT_DERIVATION_PATH_PREFIX: &str = "m/44'/60'/0'/0/";
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Signature {
+    #[serde(with = "fr_str")]
     pub hash: Fr,
+    #[serde(with = "fr_str")]
     pub s: Fr,
+    #[serde(with = "fr_str")]
     pub r8x: Fr,
+    #[serde(with = "fr_str")]
     pub r8y: Fr,
 }
 
@@ -138,14 +143,39 @@ impl L2Account {
         })
     }
     pub fn verify(&self, sig: Signature) -> bool {
-        let msg = fr_to_bigint(&sig.hash);
-        let r_b8 = Point { x: sig.r8x, y: sig.r8y };
-        let sig_bjj = SignatureBJJ {
-            r_b8,
-            s: fr_to_bigint(&sig.s),
-        };
-        let sig_final = unsafe { std::mem::transmute::<SignatureBJJ, babyjubjub_rs::Signature>(sig_bjj) };
-        babyjubjub_rs::verify(self.pub_key.clone(), sig_final, msg)
+        verify_with_pub_key(self.pub_key.clone(), sig)
+    }
+}
+
+fn verify_with_pub_key(pub_key: Point, sig: Signature) -> bool {
+    let msg = fr_to_bigint(&sig.hash);
+    let r_b8 = Point { x: sig.r8x, y: sig.r8y };
+    let sig_bjj = SignatureBJJ {
+        r_b8,
+        s: fr_to_bigint(&sig.s),
+    };
+    let sig_final = unsafe { std::mem::transmute::<SignatureBJJ, babyjubjub_rs::Signature>(sig_bjj) };
+    babyjubjub_rs::verify(pub_key, sig_final, msg)
+}
+
+// recover the bjj public key from the (sign, ay) pair stored in AccountState,
+// the inverse of `Point::compress` used in `L2Account::new`
+pub fn l2_pub_key(sign: Fr, ay: Fr) -> Result<Point, String> {
+    let mut compressed: [u8; 32] = [0; 32];
+    let (_, ay_bytes) = fr_to_bigint(&ay).to_bytes_le();
+    let len = std::cmp::min(ay_bytes.len(), compressed.len());
+    compressed[..len].copy_from_slice(&ay_bytes[..len]);
+    if !sign.is_zero() {
+        compressed[31] |= 0x80;
+    }
+    decompress_point(compressed)
+}
+
+// verify a signature against an account only known by its L2 key, e.g. a tx submitted by a user
+pub fn verify_with_l2_key(sign: Fr, ay: Fr, sig: Signature) -> bool {
+    match l2_pub_key(sign, ay) {
+        Ok(pub_key) => verify_with_pub_key(pub_key, sig),
+        Err(_) => false,
     }
 }
 
@@ -414,6 +444,8 @@ mod tests {
             "2104729104368328243963691045555606467740179640947024714099030450797354625308"
         );
         assert_eq!(acc.verify(sig), true);
+        assert_eq!(verify_with_l2_key(acc.sign, acc.ay, sig), true);
+        assert_eq!(verify_with_l2_key(Fr::zero(), acc.ay, sig), false);
 
         // mnemonic => L1 account & eth addr & L2 account
         // https://github.com/Fluidex/circuits/blob/d6e06e964b9d492f1fa5513bcc2295e7081c540d/helper.ts/account_test.ts#L7
@@ -2,14 +2,20 @@
 #![allow(dead_code)]
 
 use rollup_state_manager::config;
-use rollup_state_manager::msg::{msg_loader, msg_processor};
-use rollup_state_manager::params;
-use rollup_state_manager::state::{GlobalState, WitnessGenerator};
-use rollup_state_manager::test_utils::l2::L2Block;
+use rollup_state_manager::mempool::{api, ApiCommand, Mempool, Submission};
+use rollup_state_manager::metrics;
+use rollup_state_manager::msg::dedup::DedupTracker;
+use rollup_state_manager::msg::msg_source::{ConsumedOffsets, MsgPosition, OffsetCommitter};
+use rollup_state_manager::msg::{msg_processor, msg_source, msg_utils};
+use rollup_state_manager::state::{Genesis, GlobalState, SnapshotHeader, WitnessGenerator};
+use rollup_state_manager::test_utils::l2::{L2Block, SourceId};
 use rollup_state_manager::test_utils::messages::WrappedMessage;
 use rollup_state_manager::test_utils::L2BlockSerde;
+use rollup_state_manager::types::primitives::fr_to_string;
 use sqlx::postgres::PgPool;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[tokio::main]
 async fn main() {
@@ -17,60 +23,207 @@ async fn main() {
     env_logger::init();
     log::info!("state_keeper started");
 
-    let mut conf = config_rs::Config::new();
     let config_file = dotenv::var("CONFIG").unwrap();
-    conf.merge(config_rs::File::with_name(&config_file)).unwrap();
-    let settings: config::Settings = conf.try_into().unwrap();
+    let mut settings = config::Settings::load(&config_file).unwrap();
     log::debug!("{:?}", settings);
 
-    run(&settings).await;
+    let (state, checkpoint) = match load_checkpoint(&settings).unwrap() {
+        Some((state, header)) => {
+            log::info!("resume from the checkpoint at block {}", header.block_number);
+            // the messages up to the checkpoint are already applied
+            if let Some(last_seq) = header.processed_msgs.as_ref().and_then(|processed| processed.last_seq) {
+                settings.first_msg_seq = last_seq + 1;
+            }
+            (state, Some(header))
+        }
+        None => {
+            // without a genesis file, start from an empty state
+            let genesis = genesis_file_from_args()
+                .map(|path| Genesis::load(&path).unwrap())
+                .unwrap_or_default();
+            (genesis.build_state(&settings).unwrap(), None)
+        }
+    };
+
+    run(&settings, state, checkpoint).await;
 }
 
+fn load_checkpoint(settings: &config::Settings) -> anyhow::Result<Option<(GlobalState, SnapshotHeader)>> {
+    match &settings.checkpoint_file {
+        Some(path) if std::path::Path::new(path).exists() => {
+            let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+            Ok(Some(GlobalState::import_snapshot(reader, settings.verbose)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+// written aside and renamed, so that a crash never leaves a truncated checkpoint
+fn write_checkpoint(witgen: &WitnessGenerator, processor: &msg_processor::Processor, path: &str) -> anyhow::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let writer = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+    witgen.checkpoint(processor.dedup_tracker().processed(), writer)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// the only flag is `--genesis <file>`, everything else comes from the config file
+fn genesis_file_from_args() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--genesis" {
+            return Some(args.next().expect("missing genesis file"));
+        }
+        if let Some(path) = arg.strip_prefix("--genesis=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+const IDLE_TICK: Duration = Duration::from_secs(1);
+
 fn replay_msgs(
-    msg_receiver: crossbeam_channel::Receiver<WrappedMessage>,
+    msg_receiver: crossbeam_channel::Receiver<(WrappedMessage, Option<MsgPosition>)>,
+    // commits the offsets of the messages covered by a checkpoint, for kafka
+    committer: Option<OffsetCommitter>,
+    api_receiver: crossbeam_channel::Receiver<Submission>,
     block_sender: crossbeam_channel::Sender<L2Block>,
+    // number of blocks saved by the db writer
+    saved_blocks: Arc<AtomicUsize>,
+    settings: config::Settings,
+    state: GlobalState,
+    checkpoint: Option<SnapshotHeader>,
 ) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
     Some(std::thread::spawn(move || {
-        let state = GlobalState::new(
-            *params::BALANCELEVELS,
-            *params::ORDERLEVELS,
-            *params::ACCOUNTLEVELS,
-            *params::VERBOSE,
-        );
-        let mut witgen = WitnessGenerator::new(state, *params::NTXS, block_sender, *params::VERBOSE);
+        let mut witgen = WitnessGenerator::from_settings_with_state(&settings, state, block_sender);
+        if let Some(header) = &checkpoint {
+            witgen.resume(header);
+        }
 
         println!("genesis root {}", witgen.root());
 
-        let mut processor = msg_processor::Processor::default();
+        let mut processor = msg_processor::Processor::new(settings.token_registry()?, settings.market_registry()?);
+        processor.set_reconciler(settings.reconciler()?);
+        processor.set_handle_order(settings.place_orders);
+        let processed = checkpoint.and_then(|header| header.processed_msgs).unwrap_or_default();
+        processor.set_dedup_tracker(DedupTracker::from_processed(&processed, settings.dedup_window));
+        let mut mempool = Mempool::new(
+            settings.mempool_max_pending,
+            Duration::from_millis(settings.mempool_pending_timeout_ms),
+        );
 
-        let mut current_block_num = 0;
+        let mut msg_receiver = msg_receiver;
+        let mut api_receiver = api_receiver;
+        let mut current_block_num = witgen.get_block_generate_num();
+        // set when a checkpoint is due, it is written once no txs are buffered
+        // and the db writer has saved every sealed block
+        let mut checkpoint_due = false;
+        let mut consumed = ConsumedOffsets::default();
+        let mut open_sources = 2;
         let timing = Instant::now();
-        for msg in msg_receiver.iter() {
-            match msg {
-                WrappedMessage::BALANCE(balance) => {
-                    processor.handle_balance_msg(&mut witgen, balance);
-                }
-                WrappedMessage::TRADE(trade) => {
-                    let trade_id = trade.id;
-                    processor.handle_trade_msg(&mut witgen, trade);
-                    println!("trade {} test done", trade_id);
-                }
-                WrappedMessage::ORDER(order) => {
-                    processor.handle_order_msg(&mut witgen, order);
-                }
+        while open_sources > 0 {
+            crossbeam_channel::select! {
+                recv(msg_receiver) -> msg => match msg {
+                    Ok((msg, position)) => {
+                        // handled or rejected, the message is not delivered again after a checkpoint
+                        if let Some(position) = &position {
+                            consumed.record(position);
+                        }
+                        match msg {
+                            WrappedMessage::BALANCE(balance) => {
+                                let source = msg_utils::balance_msg_id(&balance).map(SourceId::Balance);
+                                if let Err(e) = witgen.with_block_source(source, |witgen| processor.handle_balance_msg(witgen, balance)) {
+                                    log::error!("reject balance message: {}", e);
+                                }
+                            }
+                            WrappedMessage::TRADE(trade) => {
+                                let trade_id = trade.id;
+                                match witgen.with_block_source(Some(SourceId::Trade(trade_id)), |witgen| processor.handle_trade_msg(witgen, trade)) {
+                                    Ok(()) => println!("trade {} test done", trade_id),
+                                    Err(e) => log::error!("reject trade {}: {}", trade_id, e),
+                                }
+                            }
+                            WrappedMessage::ORDER(order) => {
+                                let source = Some(SourceId::Order(order.order.id));
+                                if let Err(e) = witgen.with_block_source(source, |witgen| processor.handle_order_msg(witgen, order)) {
+                                    log::error!("reject order message: {}", e);
+                                }
+                            }
+                            WrappedMessage::TOKEN(token) => {
+                                if let Err(e) = processor.handle_token_msg(&witgen, token) {
+                                    log::error!("reject token registration: {}", e);
+                                }
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        msg_receiver = crossbeam_channel::never();
+                        open_sources -= 1;
+                    }
+                },
+                recv(api_receiver) -> submission => match submission {
+                    Ok(Submission { request, result_sender }) => {
+                        let result = match request.into_command(processor.token_registry()) {
+                            Ok(ApiCommand::SubmitTx(tx)) => mempool.add(&witgen, tx),
+                            Ok(ApiCommand::ForceSeal) => {
+                                mempool.apply_ready_txs(&mut witgen);
+                                witgen.force_seal();
+                                Ok(())
+                            }
+                            Ok(ApiCommand::SolvencyReport(path)) => {
+                                let report = witgen.solvency_report();
+                                if !report.is_solvent() {
+                                    log::error!("rollup balances mismatch the ledger: {:?}", report.tokens);
+                                }
+                                report.save(&path)
+                            }
+                            Err(e) => Err(e),
+                        };
+                        // the client may have gone away, nothing to do then
+                        result_sender.send(result).ok();
+                    }
+                    Err(_) => {
+                        api_receiver = crossbeam_channel::never();
+                        open_sources -= 1;
+                    }
+                },
+                // wake up to check the seal deadline
+                default(witgen.time_to_deadline().unwrap_or(IDLE_TICK)) => {},
             }
+            mempool.apply_ready_txs(&mut witgen);
+            witgen.seal_if_due();
+            metrics::MSG_QUEUE_DEPTH.set(msg_receiver.len());
 
             let new_block_num = witgen.get_block_generate_num();
             if new_block_num > current_block_num {
                 current_block_num = new_block_num;
                 let secs = timing.elapsed().as_secs_f32();
                 println!(
-                    "generate {} blocks with block_size {} in {}s: average TPS: {}",
+                    "generate {} blocks with block_sizes {:?} in {}s: average TPS: {}",
                     current_block_num,
-                    *params::NTXS,
+                    settings.block_sizes,
                     secs,
-                    (*params::NTXS * current_block_num) as f32 / secs
+                    witgen.get_tx_generate_num() as f32 / secs
                 );
+                checkpoint_due |= current_block_num % settings.checkpoint_interval == 0;
+            }
+            let all_saved = saved_blocks.load(Ordering::SeqCst) >= current_block_num;
+            if checkpoint_due && witgen.buffered_tx_num() == 0 && all_saved {
+                if let Some(path) = &settings.checkpoint_file {
+                    match write_checkpoint(&witgen, &processor, path) {
+                        Ok(()) => {
+                            log::info!("checkpoint at block {} written", current_block_num);
+                            if let Some(committer) = &committer {
+                                if let Err(e) = committer.commit(&consumed) {
+                                    log::error!("commit offsets: {}", e);
+                                }
+                            }
+                        }
+                        Err(e) => log::error!("write checkpoint: {}", e),
+                    }
+                }
+                checkpoint_due = false;
             }
         }
 
@@ -78,38 +231,110 @@ fn replay_msgs(
     }))
 }
 
-async fn run(settings: &config::Settings) {
-    let (msg_sender, msg_receiver) = crossbeam_channel::unbounded();
-    let (blk_sender, blk_receiver) = crossbeam_channel::unbounded();
+async fn run(settings: &config::Settings, state: GlobalState, checkpoint: Option<SnapshotHeader>) {
+    let (msg_sender, msg_receiver) = crossbeam_channel::bounded(settings.msg_queue_size);
+    let (blk_sender, blk_receiver) = crossbeam_channel::bounded(settings.block_queue_size);
+    let (api_sender, api_receiver) = crossbeam_channel::unbounded();
 
-    let loader_thread = msg_loader::load_msgs_from_mq(&settings.brokers, msg_sender);
-    let replay_thread = replay_msgs(msg_receiver, blk_sender);
+    let source = msg_source::from_settings(settings).unwrap();
+    let committer = source.offset_committer();
+    let loader_thread = msg_source::spawn_source(source, msg_sender);
+    let api_thread = settings
+        .mempool_api_addr
+        .as_ref()
+        .and_then(|addr| api::run_api_server(addr, api_sender));
+    let metrics_thread = settings.metrics_addr.as_ref().and_then(|addr| metrics::run_metrics_server(addr));
+    // blocks before the checkpoint are saved already
+    let saved_blocks = Arc::new(AtomicUsize::new(
+        checkpoint.as_ref().map(|header| header.block_number as usize).unwrap_or(0),
+    ));
+    let replay_thread = replay_msgs(
+        msg_receiver,
+        committer,
+        api_receiver,
+        blk_sender,
+        saved_blocks.clone(),
+        settings.clone(),
+        state,
+        checkpoint,
+    );
 
     let db_pool = PgPool::connect(&settings.prover_cluster_db).await.unwrap();
+    create_block_table(&db_pool).await.unwrap();
     for block in blk_receiver.iter() {
+        metrics::BLOCK_QUEUE_DEPTH.set(blk_receiver.len());
+        let block_number = block.header.block_number as usize;
         save_block_to_db(&db_pool, block).await.unwrap();
+        saved_blocks.store(block_number + 1, Ordering::SeqCst);
     }
 
-    loader_thread.map(|h| h.join().expect("loader thread failed"));
+    if let Err(e) = loader_thread.join().expect("loader thread failed") {
+        log::error!("message source failed: {}", e);
+    }
+    api_thread.map(|h| h.join().expect("mempool api thread failed"));
+    metrics_thread.map(|h| h.join().expect("metrics thread failed"));
     replay_thread.map(|h| h.join().expect("loader thread failed"));
 }
 
+async fn create_block_table(pool: &PgPool) -> anyhow::Result<()> {
+    sqlx::query(
+        "create table if not exists l2_block (
+            block_number bigint primary key,
+            block_hash varchar(64) not null unique,
+            parent_hash varchar(64) not null,
+            old_root varchar(80) not null,
+            new_root varchar(80) not null,
+            created_time bigint not null,
+            header jsonb not null
+        )",
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 async fn save_block_to_db(pool: &PgPool, block: L2Block) -> anyhow::Result<()> {
+    let header = block.header.clone();
+    // the block hash is deterministic, so a replayed block maps to the same task
+    let task_id = header.hash();
     let input = L2BlockSerde::from(block);
-    let task_id = unique_task_id();
 
-    sqlx::query("insert into task (task_id, circuit, input, status) values ($1, $2, $3, $4)")
-        .bind(task_id)
-        .bind("block")
+    // a restart seals the blocks after the checkpoint again, they are saved already if the hash matches
+    let mut db_tx = pool.begin().await?;
+    let inserted = sqlx::query("insert into l2_block (block_number, block_hash, parent_hash, old_root, new_root, created_time, header) values ($1, $2, $3, $4, $5, $6, $7) on conflict (block_number) do nothing")
+        .bind(header.block_number as i64)
+        .bind(&task_id)
+        .bind(&header.parent_hash)
+        .bind(fr_to_string(&header.old_root))
+        .bind(fr_to_string(&header.new_root))
+        .bind(header.timestamp as i64)
+        .bind(sqlx::types::Json(&header))
+        .execute(&mut db_tx)
+        .await?
+        .rows_affected();
+    if inserted == 0 {
+        let stored_hash: String = sqlx::query_scalar("select block_hash from l2_block where block_number = $1")
+            .bind(header.block_number as i64)
+            .fetch_one(&mut db_tx)
+            .await?;
+        if stored_hash != task_id {
+            anyhow::bail!(
+                "block {} is saved with hash {}, but resealed with hash {}",
+                header.block_number,
+                stored_hash,
+                task_id
+            );
+        }
+        log::info!("block {} is saved already", header.block_number);
+    }
+    sqlx::query("insert into task (task_id, circuit, input, status) values ($1, $2, $3, $4) on conflict (task_id) do nothing")
+        .bind(&task_id)
+        .bind(&header.circuit)
         .bind(sqlx::types::Json(input))
         .bind("ready")
-        .fetch_one(pool)
+        .execute(&mut db_tx)
         .await?;
+    db_tx.commit().await?;
 
     Ok(())
 }
-
-fn unique_task_id() -> String {
-    let current_millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-    format!("task_{}", current_millis)
-}
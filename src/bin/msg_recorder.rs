@@ -0,0 +1,53 @@
+// Records the live kafka topics into a JSONL file, e.g. to reproduce a production incident with
+// gen_global_state_testcase.
+//   msg_recorder <output.jsonl> [max_msgs]
+// Recording stops at max_msgs, or on ctrl-c.
+use anyhow::{Context, Result};
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rollup_state_manager::config;
+use rollup_state_manager::msg::msg_recorder::{record_msgs, RawMessage};
+use std::io::BufWriter;
+use std::time::Duration;
+
+fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let output = args.next().context("usage: msg_recorder <output.jsonl> [max_msgs]")?;
+    let limit = args.next().map(|n| n.parse::<usize>()).transpose().context("invalid max_msgs")?;
+
+    let config_file = dotenv::var("CONFIG")?;
+    let settings = config::Settings::load(&config_file)?;
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&output)
+        .with_context(|| format!("open {}", output))?;
+    // messages are read raw in arrival order, with a group of our own so that no partition is
+    // taken away from the state manager. A new group starts from the earliest retained offsets.
+    let consumer: BaseConsumer = rdkafka::config::ClientConfig::new()
+        .set("bootstrap.servers", &settings.brokers)
+        .set("group.id", format!("{}_recorder", settings.kafka_group_id))
+        .set("enable.partition.eof", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()?;
+    let topics = &settings.topics;
+    consumer.subscribe(&[
+        topics.balances.as_str(),
+        topics.orders.as_str(),
+        topics.trades.as_str(),
+        topics.tokens.as_str(),
+    ])?;
+    let msgs = std::iter::from_fn(|| loop {
+        match consumer.poll(Duration::from_secs(1)) {
+            Some(Ok(msg)) => return Some(Ok(RawMessage::from_message(&msg))),
+            Some(Err(e)) => return Some(Err(anyhow::Error::from(e))),
+            None => continue,
+        }
+    });
+    let recorded = record_msgs(msgs, &settings.payload_formats, BufWriter::new(file), limit)?;
+    println!("recorded {} messages to {}", recorded, output);
+    Ok(())
+}
@@ -1,10 +1,98 @@
+use crate::msg::codec::PayloadFormats;
+use crate::msg::dead_letter::MalformedMsgPolicy;
+use crate::msg::dedup::DEFAULT_DEDUP_WINDOW;
+use crate::msg::msg_sequencer::GapPolicy;
+use crate::msg::msg_source::MsgSourceKind;
+use crate::msg::reconcile::{ReconcileMode, Reconciler};
+use crate::state::SealPolicy;
+use crate::types::market::{default_markets, MarketInfo, MarketRegistry};
+use crate::types::token::{default_tokens, TokenInfo, TokenRegistry};
+use anyhow::{anyhow, bail};
 use serde::Deserialize;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Topics {
+    pub balances: String,
+    pub orders: String,
+    pub trades: String,
+    pub tokens: String,
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Topics {
+            balances: "balances".to_string(),
+            orders: "orders".to_string(),
+            trades: "trades".to_string(),
+            tokens: "tokens".to_string(),
+        }
+    }
+}
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
 #[serde(default)]
 pub struct Settings {
     pub brokers: String,
     pub prover_cluster_db: String,
+    pub kafka_group_id: String,
+    pub topics: Topics,
+    // kafka, or a captured file / stdin to replay for debugging
+    pub msg_source: MsgSourceKind,
+    pub msg_source_file: Option<String>,
+    // json, binary or auto for the balances, orders and trades topics
+    pub payload_formats: PayloadFormats,
+    // undecodable messages go to the dead letter topic, or else the file, and are always logged
+    pub dead_letter_topic: Option<String>,
+    pub dead_letter_file: Option<String>,
+    pub malformed_msg_policy: MalformedMsgPolicy,
+    // merge the topics by the `seq` of messages, starting from `first_msg_seq`.
+    // at most `msg_reorder_window` messages wait for a missing one, later ones are held back in kafka
+    pub sequence_msgs: bool,
+    pub first_msg_seq: u64,
+    pub msg_reorder_window: usize,
+    // a gap open for `msg_gap_timeout_ms` is logged as an error, then skipped or halted on
+    pub msg_gap_timeout_ms: u64,
+    pub msg_gap_policy: GapPolicy,
+    // emit a PlaceOrder tx for each new order message, instead of placing orders with their first trade
+    pub place_orders: bool,
+    // how many trade ids and balance message ids are remembered to drop redelivered messages
+    pub dedup_window: usize,
+    // the state and the processed messages are written here every `checkpoint_interval` blocks,
+    // and the replay resumes from it on restart
+    pub checkpoint_file: Option<String>,
+    pub checkpoint_interval: usize,
+    // capacities of the queues between the loader, the replay thread and the db writer,
+    // a full queue blocks the stage before it
+    pub msg_queue_size: usize,
+    pub block_queue_size: usize,
+    // tree heights, must match the circuits
+    pub balance_levels: usize,
+    pub order_levels: usize,
+    pub account_levels: usize,
+    // each size needs its own circuit, blocks are sealed with the smallest size fitting the txs
+    pub block_sizes: Vec<usize>,
+    pub verbose: bool,
+    // the mempool api is disabled when no address is configured
+    pub mempool_api_addr: Option<String>,
+    pub mempool_max_pending: usize,
+    // txs waiting for an earlier nonce are evicted after so long
+    pub mempool_pending_timeout_ms: u64,
+    // `metrics::render` is served over http when an address is configured
+    pub metrics_addr: Option<String>,
+    // seal a block once so many txs are buffered, defaults to the block size
+    pub block_max_txs: Option<usize>,
+    // seal a non-empty block once its oldest tx has waited so long
+    pub block_max_wait_ms: Option<u64>,
+    // tokens known at startup, more can be registered by token messages
+    pub tokens: Vec<TokenInfo>,
+    pub markets: Vec<MarketInfo>,
+    // what to do when the exchange state carried by messages mismatches the rollup state
+    pub reconcile_mode: ReconcileMode,
+    // mismatches are appended here as json lines
+    pub reconcile_report_file: Option<String>,
 }
 
 impl Default for Settings {
@@ -12,6 +100,190 @@ impl Default for Settings {
         Settings {
             brokers: "127.0.0.1:9092".to_string(),
             prover_cluster_db: Default::default(),
+            kafka_group_id: "unify_msg_dumper".to_string(),
+            topics: Topics::default(),
+            msg_source: MsgSourceKind::default(),
+            msg_source_file: None,
+            payload_formats: PayloadFormats::default(),
+            dead_letter_topic: None,
+            dead_letter_file: None,
+            malformed_msg_policy: MalformedMsgPolicy::default(),
+            sequence_msgs: false,
+            first_msg_seq: 1,
+            msg_reorder_window: 1024,
+            msg_gap_timeout_ms: 60_000,
+            msg_gap_policy: GapPolicy::default(),
+            place_orders: false,
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            checkpoint_file: None,
+            checkpoint_interval: 100,
+            msg_queue_size: 4096,
+            block_queue_size: 16,
+            balance_levels: 2,
+            order_levels: 4,
+            account_levels: 4,
+            block_sizes: vec![2],
+            verbose: false,
+            mempool_api_addr: None,
+            mempool_max_pending: 64,
+            mempool_pending_timeout_ms: 60_000,
+            metrics_addr: None,
+            block_max_txs: None,
+            block_max_wait_ms: None,
+            tokens: default_tokens(),
+            markets: default_markets(),
+            reconcile_mode: ReconcileMode::default(),
+            reconcile_report_file: None,
         }
     }
 }
+
+// a tree of height 32 already has more leaves than u32 ids
+const MAX_TREE_LEVELS: usize = 32;
+
+impl Settings {
+    // config file first, then env overrides, then validation
+    pub fn load(config_file: &str) -> anyhow::Result<Self> {
+        let mut conf = config_rs::Config::new();
+        conf.merge(config_rs::File::with_name(config_file))?;
+        let mut settings: Settings = conf.try_into()?;
+        settings.apply_env_overrides()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    // defaults with env overrides, for the test scripts which have no config file.
+    // The tree heights and block sizes must be set, since they have to match the circuits under test
+    pub fn from_env() -> anyhow::Result<Self> {
+        for name in &["BALANCELEVELS", "ORDERLEVELS", "ACCOUNTLEVELS"] {
+            if std::env::var(name).is_err() {
+                bail!("{} not set in env", name);
+            }
+        }
+        if std::env::var("NTXS").is_err() && std::env::var("BLOCK_SIZES").is_err() {
+            bail!("neither NTXS nor BLOCK_SIZES set in env");
+        }
+        let mut settings = Settings::default();
+        settings.apply_env_overrides()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    // the env names are the ones used by the test scripts and circuits
+    pub fn apply_env_overrides(&mut self) -> anyhow::Result<()> {
+        if let Some(ntxs) = env_var("NTXS")? {
+            self.block_sizes = vec![ntxs];
+        }
+        if let Ok(sizes) = std::env::var("BLOCK_SIZES") {
+            self.block_sizes = sizes
+                .split(',')
+                .map(|size| size.trim().parse::<usize>().map_err(|_| anyhow!("invalid BLOCK_SIZES {}", sizes)))
+                .collect::<anyhow::Result<_>>()?;
+        }
+        if let Some(levels) = env_var("BALANCELEVELS")? {
+            self.balance_levels = levels;
+        }
+        if let Some(levels) = env_var("ORDERLEVELS")? {
+            self.order_levels = levels;
+        }
+        if let Some(levels) = env_var("ACCOUNTLEVELS")? {
+            self.account_levels = levels;
+        }
+        if let Some(verbose) = env_var("VERBOSE")? {
+            self.verbose = verbose;
+        }
+        if let Ok(brokers) = std::env::var("BROKERS") {
+            self.brokers = brokers;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, levels) in &[
+            ("balance_levels", self.balance_levels),
+            ("order_levels", self.order_levels),
+            ("account_levels", self.account_levels),
+        ] {
+            if *levels == 0 || *levels > MAX_TREE_LEVELS {
+                bail!("{} {} out of range", name, levels);
+            }
+        }
+        if self.block_sizes.is_empty() || self.block_sizes.contains(&0) {
+            bail!("invalid block sizes {:?}", self.block_sizes);
+        }
+        if self.msg_queue_size == 0 || self.block_queue_size == 0 || self.msg_reorder_window == 0 {
+            bail!("queue sizes must be positive");
+        }
+        if self.msg_source == MsgSourceKind::File && self.msg_source_file.is_none() {
+            bail!("msg_source_file is required for the file source");
+        }
+        if self.dedup_window == 0 || self.checkpoint_interval == 0 {
+            bail!("dedup_window and checkpoint_interval must be positive");
+        }
+        if let Some(max_txs) = self.block_max_txs {
+            if max_txs == 0 || max_txs > self.max_block_size() {
+                bail!("block_max_txs {} out of range", max_txs);
+            }
+        }
+        for token in &self.tokens {
+            if token.token_id as u64 >= 1u64 << self.balance_levels {
+                bail!("token {} exceeds the balance tree", token.name);
+            }
+        }
+        self.market_registry()?.check_tokens(&self.token_registry()?)?;
+        Ok(())
+    }
+
+    pub fn max_block_size(&self) -> usize {
+        self.block_sizes.iter().cloned().max().unwrap_or(0)
+    }
+    pub fn seal_policy(&self) -> SealPolicy {
+        SealPolicy {
+            max_txs: self.block_max_txs.unwrap_or_else(|| self.max_block_size()),
+            max_wait: self.block_max_wait_ms.map(Duration::from_millis),
+        }
+    }
+    pub fn token_registry(&self) -> anyhow::Result<TokenRegistry> {
+        TokenRegistry::from_tokens(self.tokens.clone())
+    }
+    pub fn market_registry(&self) -> anyhow::Result<MarketRegistry> {
+        MarketRegistry::from_markets(self.markets.clone())
+    }
+    pub fn reconciler(&self) -> anyhow::Result<Reconciler> {
+        Reconciler::new(self.reconcile_mode, self.reconcile_report_file.as_deref())
+    }
+}
+
+fn env_var<T: FromStr>(name: &str) -> anyhow::Result<Option<T>> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<T>()
+            .map(Some)
+            .map_err(|_| anyhow!("invalid {} {}", name, value)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_validate_settings() {
+    let settings = Settings::default();
+    settings.validate().unwrap();
+    assert_eq!(settings.seal_policy().max_txs, 2);
+
+    let mut settings = Settings {
+        block_sizes: vec![2, 8],
+        block_max_txs: Some(4),
+        ..Default::default()
+    };
+    settings.validate().unwrap();
+    settings.block_max_txs = Some(16);
+    assert!(settings.validate().is_err());
+
+    let settings = Settings {
+        order_levels: 0,
+        ..Default::default()
+    };
+    assert!(settings.validate().is_err());
+}
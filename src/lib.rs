@@ -4,8 +4,9 @@
 #![allow(dead_code)]
 pub mod account;
 pub mod config;
+pub mod mempool;
+pub mod metrics;
 pub mod msg;
-pub mod params;
 pub mod state;
 pub mod test_utils;
 pub mod types;
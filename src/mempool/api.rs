@@ -0,0 +1,188 @@
+// a minimal line based JSON API: each line of the request stream is an `ApiRequest`,
+// and each line of the response stream is a `ApiResponse`
+use crate::account::Signature;
+use crate::types::fixnum::decimal_to_amount;
+use crate::types::l2::{AmountType, L2Tx, TransferTx, WithdrawTx};
+use crate::types::primitives::{fr_str, u64_to_fr, Fr};
+use crate::types::token::TokenRegistry;
+use anyhow::{anyhow, bail};
+use crossbeam_channel::TrySendError;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Deserialize, Debug)]
+pub struct SignatureRequest {
+    #[serde(with = "fr_str")]
+    pub hash: Fr,
+    #[serde(with = "fr_str")]
+    pub s: Fr,
+    #[serde(with = "fr_str")]
+    pub r8x: Fr,
+    #[serde(with = "fr_str")]
+    pub r8y: Fr,
+}
+
+impl From<SignatureRequest> for Signature {
+    fn from(sig: SignatureRequest) -> Self {
+        Signature {
+            hash: sig.hash,
+            s: sig.s,
+            r8x: sig.r8x,
+            r8y: sig.r8y,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TransferRequest {
+    pub from: u32,
+    pub to: u32,
+    pub token_id: u32,
+    pub amount: Decimal,
+    pub nonce: u64,
+    pub sig: SignatureRequest,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WithdrawRequest {
+    pub account_id: u32,
+    pub token_id: u32,
+    pub amount: Decimal,
+    pub nonce: u64,
+    // the balance is signed together with the tx
+    #[serde(with = "fr_str")]
+    pub old_balance: Fr,
+    pub sig: SignatureRequest,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum ApiRequest {
+    Transfer(TransferRequest),
+    Withdraw(WithdrawRequest),
+    // seal the buffered txs into a block right now
+    ForceSeal,
+    // write a solvency report of the current state to a local file
+    SolvencyReport { path: String },
+}
+
+impl ApiRequest {
+    // amounts are resolved with the token registry owned by the replay thread
+    pub fn into_command(self, tokens: &TokenRegistry) -> anyhow::Result<ApiCommand> {
+        let tx = match self {
+            ApiRequest::Transfer(req) => {
+                let amount = parse_amount(&req.amount, req.token_id, tokens)?;
+                let mut tx = TransferTx::new(req.from, req.to, req.token_id, amount);
+                tx.from_nonce = u64_to_fr(req.nonce);
+                tx.sig = req.sig.into();
+                L2Tx::Transfer(tx)
+            }
+            ApiRequest::Withdraw(req) => {
+                let amount = parse_amount(&req.amount, req.token_id, tokens)?;
+                let mut tx = WithdrawTx::new(req.account_id, req.token_id, amount);
+                tx.nonce = u64_to_fr(req.nonce);
+                tx.old_balance = req.old_balance;
+                tx.sig = req.sig.into();
+                L2Tx::Withdraw(tx)
+            }
+            ApiRequest::ForceSeal => return Ok(ApiCommand::ForceSeal),
+            ApiRequest::SolvencyReport { path } => return Ok(ApiCommand::SolvencyReport(path)),
+        };
+        Ok(ApiCommand::SubmitTx(tx))
+    }
+}
+
+fn parse_amount(amount: &Decimal, token_id: u32, tokens: &TokenRegistry) -> anyhow::Result<AmountType> {
+    if amount.is_sign_negative() || amount.is_zero() {
+        bail!("invalid amount {}", amount);
+    }
+    decimal_to_amount(amount, tokens.prec(token_id)?)
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiResponse {
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<anyhow::Result<()>> for ApiResponse {
+    fn from(result: anyhow::Result<()>) -> Self {
+        match result {
+            Ok(()) => ApiResponse {
+                success: true,
+                error: None,
+            },
+            Err(e) => ApiResponse {
+                success: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+pub enum ApiCommand {
+    SubmitTx(L2Tx),
+    ForceSeal,
+    SolvencyReport(String),
+}
+
+// a request sent to the replay thread, which owns the state and answers through `result_sender`
+pub struct Submission {
+    pub request: ApiRequest,
+    pub result_sender: tokio::sync::oneshot::Sender<anyhow::Result<()>>,
+}
+
+pub fn run_api_server(addr: &str, sender: crossbeam_channel::Sender<Submission>) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+    let addr = addr.to_owned();
+    println!("mempool api listening on {}", addr);
+    Some(std::thread::spawn(move || {
+        let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let listener = TcpListener::bind(&addr).await?;
+            loop {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {
+                        log::info!("Ctrl-c received, shutting down");
+                        break;
+                    },
+
+                    accepted = listener.accept() => {
+                        let (stream, peer) = accepted?;
+                        let sender = sender.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_connection(stream, sender).await {
+                                log::warn!("mempool api connection {} closed: {}", peer, e);
+                            }
+                        });
+                    }
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        })
+    }))
+}
+
+async fn handle_connection(stream: TcpStream, sender: crossbeam_channel::Sender<Submission>) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = ApiResponse::from(submit(&line, &sender).await);
+        let mut output = serde_json::to_vec(&response)?;
+        output.push(b'\n');
+        writer.write_all(&output).await?;
+    }
+    Ok(())
+}
+
+async fn submit(line: &str, sender: &crossbeam_channel::Sender<Submission>) -> anyhow::Result<()> {
+    let request: ApiRequest = serde_json::from_str(line)?;
+    let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+    sender.try_send(Submission { request, result_sender }).map_err(|e| match e {
+        TrySendError::Full(_) => anyhow!("mempool is full"),
+        TrySendError::Disconnected(_) => anyhow!("mempool is closed"),
+    })?;
+    result_receiver.await?
+}
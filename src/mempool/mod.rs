@@ -0,0 +1,274 @@
+// Mempool collects L2 txs submitted by users directly (not through the exchange),
+// validates them against the current state, and feeds them into the WitnessGenerator
+pub mod api;
+
+pub use api::{ApiCommand, Submission};
+
+use crate::account::{verify_with_l2_key, Signature};
+use crate::state::{PendingQueue, WitnessGenerator};
+use crate::types::amount::Amount;
+use crate::types::l2::L2Tx;
+use crate::types::primitives::{fr_to_bigint, fr_to_u64, Fr};
+use anyhow::{anyhow, bail};
+use fnv::FnvHashMap;
+use num_traits::ToPrimitive;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+struct TxSummary {
+    account_id: u32,
+    token_id: u32,
+    amount: Amount,
+    nonce: Fr,
+    hash: Fr,
+    sig: Signature,
+}
+
+impl TxSummary {
+    fn parse(tx: &L2Tx) -> anyhow::Result<Self> {
+        match tx {
+            L2Tx::Transfer(tx) => Ok(Self {
+                account_id: tx.from,
+                token_id: tx.token_id,
+                amount: tx.amount.to_amount()?,
+                nonce: tx.from_nonce,
+                hash: tx.hash(),
+                sig: tx.sig,
+            }),
+            L2Tx::Withdraw(tx) => Ok(Self {
+                account_id: tx.account_id,
+                token_id: tx.token_id,
+                amount: tx.amount.to_amount()?,
+                nonce: tx.nonce,
+                hash: tx.hash(),
+                sig: tx.sig,
+            }),
+            _ => bail!("only transfer and withdraw can be submitted by users"),
+        }
+    }
+}
+
+pub struct Mempool {
+    // validated txs in arrival order, their nonces are continuous for each account
+    ready_txs: VecDeque<L2Tx>,
+    // validated txs waiting for an earlier nonce, with their arrival time
+    pending_txs: PendingQueue<(Instant, L2Tx)>,
+    // pending txs are evicted once they waited so long
+    pending_timeout: Duration,
+    // account_id -> nonce expected by the next ready tx
+    next_nonces: FnvHashMap<u32, u64>,
+    // (account_id, token_id) -> amount spent by txs inside the mempool
+    reserved: FnvHashMap<(u32, u32), Amount>,
+}
+
+impl Mempool {
+    pub fn new(max_pending_per_account: usize, pending_timeout: Duration) -> Self {
+        Self {
+            ready_txs: VecDeque::new(),
+            pending_txs: PendingQueue::new(max_pending_per_account),
+            pending_timeout,
+            next_nonces: FnvHashMap::default(),
+            reserved: FnvHashMap::default(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.ready_txs.len() + self.pending_txs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // validate signature, nonce and balance against the current state, then queue the tx
+    pub fn add(&mut self, witgen: &WitnessGenerator, tx: L2Tx) -> anyhow::Result<()> {
+        let summary = TxSummary::parse(&tx)?;
+        let account_id = summary.account_id;
+        if !witgen.has_account(account_id) {
+            bail!("invalid account {}", account_id);
+        }
+        if summary.token_id as u64 >= 1u64 << witgen.balance_levels() {
+            bail!("invalid token {}", summary.token_id);
+        }
+        if let L2Tx::Transfer(transfer) = &tx {
+            // accounts out of the account tree have no key either. A transfer creating a keyless
+            // account would lock the funds forever, so the target must already exist
+            if !witgen.has_account(transfer.to) {
+                bail!("invalid transfer target account {}", transfer.to);
+            }
+        }
+
+        let account = witgen.get_account(account_id);
+        if summary.sig.hash != summary.hash {
+            bail!("signed hash mismatches tx hash");
+        }
+        if !verify_with_l2_key(account.sign, account.ay, summary.sig) {
+            bail!("invalid signature");
+        }
+
+        let state_nonce = fr_to_u64(&account.nonce);
+        let next_nonce = self.next_nonces.get(&account_id).cloned().unwrap_or(state_nonce).max(state_nonce);
+        let nonce = fr_to_bigint(&summary.nonce).to_u64().ok_or_else(|| anyhow!("nonce overflow"))?;
+        // pending txs below the nonce can never be applied
+        for (_, stale) in self.pending_txs.drain_below(account_id, next_nonce) {
+            self.release_tx(&stale);
+        }
+        if nonce < next_nonce {
+            bail!("nonce {} too low for account {}, expected {}", nonce, account_id, next_nonce);
+        }
+
+        let spent = self.reserved_amount(account_id, summary.token_id).checked_add(&summary.amount)?;
+        if witgen.get_token_balance(account_id, summary.token_id) < spent.to_fr() {
+            bail!("balance not enough");
+        }
+
+        if nonce == next_nonce {
+            self.ready_txs.push_back(tx);
+            let mut next_nonce = nonce + 1;
+            while let Some((_, tx)) = self.pending_txs.pop(account_id, next_nonce) {
+                self.ready_txs.push_back(tx);
+                next_nonce += 1;
+            }
+            self.next_nonces.insert(account_id, next_nonce);
+        } else {
+            self.pending_txs.push(account_id, nonce, (Instant::now(), tx))?;
+        }
+        self.reserved.insert((account_id, summary.token_id), spent);
+        Ok(())
+    }
+
+    // apply ready txs in arrival order, returns the number of txs applied
+    pub fn apply_ready_txs(&mut self, witgen: &mut WitnessGenerator) -> usize {
+        self.evict_expired();
+        let mut applied = 0;
+        while let Some(tx) = self.ready_txs.pop_front() {
+            let summary = TxSummary::parse(&tx).expect("ready txs are validated");
+            self.release(summary.account_id, summary.token_id, &summary.amount);
+            match witgen.submit_user_tx(tx) {
+                Ok(()) => applied += 1,
+                Err(e) => {
+                    log::warn!("drop mempool tx of account {}: {}", summary.account_id, e);
+                    // the later txs of the account follow the failed nonce, so they are dropped too
+                    // and the next submission starts again from the state nonce
+                    self.drop_account_txs(summary.account_id);
+                }
+            }
+        }
+        applied
+    }
+
+    fn evict_expired(&mut self) {
+        let timeout = self.pending_timeout;
+        for (_, tx) in self.pending_txs.drain_expired(|(arrival, _)| arrival.elapsed() >= timeout) {
+            log::warn!("evict mempool tx waiting longer than {:?} for an earlier nonce", timeout);
+            self.release_tx(&tx);
+        }
+    }
+    fn drop_account_txs(&mut self, account_id: u32) {
+        let (dropped, kept): (VecDeque<L2Tx>, VecDeque<L2Tx>) = self.ready_txs.drain(..).partition(|tx| {
+            TxSummary::parse(tx)
+                .map(|summary| summary.account_id == account_id)
+                .unwrap_or(false)
+        });
+        self.ready_txs = kept;
+        let pending = self.pending_txs.remove_account(account_id).into_iter().map(|(_, tx)| tx);
+        for tx in dropped.into_iter().chain(pending) {
+            self.release_tx(&tx);
+        }
+        self.next_nonces.remove(&account_id);
+    }
+
+    fn reserved_amount(&self, account_id: u32, token_id: u32) -> Amount {
+        self.reserved.get(&(account_id, token_id)).cloned().unwrap_or_else(Amount::zero)
+    }
+    fn release_tx(&mut self, tx: &L2Tx) {
+        let summary = TxSummary::parse(tx).expect("mempool txs are validated");
+        self.release(summary.account_id, summary.token_id, &summary.amount);
+    }
+    fn release(&mut self, account_id: u32, token_id: u32, amount: &Amount) {
+        let remained = self
+            .reserved_amount(account_id, token_id)
+            .checked_sub(amount)
+            .expect("reserved amount covers every tx in the mempool");
+        if remained.is_zero() {
+            self.reserved.remove(&(account_id, token_id));
+        } else {
+            self.reserved.insert((account_id, token_id), remained);
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_mempool_rejects_invalid_txs() {
+    use crate::account::Account;
+    use crate::state::witness_generator::{test_deposit, test_witgen};
+    use crate::types::fixnum::Float864;
+    use crate::types::l2::TransferTx;
+    use rust_decimal::Decimal;
+
+    let (mut witgen, _blocks) = test_witgen(2);
+    test_deposit(&mut witgen, 1, 100);
+    test_deposit(&mut witgen, 2, 100);
+    let amount = |amount: i64| Float864::from_decimal(&Decimal::new(amount, 0), 0).unwrap();
+    let account = Account::new(1);
+    let transfer = |to: u32, token_id: u32| {
+        let mut tx = TransferTx::new(1, to, token_id, amount(1));
+        tx.sig = account.sign_hash(tx.hash()).unwrap();
+        L2Tx::Transfer(tx)
+    };
+
+    let mut mempool = Mempool::new(4, Duration::from_secs(60));
+    // out of the account tree of 2 levels
+    assert!(mempool.add(&witgen, transfer(4, 0)).is_err());
+    // in range, but never created
+    assert!(mempool.add(&witgen, transfer(3, 0)).is_err());
+    // out of the balance tree of 2 levels
+    assert!(mempool.add(&witgen, transfer(2, 4)).is_err());
+    assert!(mempool.is_empty());
+    mempool.add(&witgen, transfer(2, 0)).unwrap();
+    assert_eq!(mempool.len(), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_mempool_releases_dropped_txs() {
+    use crate::account::Account;
+    use crate::state::witness_generator::{test_deposit, test_witgen};
+    use crate::types::fixnum::Float864;
+    use crate::types::l2::TransferTx;
+    use crate::types::primitives::u32_to_fr;
+    use rust_decimal::Decimal;
+
+    let (mut witgen, _blocks) = test_witgen(4);
+    test_deposit(&mut witgen, 1, 100);
+    test_deposit(&mut witgen, 2, 100);
+    let amount = |amount: i64| Float864::from_decimal(&Decimal::new(amount, 0), 0).unwrap();
+    let account = Account::new(1);
+    let transfer = |nonce: u32, value: i64| {
+        let mut tx = TransferTx::new(1, 2, 0, amount(value));
+        tx.from_nonce = u32_to_fr(nonce);
+        tx.sig = account.sign_hash(tx.hash()).unwrap();
+        L2Tx::Transfer(tx)
+    };
+
+    // an expired pending tx no longer holds its amount
+    let mut mempool = Mempool::new(4, Duration::from_secs(0));
+    mempool.add(&witgen, transfer(1, 60)).unwrap();
+    assert!(mempool.add(&witgen, transfer(0, 60)).is_err());
+    assert_eq!(mempool.apply_ready_txs(&mut witgen), 0);
+    assert!(mempool.is_empty());
+    mempool.add(&witgen, transfer(0, 60)).unwrap();
+    assert_eq!(mempool.apply_ready_txs(&mut witgen), 1);
+
+    // the nonce 1 is spent outside the mempool, so every queued tx of the account is dropped
+    let mut mempool = Mempool::new(4, Duration::from_secs(60));
+    mempool.add(&witgen, transfer(1, 10)).unwrap();
+    mempool.add(&witgen, transfer(2, 10)).unwrap();
+    mempool.add(&witgen, transfer(4, 10)).unwrap();
+    assert_eq!(mempool.len(), 3);
+    witgen.submit_user_tx(transfer(1, 5)).unwrap();
+    assert_eq!(mempool.apply_ready_txs(&mut witgen), 0);
+    assert!(mempool.is_empty());
+    assert_eq!(witgen.get_token_balance(1, 0), u32_to_fr(35));
+    mempool.add(&witgen, transfer(2, 35)).unwrap();
+    assert_eq!(mempool.apply_ready_txs(&mut witgen), 1);
+}
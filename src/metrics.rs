@@ -0,0 +1,145 @@
+// Process wide metrics, plain atomics so they are cheap enough to update on every message.
+// `render` dumps them in the prometheus text format, `run_metrics_server` serves it over http.
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+pub struct Counter {
+    name: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: AtomicU64::new(0),
+        }
+    }
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+pub struct Gauge {
+    name: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: AtomicU64::new(0),
+        }
+    }
+    pub fn set(&self, value: usize) {
+        self.value.store(value as u64, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+// kafka messages which cannot be decoded
+pub static MALFORMED_MSGS: Counter = Counter::new("rollup_malformed_msgs_total");
+// messages written to the dead letter sink
+pub static DEAD_LETTERS: Counter = Counter::new("rollup_dead_letters_total");
+
+// times the kafka consumer is paused because the message queue is full
+pub static CONSUMER_PAUSES: Counter = Counter::new("rollup_consumer_pauses_total");
+
+// redelivered messages skipped by the sequencer
+pub static DUPLICATED_MSGS: Counter = Counter::new("rollup_duplicated_msgs_total");
+// times a partition is paused because its message is ahead of the reorder window
+pub static REORDER_PAUSES: Counter = Counter::new("rollup_reorder_pauses_total");
+
+// sequence numbers missing before the first message buffered by the sequencer
+pub static MSG_GAP: Gauge = Gauge::new("rollup_msg_gap");
+// messages buffered by the sequencer until the gap is filled
+pub static SEQUENCER_BUFFERED: Gauge = Gauge::new("rollup_sequencer_buffered_msgs");
+// gaps skipped after the gap timeout
+pub static SKIPPED_GAPS: Counter = Counter::new("rollup_skipped_gaps_total");
+
+// messages waiting for the replay thread
+pub static MSG_QUEUE_DEPTH: Gauge = Gauge::new("rollup_msg_queue_depth");
+// blocks waiting for the db writer
+pub static BLOCK_QUEUE_DEPTH: Gauge = Gauge::new("rollup_block_queue_depth");
+
+static COUNTERS: [&Counter; 6] = [
+    &MALFORMED_MSGS,
+    &DEAD_LETTERS,
+    &CONSUMER_PAUSES,
+    &DUPLICATED_MSGS,
+    &REORDER_PAUSES,
+    &SKIPPED_GAPS,
+];
+static GAUGES: [&Gauge; 4] = [&MSG_QUEUE_DEPTH, &BLOCK_QUEUE_DEPTH, &MSG_GAP, &SEQUENCER_BUFFERED];
+
+pub fn render() -> String {
+    let counters = COUNTERS.iter().map(|counter| (counter.name(), counter.get()));
+    let gauges = GAUGES.iter().map(|gauge| (gauge.name(), gauge.get()));
+    counters
+        .chain(gauges)
+        .map(|(name, value)| format!("{} {}\n", name, value))
+        .collect()
+}
+
+pub fn run_metrics_server(addr: &str) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+    let addr = addr.to_owned();
+    println!("metrics listening on {}", addr);
+    Some(std::thread::spawn(move || {
+        let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let listener = TcpListener::bind(&addr).await?;
+            loop {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {
+                        log::info!("Ctrl-c received, shutting down");
+                        break;
+                    },
+
+                    accepted = listener.accept() => {
+                        let (stream, peer) = accepted?;
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_scrape(stream).await {
+                                log::debug!("metrics connection {} closed: {}", peer, e);
+                            }
+                        });
+                    }
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        })
+    }))
+}
+
+// every request gets the metrics, whatever its path
+async fn handle_scrape(stream: TcpStream) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    // skip the request head, which ends with an empty line
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+    let body = render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,94 @@
+// Payload encodings of the message topics. Besides json, producers may send a versioned bincode
+// payload: the magic bytes, the schema version as little endian u16, then the bincode body.
+// The magic is not valid utf8, so it never collides with a json payload.
+use anyhow::bail;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub const BINARY_MAGIC: [u8; 2] = [0xfe, b'M'];
+// bump on any change of the message types, old producers are rejected then
+pub const BINARY_VERSION: u16 = 1;
+const BINARY_HEADER_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    Json,
+    Binary,
+    // either one, told by the magic bytes, so that producers can migrate one by one
+    Auto,
+}
+
+impl Default for PayloadFormat {
+    fn default() -> Self {
+        PayloadFormat::Auto
+    }
+}
+
+// accepted encodings per topic, token registrations are always json
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct PayloadFormats {
+    pub balances: PayloadFormat,
+    pub orders: PayloadFormat,
+    pub trades: PayloadFormat,
+}
+
+pub fn is_binary(payload: &[u8]) -> bool {
+    payload.starts_with(&BINARY_MAGIC)
+}
+
+pub fn encode_binary<T: Serialize>(value: &T) -> anyhow::Result<Vec<u8>> {
+    let mut payload = BINARY_MAGIC.to_vec();
+    payload.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut payload, value)?;
+    Ok(payload)
+}
+
+pub fn decode<T: DeserializeOwned>(payload: &[u8], format: PayloadFormat) -> anyhow::Result<T> {
+    match (format, is_binary(payload)) {
+        (PayloadFormat::Json, true) => bail!("binary payload on a json topic"),
+        (PayloadFormat::Binary, false) => bail!("json payload on a binary topic"),
+        (_, true) => decode_binary(payload),
+        (_, false) => Ok(serde_json::from_slice(payload)?),
+    }
+}
+
+fn decode_binary<T: DeserializeOwned>(payload: &[u8]) -> anyhow::Result<T> {
+    if payload.len() < BINARY_HEADER_LEN {
+        bail!("truncated binary payload");
+    }
+    let version = u16::from_le_bytes([payload[2], payload[3]]);
+    if version != BINARY_VERSION {
+        bail!("unsupported binary payload version {}, expected {}", version, BINARY_VERSION);
+    }
+    Ok(bincode::deserialize(&payload[BINARY_HEADER_LEN..])?)
+}
+
+#[cfg(test)]
+#[test]
+fn test_payload_codec() {
+    use crate::types::matchengine::messages::BalanceMessage;
+
+    let json =
+        br#"{"timestamp": 1.5, "user_id": 3, "asset": "ETH", "business": "deposit", "change": "1.25", "balance": "2.5", "detail": ""}"#;
+    let balance: BalanceMessage = decode(json, PayloadFormat::Auto).unwrap();
+    let binary = encode_binary(&balance).unwrap();
+    assert!(is_binary(&binary));
+
+    let decoded: BalanceMessage = decode(&binary, PayloadFormat::Binary).unwrap();
+    assert_eq!(decoded.change.to_string(), "1.25");
+    assert_eq!(decoded.balance, balance.balance);
+    assert_eq!(decoded.user_id, 3);
+    assert_eq!(decoded.seq, None);
+    // json is unaffected by the binary representation of decimals
+    let from_json: BalanceMessage = serde_json::from_slice(&serde_json::to_vec(&decoded).unwrap()).unwrap();
+    assert_eq!(from_json.change, balance.change);
+
+    assert!(decode::<BalanceMessage>(&binary, PayloadFormat::Json).is_err());
+    assert!(decode::<BalanceMessage>(json, PayloadFormat::Binary).is_err());
+    let mut future = binary.clone();
+    future[2] = 2;
+    assert!(decode::<BalanceMessage>(&future, PayloadFormat::Auto).is_err());
+    assert!(decode::<BalanceMessage>(&binary[..3], PayloadFormat::Auto).is_err());
+}
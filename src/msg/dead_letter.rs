@@ -0,0 +1,121 @@
+// Kafka messages which cannot be decoded are written to a dead letter sink together with the
+// error and their position, instead of killing the ingestion.
+use crate::metrics;
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MalformedMsgPolicy {
+    // dead letter the message and consume the next one
+    Skip,
+    // dead letter the message and stop consuming, the dead letter has the offset to restart from
+    Halt,
+}
+
+impl Default for MalformedMsgPolicy {
+    fn default() -> Self {
+        MalformedMsgPolicy::Skip
+    }
+}
+
+// lines of a file source have the source name as topic and the line number as offset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    // key and payload are lossily decoded, they may not be valid utf8
+    pub key: Option<String>,
+    pub payload: Option<String>,
+    pub error: String,
+}
+
+pub enum DeadLetterSink {
+    // only logged
+    Log,
+    // appended as json lines
+    File(Mutex<BufWriter<File>>),
+    // produced to a kafka topic, keyed by the original topic
+    Topic { producer: BaseProducer, topic: String },
+}
+
+impl DeadLetterSink {
+    // a topic wins over a file if both are configured
+    pub fn new(brokers: &str, topic: Option<&str>, file: Option<&str>) -> anyhow::Result<Self> {
+        if let Some(topic) = topic {
+            let producer: BaseProducer = rdkafka::config::ClientConfig::new().set("bootstrap.servers", brokers).create()?;
+            return Ok(DeadLetterSink::Topic {
+                producer,
+                topic: topic.to_string(),
+            });
+        }
+        if let Some(path) = file {
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+            return Ok(DeadLetterSink::File(Mutex::new(BufWriter::new(file))));
+        }
+        Ok(DeadLetterSink::Log)
+    }
+
+    pub fn send(&self, letter: &DeadLetter) {
+        log::error!(
+            "dead letter from {}/{}@{}: {}",
+            letter.topic,
+            letter.partition,
+            letter.offset,
+            letter.error
+        );
+        metrics::DEAD_LETTERS.inc();
+        if let Err(e) = self.write(letter) {
+            log::error!("write dead letter: {}", e);
+        }
+    }
+
+    fn write(&self, letter: &DeadLetter) -> anyhow::Result<()> {
+        match self {
+            DeadLetterSink::Log => {}
+            DeadLetterSink::File(writer) => {
+                let mut writer = writer.lock().unwrap();
+                serde_json::to_writer(&mut *writer, letter)?;
+                writer.write_all(b"\n")?;
+                writer.flush()?;
+            }
+            DeadLetterSink::Topic { producer, topic } => {
+                let payload = serde_json::to_vec(letter)?;
+                producer
+                    .send(BaseRecord::to(topic).key(&letter.topic).payload(&payload))
+                    .map_err(|(e, _)| e)?;
+                producer.flush(Duration::from_secs(1));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_dead_letter_file() {
+    let path = std::env::temp_dir().join(format!("dead_letters_{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    let sink = DeadLetterSink::new("", None, Some(path)).unwrap();
+    let letter = DeadLetter {
+        topic: "trades".to_string(),
+        partition: 0,
+        offset: 42,
+        key: Some("trades".to_string()),
+        payload: Some("{".to_string()),
+        error: "EOF while parsing an object".to_string(),
+    };
+    let sent = metrics::DEAD_LETTERS.get();
+    sink.send(&letter);
+    assert_eq!(metrics::DEAD_LETTERS.get(), sent + 1);
+
+    let written = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let read: DeadLetter = serde_json::from_str(written.lines().next().unwrap()).unwrap();
+    assert_eq!(read, letter);
+}
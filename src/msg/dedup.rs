@@ -0,0 +1,139 @@
+// Kafka delivers at least once, so after a rebalance or a restart the same message may be
+// consumed again. The tracker remembers what has been applied to the state, so that a message
+// replayed by kafka is dropped instead of being applied twice.
+use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsgId {
+    // global sequence number, which is used whenever a message carries one
+    Seq(u64),
+    Trade(u64),
+    Balance(u64),
+}
+
+// the persisted form of a tracker, stored in the header of state checkpoints
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessedMsgs {
+    pub last_seq: Option<u64>,
+    // the most recent ids, oldest first
+    pub trade_ids: Vec<u64>,
+    pub balance_ids: Vec<u64>,
+}
+
+// the last `capacity` ids, redeliveries older than that are not detected
+struct RecentIds {
+    ids: FnvHashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl RecentIds {
+    fn new(capacity: usize) -> Self {
+        Self {
+            ids: FnvHashSet::default(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+    fn contains(&self, id: u64) -> bool {
+        self.ids.contains(&id)
+    }
+    fn insert(&mut self, id: u64) {
+        if !self.ids.insert(id) {
+            return;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.ids.remove(&oldest);
+        }
+    }
+    fn to_vec(&self) -> Vec<u64> {
+        self.order.iter().copied().collect()
+    }
+}
+
+pub const DEFAULT_DEDUP_WINDOW: usize = 100_000;
+
+pub struct DedupTracker {
+    // sequence numbers are applied in order, so the last one covers all previous ones
+    last_seq: Option<u64>,
+    trades: RecentIds,
+    balances: RecentIds,
+}
+
+impl Default for DedupTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEDUP_WINDOW)
+    }
+}
+
+impl DedupTracker {
+    // `window` is the number of trade ids and of balance ids remembered
+    pub fn new(window: usize) -> Self {
+        Self {
+            last_seq: None,
+            trades: RecentIds::new(window),
+            balances: RecentIds::new(window),
+        }
+    }
+    pub fn from_processed(processed: &ProcessedMsgs, window: usize) -> Self {
+        let mut tracker = Self::new(window);
+        tracker.last_seq = processed.last_seq;
+        processed.trade_ids.iter().for_each(|id| tracker.trades.insert(*id));
+        processed.balance_ids.iter().for_each(|id| tracker.balances.insert(*id));
+        tracker
+    }
+    pub fn processed(&self) -> ProcessedMsgs {
+        ProcessedMsgs {
+            last_seq: self.last_seq,
+            trade_ids: self.trades.to_vec(),
+            balance_ids: self.balances.to_vec(),
+        }
+    }
+    pub fn last_seq(&self) -> Option<u64> {
+        self.last_seq
+    }
+    pub fn is_processed(&self, id: MsgId) -> bool {
+        match id {
+            MsgId::Seq(seq) => self.last_seq.map_or(false, |last| seq <= last),
+            MsgId::Trade(id) => self.trades.contains(id),
+            MsgId::Balance(id) => self.balances.contains(id),
+        }
+    }
+    // should only be called once the message is applied, a rejected message may be retried
+    pub fn mark_processed(&mut self, id: MsgId) {
+        match id {
+            MsgId::Seq(seq) => self.last_seq = Some(self.last_seq.map_or(seq, |last| last.max(seq))),
+            MsgId::Trade(id) => self.trades.insert(id),
+            MsgId::Balance(id) => self.balances.insert(id),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_dedup_tracker() {
+    let mut tracker = DedupTracker::new(2);
+    for id in 1..=3 {
+        assert!(!tracker.is_processed(MsgId::Trade(id)));
+        tracker.mark_processed(MsgId::Trade(id));
+        assert!(tracker.is_processed(MsgId::Trade(id)));
+    }
+    // only the last 2 trade ids are kept
+    assert!(!tracker.is_processed(MsgId::Trade(1)));
+    assert!(!tracker.is_processed(MsgId::Balance(3)));
+    tracker.mark_processed(MsgId::Seq(7));
+    assert!(tracker.is_processed(MsgId::Seq(5)));
+    assert!(!tracker.is_processed(MsgId::Seq(8)));
+
+    let processed = tracker.processed();
+    assert_eq!(processed.trade_ids, vec![2, 3]);
+    let json = serde_json::to_string(&processed).unwrap();
+    let restored = DedupTracker::from_processed(&serde_json::from_str(&json).unwrap(), 2);
+    assert!(restored.is_processed(MsgId::Trade(3)));
+    assert!(restored.is_processed(MsgId::Seq(7)));
+    assert_eq!(restored.processed(), processed);
+}
@@ -0,0 +1,166 @@
+// An in-memory stand-in for the kafka consumer, so that `TopicHandler`s can be unit tested
+// without a broker. Messages are queued by the test and delivered in order by `run`.
+use super::msg_consumer::{DynTopicHandler, Handler, MessageMeta, TopicHandler};
+use anyhow::bail;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+struct MockMessage {
+    meta: MessageMeta,
+    payload: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub struct MockConsumer {
+    handlers: HashMap<String, Box<dyn DynTopicHandler>>,
+    queued: VecDeque<MockMessage>,
+    // next offset of each topic, all messages go to partition 0
+    offsets: HashMap<String, i64>,
+}
+
+impl MockConsumer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_topic<U: TopicHandler + 'static>(mut self, topic: &str, h: U) -> Self {
+        self.handlers.insert(topic.to_string(), Box::new(Handler::from(h)));
+        self
+    }
+    pub fn push(&mut self, topic: &str, key: Option<&[u8]>, payload: Option<&[u8]>) {
+        let offset = self.offsets.entry(topic.to_string()).or_insert(0);
+        self.queued.push_back(MockMessage {
+            meta: MessageMeta {
+                topic: topic.to_string(),
+                partition: 0,
+                offset: *offset,
+                key: key.map(<[u8]>::to_vec),
+            },
+            payload: payload.map(<[u8]>::to_vec),
+        });
+        *offset += 1;
+    }
+    pub fn push_json<T: Serialize>(&mut self, topic: &str, value: &T) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(value)?;
+        self.push(topic, None, Some(&payload));
+        Ok(())
+    }
+    // delivers the queued messages one by one like `SimpleConsumer::run_stream`, then reports
+    // the idle stream to every handler. Returns the number of delivered messages.
+    pub async fn run(&mut self) -> anyhow::Result<usize> {
+        let mut delivered = 0;
+        while let Some(msg) = self.queued.pop_front() {
+            let handler = match self.handlers.get(&msg.meta.topic) {
+                Some(handler) => handler,
+                None => bail!("message of unsubscribed topic {}", msg.meta.topic),
+            };
+            handler.dispatch(msg.payload.as_deref(), msg.meta).await;
+            delivered += 1;
+        }
+        let idle: Vec<_> = self.handlers.values().map(|handler| handler.dispatch_no_msg()).collect();
+        futures::future::join_all(idle).await;
+        Ok(delivered)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_mock_consumer() {
+    use crate::types::token::TokenInfo;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct TokenCollector {
+        tokens: Arc<Mutex<Vec<TokenInfo>>>,
+        failed_offsets: Arc<Mutex<Vec<i64>>>,
+        malformed: Arc<Mutex<Vec<(i64, Vec<u8>)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl TopicHandler for TokenCollector {
+        type DataType = TokenInfo;
+        async fn on_message(&self, token: TokenInfo, _meta: &MessageMeta) -> anyhow::Result<()> {
+            if token.decimals > 18 {
+                bail!("too many decimals {}", token.decimals);
+            }
+            self.tokens.lock().unwrap().push(token);
+            Ok(())
+        }
+        fn on_error(&self, meta: &MessageMeta, _err: anyhow::Error) {
+            self.failed_offsets.lock().unwrap().push(meta.offset);
+        }
+        fn on_malformed(&self, meta: &MessageMeta, payload: Option<&[u8]>, _err: anyhow::Error) {
+            self.malformed
+                .lock()
+                .unwrap()
+                .push((meta.offset, payload.unwrap_or_default().to_vec()));
+        }
+    }
+
+    let collector = TokenCollector::default();
+    let tokens = collector.tokens.clone();
+    let failed_offsets = collector.failed_offsets.clone();
+    let malformed = collector.malformed.clone();
+    let mut consumer = MockConsumer::new().add_topic("tokens", collector);
+    let btc = TokenInfo {
+        name: "BTC".to_string(),
+        token_id: 3,
+        decimals: 6,
+        address: String::new(),
+    };
+    consumer.push_json("tokens", &btc).unwrap();
+    consumer.push("tokens", None, Some(b"{"));
+    consumer.push("tokens", None, Some(br#"{"token_id": 4, "name": "X", "decimals": 30}"#));
+    assert_eq!(futures::executor::block_on(consumer.run()).unwrap(), 3);
+    assert_eq!(*tokens.lock().unwrap(), vec![btc]);
+    // the undecodable payload is handed over raw, the rejected token fails in `on_message`
+    assert_eq!(*malformed.lock().unwrap(), vec![(1, b"{".to_vec())]);
+    assert_eq!(*failed_offsets.lock().unwrap(), vec![2]);
+
+    consumer.push("orders", None, None);
+    assert!(futures::executor::block_on(consumer.run()).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_mock_consumer_payload_formats() {
+    use super::codec::{encode_binary, PayloadFormat};
+    use crate::types::matchengine::messages::BalanceMessage;
+    use std::sync::{Arc, Mutex};
+
+    struct BalanceCollector {
+        format: PayloadFormat,
+        changes: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl TopicHandler for BalanceCollector {
+        type DataType = BalanceMessage;
+        fn payload_format(&self) -> PayloadFormat {
+            self.format
+        }
+        async fn on_message(&self, balance: BalanceMessage, _meta: &MessageMeta) -> anyhow::Result<()> {
+            self.changes.lock().unwrap().push(balance.change.to_string());
+            Ok(())
+        }
+    }
+
+    let json: &[u8] =
+        br#"{"timestamp": 0, "user_id": 1, "asset": "ETH", "business": "deposit", "change": "1.5", "balance": "1.5", "detail": ""}"#;
+    let binary = encode_binary(&serde_json::from_slice::<BalanceMessage>(json).unwrap()).unwrap();
+    for (format, expected) in &[
+        (PayloadFormat::Auto, vec!["1.5", "1.5"]),
+        (PayloadFormat::Json, vec!["1.5"]),
+        (PayloadFormat::Binary, vec!["1.5"]),
+    ] {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let collector = BalanceCollector {
+            format: *format,
+            changes: changes.clone(),
+        };
+        let mut consumer = MockConsumer::new().add_topic("balances", collector);
+        consumer.push("balances", None, Some(json));
+        consumer.push("balances", None, Some(&binary));
+        assert_eq!(futures::executor::block_on(consumer.run()).unwrap(), 2);
+        assert_eq!(*changes.lock().unwrap(), *expected);
+    }
+}
@@ -1,4 +1,12 @@
+pub mod codec;
+pub mod dead_letter;
+pub mod dedup;
+pub mod mock_consumer;
 pub mod msg_consumer;
 pub mod msg_loader;
 pub mod msg_processor;
+pub mod msg_recorder;
+pub mod msg_sequencer;
+pub mod msg_source;
 pub mod msg_utils;
+pub mod reconcile;
@@ -13,6 +13,7 @@ use rdkafka::Message;
 // use crate::config;
 use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::Arc;
 
 pub(crate) type PinBox<T> = Pin<Box<T>>;
 
@@ -45,6 +46,7 @@ impl<C: ConsumerContext> RdConsumerExt for base_consumer::BaseConsumer<C> {
     Notice this trait is not easy to be implied (self cannot be involved
     into the return futures, that is why I abondoned the async_trait macro)
     We should provide some trait which is better understood for users
+    -- that is `TopicHandler` below, plugged in through `Handler`
 */
 pub trait MessageHandlerAsync<'c, C: RdConsumerExt>: Send {
     fn on_message(&self, msg: &BorrowedMessage<'c>, cr: &'c C::SelfType) -> PinBox<dyn futures::Future<Output = ()> + Send>;
@@ -129,6 +131,8 @@ impl<'c, C: RdConsumerExt> SimpleConsumer<'c, C> {
     }
 }
 
+use super::codec::{self, PayloadFormat};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 
 pub trait TypedMessageHandlerAsync<'c, C: RdConsumerExt>: Send {
@@ -243,3 +247,101 @@ impl<'c, C: RdConsumerExt, U: SimpleMessageHandler> MessageHandlerAsync<'c, C> f
         Box::pin(async {})
     }
 }
+
+// where a message comes from, owned so that handlers do not depend on the consumer lifetime
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageMeta {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+}
+
+impl MessageMeta {
+    pub fn from_message(msg: &BorrowedMessage<'_>) -> Self {
+        MessageMeta {
+            topic: msg.topic().to_string(),
+            partition: msg.partition(),
+            offset: msg.offset(),
+            key: msg.key().map(<[u8]>::to_vec),
+        }
+    }
+}
+
+// The handler trait to implement for a topic: plain async methods, the payload is deserialized
+// into `DataType` beforehand, and failures are returned instead of logged by each handler.
+#[async_trait::async_trait]
+pub trait TopicHandler: Send + Sync {
+    type DataType: DeserializeOwned + std::fmt::Debug + Send;
+    // the encoding accepted on the topic, see `codec::decode`
+    fn payload_format(&self) -> PayloadFormat {
+        PayloadFormat::Json
+    }
+    async fn on_message(&self, msg: Self::DataType, meta: &MessageMeta) -> Result<()>;
+    async fn on_no_msg(&self) -> Result<()> {
+        Ok(())
+    }
+    // called for failed `on_message` calls, and for undecodable payloads unless `on_malformed` is overridden
+    fn on_error(&self, meta: &MessageMeta, err: anyhow::Error) {
+        log::error!("handle message {}/{}@{}: {}", meta.topic, meta.partition, meta.offset, err);
+    }
+    // called with the raw payload which cannot be decoded into `DataType`
+    fn on_malformed(&self, meta: &MessageMeta, _payload: Option<&[u8]>, err: anyhow::Error) {
+        self.on_error(meta, err)
+    }
+}
+
+// adapts a `TopicHandler` to `SimpleConsumer`, the handler is shared with the returned futures
+pub struct Handler<U>(Arc<U>);
+
+impl<U> From<U> for Handler<U> {
+    fn from(t: U) -> Self {
+        Handler(Arc::new(t))
+    }
+}
+
+// object safe part of `Handler`, consumers dispatch raw payloads without knowing the data types
+pub(crate) trait DynTopicHandler: Send + Sync {
+    fn dispatch(&self, payload: Option<&[u8]>, meta: MessageMeta) -> PinBox<dyn futures::Future<Output = ()> + Send>;
+    fn dispatch_no_msg(&self) -> PinBox<dyn futures::Future<Output = ()> + Send>;
+}
+
+fn decode_payload<T: DeserializeOwned>(payload: Option<&[u8]>, format: PayloadFormat) -> Result<T> {
+    let payload = payload.ok_or_else(|| format_err!("empty message"))?;
+    codec::decode(payload, format).map_err(|e| format_err!("decode payload fail: {}, payload: {}", e, String::from_utf8_lossy(payload)))
+}
+
+impl<U: TopicHandler + 'static> DynTopicHandler for Handler<U> {
+    fn dispatch(&self, payload: Option<&[u8]>, meta: MessageMeta) -> PinBox<dyn futures::Future<Output = ()> + Send> {
+        let handler = self.0.clone();
+        let msg = match decode_payload::<U::DataType>(payload, handler.payload_format()) {
+            Ok(msg) => msg,
+            Err(e) => {
+                handler.on_malformed(&meta, payload, e);
+                return Box::pin(async {});
+            }
+        };
+        Box::pin(async move {
+            if let Err(e) = handler.on_message(msg, &meta).await {
+                handler.on_error(&meta, e);
+            }
+        })
+    }
+    fn dispatch_no_msg(&self) -> PinBox<dyn futures::Future<Output = ()> + Send> {
+        let handler = self.0.clone();
+        Box::pin(async move {
+            if let Err(e) = handler.on_no_msg().await {
+                log::error!("handle idle stream: {}", e);
+            }
+        })
+    }
+}
+
+impl<'c, C: RdConsumerExt, U: TopicHandler + 'static> MessageHandlerAsync<'c, C> for Handler<U> {
+    fn on_message(&self, msg: &BorrowedMessage<'c>, _cr: &'c C::SelfType) -> PinBox<dyn futures::Future<Output = ()> + Send> {
+        self.dispatch(msg.payload(), MessageMeta::from_message(msg))
+    }
+    fn on_no_msg(&self, _cr: &'c C::SelfType) -> PinBox<dyn futures::Future<Output = ()> + Send> {
+        self.dispatch_no_msg()
+    }
+}
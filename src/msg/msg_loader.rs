@@ -1,58 +1,112 @@
-use super::msg_consumer::{Simple, SimpleConsumer, SimpleMessageHandler};
-use crate::test_utils::messages::{parse_msg, WrappedMessage};
-use rdkafka::consumer::StreamConsumer;
-use rdkafka::message::{BorrowedMessage, Message};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-
-pub fn load_msgs_from_file(
-    filepath: &str,
-    sender: crossbeam_channel::Sender<WrappedMessage>,
-) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
-    let filepath = filepath.to_string();
-    println!("loading from {}", filepath);
-    Some(std::thread::spawn(move || {
-        let file = File::open(filepath)?;
-        // since
-        for l in BufReader::new(file).lines() {
-            let msg = parse_msg(l?).expect("invalid data");
-            sender.try_send(msg)?;
-        }
-        Ok(())
-    }))
-}
+use super::codec::{self, PayloadFormat, PayloadFormats};
+use super::dead_letter::{DeadLetter, DeadLetterSink, MalformedMsgPolicy};
+use super::msg_consumer::{Handler, MessageMeta, SimpleConsumer, TopicHandler};
+use super::msg_sequencer::{GapPolicy, MsgSequencer, Sequenced};
+use super::msg_source::{ConsumedOffsets, MsgPosition};
+use crate::config::Settings;
+use crate::metrics;
+use crate::test_utils::messages::WrappedMessage;
+use anyhow::{anyhow, bail};
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::{Offset, TopicPartitionList};
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
 
+// message keys, the topics themselves are configurable
 const BALANCES_TOPIC: &str = "balances";
 const ORDERS_TOPIC: &str = "orders";
 const TRADES_TOPIC: &str = "trades";
+const TOKENS_TOPIC: &str = "tokens";
 
-pub fn load_msgs_from_mq(
-    brokers: &str,
-    sender: crossbeam_channel::Sender<WrappedMessage>,
-) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
-    let brokers = brokers.to_owned();
-    Some(std::thread::spawn(move || {
+// consumes the kafka topics on its own thread, see `KafkaSource`. Offsets are only committed
+// for the messages covered by a checkpoint, which arrive through `commits`, so that a restart
+// from the checkpoint gets every message after it again
+pub(crate) fn load_msgs_from_mq(
+    settings: &Settings,
+    sender: crossbeam_channel::Sender<(WrappedMessage, MsgPosition)>,
+    mut commits: tokio::sync::mpsc::UnboundedReceiver<ConsumedOffsets>,
+) -> std::thread::JoinHandle<anyhow::Result<()>> {
+    let brokers = settings.brokers.clone();
+    let group_id = settings.kafka_group_id.clone();
+    let topics = settings.topics.clone();
+    let policy = settings.malformed_msg_policy;
+    let formats = settings.payload_formats.clone();
+    let gap_timeout = Duration::from_millis(settings.msg_gap_timeout_ms);
+    let gap_policy = settings.msg_gap_policy;
+    let sequencer = if settings.sequence_msgs {
+        Some(Mutex::new(MsgSequencer::new(settings.first_msg_seq, settings.msg_reorder_window)))
+    } else {
+        None
+    };
+    let dead_letters = DeadLetterSink::new(
+        &settings.brokers,
+        settings.dead_letter_topic.as_deref(),
+        settings.dead_letter_file.as_deref(),
+    );
+    std::thread::spawn(move || {
         let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
-        let writer = MessageWriter { sender };
+        let dead_letters = dead_letters?;
         rt.block_on(async move {
             let consumer: StreamConsumer = rdkafka::config::ClientConfig::new()
                 .set("bootstrap.servers", brokers)
-                .set("group.id", "unify_msg_dumper")
+                .set("group.id", &group_id)
                 .set("enable.partition.eof", "false")
                 .set("session.timeout.ms", "6000")
-                .set("enable.auto.commit", "true")
+                .set("enable.auto.commit", "false")
                 .set("auto.offset.reset", "earliest")
                 .create()
                 .unwrap();
 
-            let consumer = std::sync::Arc::new(consumer);
+            let consumer = Arc::new(consumer);
+            let committer = consumer.clone();
+            tokio::spawn(async move {
+                while let Some(offsets) = commits.recv().await {
+                    if let Err(e) = commit_offsets(committer.as_ref(), &offsets) {
+                        log::error!("commit offsets {:?}: {}", offsets, e);
+                    }
+                }
+            });
+            let writer = Arc::new(MessageWriter {
+                sender,
+                consumer: consumer.clone(),
+                sequencer,
+                dead_letters,
+                policy,
+                gap_timeout,
+                gap_policy,
+                held_back: Mutex::new(HashSet::new()),
+                halted: Arc::new(Notify::new()),
+            });
+            let halted = writer.halted.clone();
+            // a gap may stay open while no message arrives at all, so it is checked on a timer
+            if writer.sequencer.is_some() {
+                let writer = writer.clone();
+                tokio::spawn(async move {
+                    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+                    loop {
+                        ticks.tick().await;
+                        writer.check_gap();
+                    }
+                });
+            }
             loop {
                 let cr_main = SimpleConsumer::new(consumer.as_ref())
-                    .add_topic(BALANCES_TOPIC, Simple::from(&writer))
+                    .add_topic(
+                        &topics.balances,
+                        TopicWriter::handler(&writer, formats.balances, WrappedMessage::BALANCE),
+                    )
                     .unwrap()
-                    .add_topic(ORDERS_TOPIC, Simple::from(&writer))
+                    .add_topic(&topics.orders, TopicWriter::handler(&writer, formats.orders, WrappedMessage::ORDER))
                     .unwrap()
-                    .add_topic(TRADES_TOPIC, Simple::from(&writer))
+                    .add_topic(&topics.trades, TopicWriter::handler(&writer, formats.trades, WrappedMessage::TRADE))
+                    .unwrap()
+                    .add_topic(
+                        &topics.tokens,
+                        TopicWriter::handler(&writer, PayloadFormat::Json, WrappedMessage::TOKEN),
+                    )
                     .unwrap();
 
                 tokio::select! {
@@ -61,41 +115,337 @@ pub fn load_msgs_from_mq(
                         break;
                     },
 
+                    _ = halted.notified() => {
+                        log::error!("stop consuming after the errors above");
+                        return Err(anyhow!("kafka consumer halted"));
+                    },
+
                     err = cr_main.run_stream(|cr|cr.stream()) => {
                         log::error!("Kafka consumer error: {}", err);
                     }
                 }
             }
-        });
+            Ok(())
+        })
+    })
+}
+
+struct MessageWriter {
+    sender: crossbeam_channel::Sender<(WrappedMessage, MsgPosition)>,
+    // paused while the message queue is full
+    consumer: Arc<StreamConsumer>,
+    // restores the exchange order of messages from different topics, if enabled
+    sequencer: Option<Mutex<MsgSequencer>>,
+    dead_letters: DeadLetterSink,
+    policy: MalformedMsgPolicy,
+    // how long a gap of the sequencer may stay open, and what happens then
+    gap_timeout: Duration,
+    gap_policy: GapPolicy,
+    // partitions paused while the reorder window is full, as (topic, partition)
+    held_back: Mutex<HashSet<(String, i32)>>,
+    // notified when the consumer should stop, e.g. on a malformed message
+    halted: Arc<Notify>,
+}
+
+// the handler of one topic, the topic tells the message type
+struct TopicWriter<T> {
+    writer: Arc<MessageWriter>,
+    format: PayloadFormat,
+    wrap: fn(T) -> WrappedMessage,
+}
 
+impl<T> TopicWriter<T> {
+    fn handler(writer: &Arc<MessageWriter>, format: PayloadFormat, wrap: fn(T) -> WrappedMessage) -> Handler<Self> {
+        Handler::from(TopicWriter {
+            writer: writer.clone(),
+            format,
+            wrap,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: DeserializeOwned + std::fmt::Debug + Send + 'static> TopicHandler for TopicWriter<T> {
+    type DataType = T;
+    fn payload_format(&self) -> PayloadFormat {
+        self.format
+    }
+    async fn on_message(&self, msg: T, meta: &MessageMeta) -> anyhow::Result<()> {
+        self.writer.on_message((self.wrap)(msg), meta);
         Ok(())
-    }))
+    }
+    fn on_malformed(&self, meta: &MessageMeta, payload: Option<&[u8]>, err: anyhow::Error) {
+        let payload = payload.map(|payload| String::from_utf8_lossy(payload).into_owned());
+        self.writer.on_malformed(meta, payload, err);
+    }
 }
 
-struct MessageWriter {
-    sender: crossbeam_channel::Sender<WrappedMessage>,
+fn commit_offsets(consumer: &StreamConsumer, offsets: &ConsumedOffsets) -> anyhow::Result<()> {
+    let mut partitions = TopicPartitionList::new();
+    for (topic, partition, offset) in offsets.iter() {
+        partitions.add_partition_offset(topic, partition, Offset::Offset(offset))?;
+    }
+    consumer.commit(&partitions, CommitMode::Async)?;
+    Ok(())
 }
 
-impl SimpleMessageHandler for &MessageWriter {
-    fn on_message(&self, msg: &BorrowedMessage<'_>) {
-        let msg_type = std::str::from_utf8(msg.key().unwrap()).unwrap();
-        let msg_payload = std::str::from_utf8(msg.payload().unwrap()).unwrap();
-        let message = match msg_type {
-            BALANCES_TOPIC => {
-                let data = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::BALANCE(data)
+fn update_gap_metrics(sequencer: &MsgSequencer) {
+    let gap = sequencer.gap().map(|(start, end)| end - start).unwrap_or(0);
+    metrics::MSG_GAP.set(gap as usize);
+    metrics::SEQUENCER_BUFFERED.set(sequencer.buffered_len());
+}
+
+impl MessageWriter {
+    fn is_held_back(&self, meta: &MessageMeta) -> bool {
+        self.held_back.lock().unwrap().contains(&(meta.topic.clone(), meta.partition))
+    }
+    fn on_message(&self, message: WrappedMessage, meta: &MessageMeta) {
+        // fetched before the partition was paused, it is fetched again after resuming
+        if self.is_held_back(meta) {
+            return;
+        }
+        let position = MsgPosition::Kafka {
+            topic: meta.topic.clone(),
+            partition: meta.partition,
+            offset: meta.offset,
+        };
+        // held until the ready messages are sent, so that a skipped gap cannot overtake them
+        let mut sequencer = self.sequencer.as_ref().map(|sequencer| sequencer.lock().unwrap());
+        let sequenced = match sequencer.as_mut() {
+            Some(sequencer) => {
+                // only dead lettered without a sequence number, the raw payload is gone by now
+                let payload = match message.seq() {
+                    Some(_) => None,
+                    None => message.value().ok().map(|value| value.to_string()),
+                };
+                let sequenced = sequencer.push(message, position);
+                update_gap_metrics(sequencer);
+                sequenced.map_err(|e| (e, payload))
+            }
+            None => Ok(Sequenced::Ready(vec![(message, position)])),
+        };
+        match sequenced {
+            Ok(Sequenced::Ready(messages)) => self.send_ready(messages),
+            Ok(Sequenced::Duplicate(seq)) => {
+                metrics::DUPLICATED_MSGS.inc();
+                log::debug!(
+                    "skip duplicated message {} at {}/{}@{}",
+                    seq,
+                    meta.topic,
+                    meta.partition,
+                    meta.offset
+                );
             }
-            ORDERS_TOPIC => {
-                let data = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::ORDER(data)
+            Ok(Sequenced::WindowFull(_, position)) => {
+                if let Err(e) = self.hold_back(&position) {
+                    // the message would be lost otherwise
+                    log::error!("cannot hold back message at {}: {}", position, e);
+                    self.halted.notify_one();
+                }
             }
-            TRADES_TOPIC => {
-                let data = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::TRADE(data)
+            Err((e, payload)) => self.on_malformed(meta, payload, e),
+        }
+    }
+    fn send_ready(&self, messages: Vec<(WrappedMessage, MsgPosition)>) {
+        // the gap moved, held back messages may fit into the window now
+        if !messages.is_empty() {
+            self.resume_held_back();
+        }
+        for (message, position) in messages {
+            let at = position.to_string();
+            if let Err(e) = self.send((message, position)) {
+                log::error!("drop message at {}: {}", at, e);
             }
-            _ => unreachable!(),
+        }
+    }
+    // a missing message may never arrive, e.g. when it was lost by the exchange
+    fn check_gap(&self) {
+        let sequencer = match &self.sequencer {
+            Some(sequencer) => sequencer,
+            None => return,
         };
+        let mut sequencer = sequencer.lock().unwrap();
+        let (start, end) = match sequencer.expired_gap(self.gap_timeout) {
+            Some(gap) => gap,
+            None => return,
+        };
+        log::error!(
+            "messages {}..{} missing for more than {:?}, {} messages buffered",
+            start,
+            end,
+            self.gap_timeout,
+            sequencer.buffered_len()
+        );
+        if self.gap_policy == GapPolicy::Halt {
+            self.halted.notify_one();
+            return;
+        }
+        metrics::SKIPPED_GAPS.inc();
+        let skipped = sequencer.skip_gap();
+        update_gap_metrics(&sequencer);
+        self.send_ready(skipped);
+    }
+    fn on_malformed(&self, meta: &MessageMeta, payload: Option<String>, e: anyhow::Error) {
+        // dead lettered once it is fetched again
+        if self.is_held_back(meta) {
+            return;
+        }
+        metrics::MALFORMED_MSGS.inc();
+        self.dead_letters.send(&DeadLetter {
+            topic: meta.topic.clone(),
+            partition: meta.partition,
+            offset: meta.offset,
+            key: meta.key.as_ref().map(|key| String::from_utf8_lossy(key).into_owned()),
+            payload,
+            error: e.to_string(),
+        });
+        if self.policy == MalformedMsgPolicy::Halt {
+            self.halted.notify_one();
+        }
+    }
+    // pauses the partition of a message ahead of the reorder window and rewinds it to the message,
+    // so that it is fetched again once the gap is filled. The partition holding the missing
+    // messages is never paused, since messages of a partition come in sequence order
+    fn hold_back(&self, position: &MsgPosition) -> anyhow::Result<()> {
+        let (topic, partition, offset) = match position {
+            MsgPosition::Kafka { topic, partition, offset } => (topic, *partition, *offset),
+            MsgPosition::Line(_) => bail!("not a kafka message"),
+        };
+        metrics::REORDER_PAUSES.inc();
+        log::debug!("reorder window full, hold back {}/{} from offset {}", topic, partition, offset);
+        self.held_back.lock().unwrap().insert((topic.clone(), partition));
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition(topic, partition);
+        self.consumer.pause(&partitions)?;
+        self.consumer
+            .seek(topic, partition, Offset::Offset(offset), Duration::from_secs(1))?;
+        Ok(())
+    }
+    fn resume_held_back(&self) {
+        let held_back: Vec<(String, i32)> = self.held_back.lock().unwrap().drain().collect();
+        if held_back.is_empty() {
+            return;
+        }
+        let mut partitions = TopicPartitionList::new();
+        for (topic, partition) in &held_back {
+            partitions.add_partition(topic, *partition);
+        }
+        if let Err(e) = self.consumer.resume(&partitions) {
+            log::error!("cannot resume partitions {:?}: {}", held_back, e);
+            self.halted.notify_one();
+        }
+    }
+    // when the queue is full, stop fetching from kafka until the replay thread catches up,
+    // so that neither the queue nor the consumer prefetch grows without bound. The wait runs in
+    // `block_in_place`, so that other tasks of the runtime move off this thread meanwhile
+    fn send(&self, message: (WrappedMessage, MsgPosition)) -> anyhow::Result<()> {
+        let message = match self.sender.try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(crossbeam_channel::TrySendError::Full(message)) => message,
+            Err(crossbeam_channel::TrySendError::Disconnected(_)) => bail!("message receiver closed"),
+        };
+        metrics::CONSUMER_PAUSES.inc();
+        let assignment = self.consumer.assignment()?;
+        self.consumer.pause(&assignment)?;
+        log::debug!("message queue full, consumer paused");
+        let sent = tokio::task::block_in_place(|| self.sender.send(message));
+        // held back partitions stay paused until the reorder gap moves
+        let held_back = self.held_back.lock().unwrap();
+        let mut resumed = TopicPartitionList::new();
+        for elem in assignment.elements() {
+            if !held_back.contains(&(elem.topic().to_string(), elem.partition())) {
+                resumed.add_partition(elem.topic(), elem.partition());
+            }
+        }
+        self.consumer.resume(&resumed)?;
+        sent.map_err(|_| anyhow!("message receiver closed"))
+    }
+}
+
+// the key tells the message type, the payload is its json or binary encoding
+pub fn decode_message(key: Option<&[u8]>, payload: Option<&[u8]>, formats: &PayloadFormats) -> anyhow::Result<WrappedMessage> {
+    let msg_type = std::str::from_utf8(key.ok_or_else(|| anyhow!("message without key"))?)?;
+    let payload = payload.ok_or_else(|| anyhow!("message without payload"))?;
+    let message = match msg_type {
+        BALANCES_TOPIC => WrappedMessage::BALANCE(codec::decode(payload, formats.balances)?),
+        ORDERS_TOPIC => WrappedMessage::ORDER(codec::decode(payload, formats.orders)?),
+        TRADES_TOPIC => WrappedMessage::TRADE(codec::decode(payload, formats.trades)?),
+        TOKENS_TOPIC => WrappedMessage::TOKEN(codec::decode(payload, PayloadFormat::Json)?),
+        other => bail!("unknown message key {}", other),
+    };
+    Ok(message)
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode_message() {
+    let formats = PayloadFormats::default();
+    let token: &[u8] = br#"{"token_id": 3, "name": "BTC", "decimals": 6}"#;
+    let key: &[u8] = b"tokens";
+    assert!(matches!(
+        decode_message(Some(key), Some(token), &formats).unwrap(),
+        WrappedMessage::TOKEN(_)
+    ));
+    assert!(decode_message(None, Some(token), &formats).is_err());
+    assert!(decode_message(Some(key), None, &formats).is_err());
+    assert!(decode_message(Some(key), Some(b"{"), &formats).is_err());
+    assert!(decode_message(Some(b"unknown"), Some(token), &formats).is_err());
+    assert!(decode_message(Some(&[0xff]), Some(token), &formats).is_err());
+
+    let balance: &[u8] =
+        br#"{"timestamp": 0, "user_id": 1, "asset": "ETH", "business": "deposit", "change": "1", "balance": "1", "detail": ""}"#;
+    let balance: crate::types::matchengine::messages::BalanceMessage = serde_json::from_slice(balance).unwrap();
+    let binary = codec::encode_binary(&balance).unwrap();
+    assert!(matches!(
+        decode_message(Some(b"balances"), Some(&binary), &formats).unwrap(),
+        WrappedMessage::BALANCE(_)
+    ));
+    let json_only = PayloadFormats {
+        balances: PayloadFormat::Json,
+        ..Default::default()
+    };
+    assert!(decode_message(Some(b"balances"), Some(&binary), &json_only).is_err());
+}
 
-        self.sender.try_send(message).unwrap();
+#[cfg(test)]
+#[test]
+fn test_decode_binary_topics() {
+    use crate::test_utils::messages::sample_msgs;
+
+    let binary_only = PayloadFormats {
+        balances: PayloadFormat::Binary,
+        orders: PayloadFormat::Binary,
+        trades: PayloadFormat::Binary,
+    };
+    let json_only = PayloadFormats {
+        balances: PayloadFormat::Json,
+        orders: PayloadFormat::Json,
+        trades: PayloadFormat::Json,
+    };
+    let mut topics = 0;
+    for msg in sample_msgs() {
+        let (key, binary) = match &msg {
+            WrappedMessage::BALANCE(balance) => (BALANCES_TOPIC, codec::encode_binary(balance).unwrap()),
+            WrappedMessage::ORDER(order) => (ORDERS_TOPIC, codec::encode_binary(order).unwrap()),
+            WrappedMessage::TRADE(trade) => (TRADES_TOPIC, codec::encode_binary(trade).unwrap()),
+            WrappedMessage::TOKEN(_) => continue,
+        };
+        topics += 1;
+        let json = serde_json::to_vec(&msg.value().unwrap()).unwrap();
+        for (payload, formats) in &[(&binary, &binary_only), (&binary, &PayloadFormats::default()), (&json, &json_only)] {
+            let decoded = decode_message(Some(key.as_bytes()), Some(payload.as_slice()), formats).unwrap();
+            assert_eq!(decoded.type_name(), msg.type_name());
+            assert_eq!(decoded.value().unwrap(), msg.value().unwrap());
+        }
+        assert!(decode_message(Some(key.as_bytes()), Some(&binary), &json_only).is_err());
+        assert!(decode_message(Some(key.as_bytes()), Some(&json), &binary_only).is_err());
+        // payloads of other schema versions are rejected
+        for version in &[0u16, codec::BINARY_VERSION + 1] {
+            let mut versioned = binary.clone();
+            versioned[2..4].copy_from_slice(&version.to_le_bytes());
+            let err = decode_message(Some(key.as_bytes()), Some(&versioned), &binary_only).unwrap_err();
+            assert!(err.to_string().starts_with("unsupported binary payload version"));
+        }
     }
+    assert_eq!(topics, 3);
 }
@@ -1,17 +1,23 @@
 use crate::account::{Account, Signature};
 use crate::state::WitnessGenerator;
-use crate::test_utils::types::{get_token_id_by_name, prec_token_id};
 use crate::types::l2::{self, OrderInput, OrderSide};
-use crate::types::primitives::{u32_to_fr, Fr};
+use crate::types::market::MarketRegistry;
+use crate::types::primitives::{fr_to_decimal, u32_to_fr, Fr};
+use crate::types::token::{TokenInfo, TokenRegistry};
 use crate::types::{fixnum, matchengine::messages};
+use anyhow::{anyhow, bail};
 use num::Zero;
 use rust_decimal::Decimal;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::time::Instant;
 
+use super::dedup::{DedupTracker, MsgId};
 use super::msg_utils::{
-    assert_balance_state, assert_order_state, exchange_order_to_rollup_order, trade_to_order_state, TokenIdPair, TokenPair,
+    balance_dedup_ids, balance_msg_id, diff_balance_state, diff_order_state, exchange_order_to_rollup_order, order_totals, trade_dedup_ids,
+    trade_to_order_state, TokenIdPair,
 };
+use super::reconcile::{Mismatch, Reconciler};
 
 // Preprocessor is used to attach order_sig for each order
 // it is only useful in development system
@@ -20,6 +26,12 @@ pub struct Processor {
     trade_tx_total_time: f32,
     balance_tx_total_time: f32,
     accounts: HashMap<u32, Account>,
+    tokens: TokenRegistry,
+    markets: MarketRegistry,
+    // handles mismatches between the exchange state and the rollup state
+    reconciler: Reconciler,
+    // messages already applied, redelivered ones are dropped
+    dedup: DedupTracker,
 
     // (order_hash, bjj_key) -> sig
     // only useful in debug mode
@@ -35,19 +47,51 @@ pub struct Processor {
 
 impl Default for Processor {
     fn default() -> Self {
+        Self::new(TokenRegistry::default(), MarketRegistry::default())
+    }
+}
+
+impl Processor {
+    pub fn new(tokens: TokenRegistry, markets: MarketRegistry) -> Self {
         Processor {
             trade_tx_total_time: 0.0,
             balance_tx_total_time: 0.0,
             accounts: Default::default(),
+            tokens,
+            markets,
+            reconciler: Reconciler::default(),
+            dedup: DedupTracker::default(),
             order_sig_cache: Default::default(),
             order_cache: Default::default(),
             enable_check_order_sig: false,
             enable_handle_order: false,
         }
     }
-}
-
-impl Processor {
+    pub fn set_handle_order(&mut self, enable: bool) {
+        self.enable_handle_order = enable;
+    }
+    pub fn token_registry(&self) -> &TokenRegistry {
+        &self.tokens
+    }
+    pub fn set_reconciler(&mut self, reconciler: Reconciler) {
+        self.reconciler = reconciler;
+    }
+    pub fn reconciler(&self) -> &Reconciler {
+        &self.reconciler
+    }
+    // e.g. restored from a state checkpoint
+    pub fn set_dedup_tracker(&mut self, dedup: DedupTracker) {
+        self.dedup = dedup;
+    }
+    pub fn dedup_tracker(&self) -> &DedupTracker {
+        &self.dedup
+    }
+    fn is_duplicate(&self, ids: &[MsgId]) -> bool {
+        ids.iter().any(|id| self.dedup.is_processed(*id))
+    }
+    fn mark_processed(&mut self, ids: &[MsgId]) {
+        ids.iter().for_each(|id| self.dedup.mark_processed(*id));
+    }
     pub fn take_bench(&mut self) -> (f32, f32) {
         let ret = (self.trade_tx_total_time, self.balance_tx_total_time);
         self.trade_tx_total_time = 0.0;
@@ -59,82 +103,142 @@ impl Processor {
         //println!("set account {} {}", account_id, account. bjj_pub_key());
         self.accounts.insert(account_id, account);
     }
-    pub fn handle_balance_msg(&mut self, witgen: &mut WitnessGenerator, deposit: messages::BalanceMessage) {
-        assert!(!deposit.change.is_sign_negative(), "only support deposit now");
-        let token_id = get_token_id_by_name(&deposit.asset);
+    // the same check as for the tokens of the config, before the token can reach the balance tree
+    pub fn handle_token_msg(&mut self, witgen: &WitnessGenerator, token: TokenInfo) -> anyhow::Result<()> {
+        if token.token_id as u64 >= 1u64 << witgen.balance_levels() {
+            bail!("token {} exceeds the balance tree", token.name);
+        }
+        log::info!("register token {:?}", token);
+        self.tokens.register(token)
+    }
+    pub fn handle_balance_msg(&mut self, witgen: &mut WitnessGenerator, deposit: messages::BalanceMessage) -> anyhow::Result<()> {
+        let dedup_ids = balance_dedup_ids(&deposit);
+        if self.is_duplicate(&dedup_ids) {
+            log::warn!("drop duplicated balance message {:?}", dedup_ids);
+            return Ok(());
+        }
+        let token_id = self.tokens.token_id(&deposit.asset)?;
+        let prec = self.tokens.prec(token_id)?;
+        let balance_before = deposit.balance - deposit.change;
+        if balance_before.is_sign_negative() {
+            bail!("invalid balance {:?}", deposit);
+        }
+        // the ledger follows the exchange, whether or not the change can be applied to the state
+        let change = fixnum::decimal_to_amount(&deposit.change.abs(), prec)?.to_fr();
+        if deposit.change.is_sign_negative() {
+            witgen.ledger_mut().record_withdraw(token_id, &change);
+            bail!(
+                "withdraw of {} {} by account {} is not supported",
+                deposit.change,
+                deposit.asset,
+                deposit.user_id
+            );
+        }
+        witgen.ledger_mut().record_deposit(token_id, &change);
+        let amount = fixnum::Float864::from_decimal(&deposit.change, prec)?;
         let account_id = deposit.user_id;
         let is_old = witgen.has_account(account_id);
-        let account = self.accounts.entry(account_id).or_insert_with(|| Account::new(account_id));
-
-        let balance_before = deposit.balance - deposit.change;
-        assert!(!balance_before.is_sign_negative(), "invalid balance {:?}", deposit);
 
-        let expected_balance_before = witgen.get_token_balance(deposit.user_id, token_id);
-        assert_eq!(
-            expected_balance_before,
-            fixnum::decimal_to_amount(&balance_before, prec_token_id(token_id)).to_fr()
-        );
+        let local_balance_before = witgen.get_token_balance(deposit.user_id, token_id);
+        if local_balance_before != fixnum::decimal_to_amount(&balance_before, prec)?.to_fr() {
+            self.record_mismatches(
+                witgen,
+                vec![Mismatch {
+                    msg_id: balance_msg_id(&deposit),
+                    account_id,
+                    token_id: Some(token_id),
+                    order_id: None,
+                    expected: balance_before.to_string(),
+                    actual: fr_to_decimal(&local_balance_before, prec).to_string(),
+                }],
+            );
+        }
 
         let timing = Instant::now();
-
-        let amount = fixnum::decimal_to_amount(&deposit.change, prec_token_id(token_id));
+        let account = self.accounts.entry(account_id).or_insert_with(|| Account::new(account_id));
         if is_old {
-            witgen
-                .deposit(l2::DepositTx {
-                    token_id,
-                    account_id,
-                    amount,
-                    l2key: None,
-                })
-                .unwrap();
+            witgen.deposit(l2::DepositTx {
+                token_id,
+                account_id,
+                amount,
+                l2key: None,
+            })?;
         } else {
-            witgen
-                .deposit(l2::DepositTx {
-                    token_id,
-                    account_id,
-                    amount,
-                    l2key: Some(l2::L2Key {
-                        eth_addr: account.eth_addr(),
-                        sign: account.sign(),
-                        ay: account.ay(),
-                    }),
-                })
-                .unwrap();
+            witgen.deposit(l2::DepositTx {
+                token_id,
+                account_id,
+                amount,
+                l2key: Some(l2::L2Key {
+                    eth_addr: account.eth_addr(),
+                    sign: account.sign(),
+                    ay: account.ay(),
+                }),
+            })?;
         }
 
+        self.mark_processed(&dedup_ids);
         self.balance_tx_total_time += timing.elapsed().as_secs_f32();
+        Ok(())
     }
 
-    pub fn handle_order_msg(&mut self, _witgen: &mut WitnessGenerator, order: messages::OrderMessage) {
+    // with order handling enabled, a new order is placed into the order tree right away,
+    // otherwise it is reconstructed from its first trade
+    pub fn handle_order_msg(&mut self, witgen: &mut WitnessGenerator, order: messages::OrderMessage) -> anyhow::Result<()> {
+        // order events share the order id, so only the sequence number can tell a duplicate
+        let dedup_ids: Vec<MsgId> = order.seq.map(MsgId::Seq).into_iter().collect();
+        if self.is_duplicate(&dedup_ids) {
+            log::warn!("drop duplicated order message {:?}", dedup_ids);
+            return Ok(());
+        }
         if !self.enable_handle_order {
             // in this case, we will reconstruct order from trade state
-            return;
+            self.mark_processed(&dedup_ids);
+            return Ok(());
         }
         match order.event {
             messages::OrderEventType::FINISH => {
                 self.order_cache.remove(&(order.order.user, order.order.id as u32));
             }
             messages::OrderEventType::PUT => {
+                let (account_id, order_id) = (order.order.user, order.order.id as u32);
                 let is_new_order = order.order.finished_base == Decimal::zero() && order.order.finished_quote == Decimal::zero();
-                debug_assert!(is_new_order);
-                let order_input = Self::parse_order_from_msg(&order);
-                self.cache_order(&order_input);
+                // a taker filled on arrival is put after its first trades, which placed it already
+                if !is_new_order || witgen.has_order(account_id, order_id) {
+                    log::debug!("skip put of order {} of account {}, placed already", order_id, account_id);
+                } else {
+                    if !witgen.has_account(account_id) {
+                        bail!("order {} of unknown account {}", order_id, account_id);
+                    }
+                    let order_input = self.parse_order_from_msg(&order)?;
+                    let order_input = self.cache_order(witgen, &order_input)?;
+                    witgen.place_order(l2::order::Order::from_order_input(&order_input))?;
+                }
             }
             _ => {
                 log::debug!("skip order msg {:?}", order.event);
             }
         }
+        self.mark_processed(&dedup_ids);
+        Ok(())
     }
-    pub fn handle_trade_msg(&mut self, witgen: &mut WitnessGenerator, trade: messages::TradeMessage) {
-        self.check_state(witgen, &trade.state_before, &trade);
+    // the trade is validated before any state change, so a rejected trade leaves the state untouched
+    pub fn handle_trade_msg(&mut self, witgen: &mut WitnessGenerator, trade: messages::TradeMessage) -> anyhow::Result<()> {
+        let dedup_ids = trade_dedup_ids(&trade);
+        if self.is_duplicate(&dedup_ids) {
+            log::warn!("drop duplicated trade message {:?}", dedup_ids);
+            return Ok(());
+        }
+        let spot_trade = self.trade_into_spot_tx(&trade)?;
+        self.check_state(witgen, &trade.state_before, &trade)?;
 
         let timing = Instant::now();
         let mut taker_order: Option<l2::Order> = None;
         let mut maker_order: Option<l2::Order> = None;
-        if let Some(ask_order) = &trade.ask_order {
-            let mut order = exchange_order_to_rollup_order(&ask_order);
-            self.check_order_sig(&mut order);
-            assert!(!witgen.has_order(order.account_id, order.order_id));
+        // orders placed by their order message are already in the order tree
+        let is_new = |order: &&messages::Order| !witgen.has_order(order.user, order.id as u32);
+        if let Some(ask_order) = trade.ask_order.as_ref().filter(is_new) {
+            let mut order = exchange_order_to_rollup_order(&ask_order, &self.markets, &self.tokens)?;
+            self.check_order_sig(witgen, &mut order)?;
             let ask_order = l2::order::Order::from_order_input(&order);
             match trade.ask_role {
                 messages::MarketRole::MAKER => {
@@ -145,10 +249,9 @@ impl Processor {
                 }
             };
         }
-        if let Some(bid_order) = &trade.bid_order {
-            let mut bid_order = exchange_order_to_rollup_order(&bid_order);
-            self.check_order_sig(&mut bid_order);
-            assert!(!witgen.has_order(bid_order.account_id, bid_order.order_id));
+        if let Some(bid_order) = trade.bid_order.as_ref().filter(is_new) {
+            let mut bid_order = exchange_order_to_rollup_order(&bid_order, &self.markets, &self.tokens)?;
+            self.check_order_sig(witgen, &mut bid_order)?;
             let bid_order = l2::order::Order::from_order_input(&bid_order);
             match trade.bid_role {
                 messages::MarketRole::MAKER => {
@@ -160,27 +263,43 @@ impl Processor {
             };
         }
         let tx = l2::FullSpotTradeTx {
-            trade: self.trade_into_spot_tx(&trade),
+            trade: spot_trade,
             taker_order,
             maker_order,
         };
-        witgen.full_spot_trade(tx);
+        witgen.full_spot_trade(tx)?;
+        self.mark_processed(&dedup_ids);
         self.trade_tx_total_time += timing.elapsed().as_secs_f32();
-        self.check_state(witgen, &trade.state_after, &trade);
+        self.check_state(witgen, &trade.state_after, &trade)?;
+        Ok(())
     }
 
-    fn trade_into_spot_tx(&self, trade: &messages::TradeMessage) -> l2::SpotTradeTx {
+    fn trade_into_spot_tx(&self, trade: &messages::TradeMessage) -> anyhow::Result<l2::SpotTradeTx> {
         //allow information can be obtained from trade
-        let id_pair = TokenIdPair::from(TokenPair::from(trade.market.as_str()));
+        let market = self.markets.get(&trade.market)?;
+        if trade.base != market.base || trade.quote != market.quote {
+            bail!(
+                "trade {} of {}_{} mismatches market {}",
+                trade.id,
+                trade.base,
+                trade.quote,
+                trade.market
+            );
+        }
+        market.check_amount(&trade.amount)?;
+        market.check_price(&trade.price)?;
+        let id_pair = TokenIdPair::from_market(market, &self.tokens)?;
+        let base_amount = fixnum::Float864::from_decimal(&trade.amount, self.tokens.prec(id_pair.0)?)?;
+        let quote_amount = fixnum::Float864::from_decimal(&trade.quote_amount, self.tokens.prec(id_pair.1)?)?;
 
-        match trade.ask_role {
+        let tx = match trade.ask_role {
             messages::MarketRole::MAKER => l2::SpotTradeTx {
                 order1_account_id: trade.ask_user_id,
                 order2_account_id: trade.bid_user_id,
                 token_id_1to2: id_pair.0,
                 token_id_2to1: id_pair.1,
-                amount_1to2: fixnum::decimal_to_amount(&trade.amount, prec_token_id(id_pair.0)),
-                amount_2to1: fixnum::decimal_to_amount(&trade.quote_amount, prec_token_id(id_pair.1)),
+                amount_1to2: base_amount,
+                amount_2to1: quote_amount,
                 order1_id: trade.ask_order_id as u32,
                 order2_id: trade.bid_order_id as u32,
             },
@@ -189,57 +308,83 @@ impl Processor {
                 order2_account_id: trade.ask_user_id,
                 token_id_1to2: id_pair.1,
                 token_id_2to1: id_pair.0,
-                amount_1to2: fixnum::decimal_to_amount(&trade.quote_amount, prec_token_id(id_pair.1)),
-                amount_2to1: fixnum::decimal_to_amount(&trade.amount, prec_token_id(id_pair.0)),
+                amount_1to2: quote_amount,
+                amount_2to1: base_amount,
                 order1_id: trade.bid_order_id as u32,
                 order2_id: trade.ask_order_id as u32,
             },
-        }
+        };
+        Ok(tx)
     }
-    fn parse_order_from_msg(order_msg: &messages::OrderMessage) -> OrderInput {
+    fn parse_order_from_msg(&self, order_msg: &messages::OrderMessage) -> anyhow::Result<OrderInput> {
         let order: &messages::Order = &order_msg.order;
-        let base_token_id = get_token_id_by_name(&order_msg.base);
-        let quote_token_id = get_token_id_by_name(&order_msg.quote);
-        let base_amount = order.amount;
-        assert_ne!(order.price, Decimal::zero());
-        let quote_amount = order.amount * order.price;
+        let market = self.markets.get(&order.market)?;
+        if order_msg.base != market.base || order_msg.quote != market.quote {
+            bail!(
+                "order {} of {}_{} mismatches market {}",
+                order.id,
+                order_msg.base,
+                order_msg.quote,
+                order.market
+            );
+        }
+        market.check_order_amount(&order.amount)?;
+        let TokenIdPair(base_token_id, quote_token_id) = TokenIdPair::from_market(market, &self.tokens)?;
+        let (total_sell, total_buy) = order_totals(order, market, &self.tokens)?;
         let is_ask = matches!(order.side, messages::OrderSide::ASK);
         let (tokensell, tokenbuy) = if is_ask {
             (base_token_id, quote_token_id)
         } else {
             (quote_token_id, base_token_id)
         };
-        let (total_sell, total_buy) = if is_ask {
-            (base_amount, quote_amount)
-        } else {
-            (quote_amount, base_amount)
-        };
 
-        OrderInput {
+        Ok(OrderInput {
             order_id: order.id as u32,
             token_sell: u32_to_fr(tokensell),
             token_buy: u32_to_fr(tokenbuy),
-            total_sell: fixnum::decimal_to_amount(&total_sell, prec_token_id(tokensell)).to_fr(),
-            total_buy: fixnum::decimal_to_amount(&total_buy, prec_token_id(tokenbuy)).to_fr(),
+            total_sell,
+            total_buy,
             sig: Signature::default(),
             account_id: order.user,
             side: if is_ask { OrderSide::Sell } else { OrderSide::Buy },
-        }
+        })
     }
-    fn check_order_sig(&mut self, order_to_put: &mut OrderInput) {
+    fn check_order_sig(&mut self, witgen: &WitnessGenerator, order_to_put: &mut OrderInput) -> anyhow::Result<()> {
         if self.enable_check_order_sig {
             // TODO: if order has no sig, deny
-        } else {
-            // if order has no sig, auto fill a sig
-            let order_hash = order_to_put.hash();
-            let account = self.accounts.get(&order_to_put.account_id).unwrap();
-            //println!("hash {} {} {} {}", account_id, order_state.order_id, order_hash, account.bjj_pub_key());
-            let sig = *self.order_sig_cache.entry((order_hash, account.bjj_pub_key())).or_insert_with(|| {
-                //println!("sign order");
-                account.sign_hash(order_hash).unwrap()
-            });
-            order_to_put.sig = sig;
+            return Ok(());
+        }
+        // if order has no sig, auto fill a sig. The state holds the L2 key of the account, which may
+        // come from a genesis file or a checkpoint instead of a deposit seen by this processor
+        let account_id = order_to_put.account_id;
+        if !witgen.has_account(account_id) {
+            bail!("order {} of unknown account {}", order_to_put.order_id, account_id);
         }
+        let l2_key = witgen.get_account(account_id);
+        let account = self.accounts.entry(account_id).or_insert_with(|| Account::new(account_id));
+        if account.sign() != l2_key.sign || account.ay() != l2_key.ay {
+            bail!("no signing key matching the L2 key of account {}", account_id);
+        }
+        self.sign_order(order_to_put)
+    }
+    // signs with the local key of the account
+    fn sign_order(&mut self, order_to_put: &mut OrderInput) -> anyhow::Result<()> {
+        let order_hash = order_to_put.hash();
+        let account = self
+            .accounts
+            .get(&order_to_put.account_id)
+            .ok_or_else(|| anyhow!("no signing key of account {}", order_to_put.account_id))?;
+        let cache_key = (order_hash, account.bjj_pub_key());
+        let sig = match self.order_sig_cache.get(&cache_key) {
+            Some(sig) => *sig,
+            None => {
+                let sig = account.sign_hash(order_hash).map_err(|e| anyhow!(e))?;
+                self.order_sig_cache.insert(cache_key, sig);
+                sig
+            }
+        };
+        order_to_put.sig = sig;
+        Ok(())
     }
 
     //fn check_global_state_knows_order(&self, witgen: &mut WitnessGenerator, account_id: u32, order_id: u32) {
@@ -250,25 +395,351 @@ impl Processor {
     //        witgen.update_order_state(order.account_id, order);
     //    }
     //}
-    fn cache_order(&mut self, order_input: &OrderInput) {
+    // returns the signed order
+    fn cache_order(&mut self, witgen: &WitnessGenerator, order_input: &OrderInput) -> anyhow::Result<OrderInput> {
         let mut order_input = *order_input;
-        self.check_order_sig(&mut order_input);
+        self.check_order_sig(witgen, &mut order_input)?;
         self.order_cache.insert((order_input.account_id, order_input.order_id), order_input);
         //println!("store order {} {}", order_input.account_id, order_input.order_id);
+        Ok(order_input)
     }
-    fn check_state(&self, witgen: &WitnessGenerator, trade_state: &Option<messages::VerboseTradeState>, trade: &messages::TradeMessage) {
-        let token_pair = TokenPair::from(trade.market.as_str());
-        let id_pair = TokenIdPair::from(token_pair);
+    // with ReconcileMode::RecordAndHalt, sealing stops before the tx of the mismatching message is applied
+    fn record_mismatches(&mut self, witgen: &mut WitnessGenerator, mismatches: Vec<Mismatch>) {
+        for mismatch in mismatches {
+            self.reconciler.record(mismatch);
+        }
+        if self.reconciler.should_halt_sealing() {
+            witgen.halt_sealing();
+        }
+    }
+    fn check_state(
+        &mut self,
+        witgen: &mut WitnessGenerator,
+        trade_state: &Option<messages::VerboseTradeState>,
+        trade: &messages::TradeMessage,
+    ) -> anyhow::Result<()> {
+        let id_pair = TokenIdPair::from_market(self.markets.get(&trade.market)?, &self.tokens)?;
         if let Some(state) = trade_state {
-            assert_balance_state(&state.balance, witgen, trade.bid_user_id, trade.ask_user_id, id_pair);
-            let (ask_order_state, bid_order_state) = trade_to_order_state(&state, &trade);
-            assert_order_state(witgen, ask_order_state);
-            assert_order_state(witgen, bid_order_state);
+            let mut mismatches = diff_balance_state(
+                &state.balance,
+                witgen,
+                trade.bid_user_id,
+                trade.ask_user_id,
+                id_pair,
+                &self.tokens,
+                trade.id,
+            )?;
+            let (ask_order_state, bid_order_state) = trade_to_order_state(&state, &trade, &self.markets, &self.tokens)?;
+            mismatches.extend(diff_order_state(witgen, ask_order_state, trade.id)?);
+            mismatches.extend(diff_order_state(witgen, bid_order_state, trade.id)?);
+            self.record_mismatches(witgen, mismatches);
         }
+        Ok(())
     }
-    pub fn sign_orders(&mut self, trade: messages::TradeMessage) {
-        let (ask, bid) = trade_to_order_state(&trade.state_before.clone().unwrap(), &trade);
-        self.check_order_sig(&mut OrderInput::from(ask));
-        self.check_order_sig(&mut OrderInput::from(bid));
+    // warms up the signature cache with the keys set by `set_account`
+    pub fn sign_orders(&mut self, trade: messages::TradeMessage) -> anyhow::Result<()> {
+        let state = trade
+            .state_before
+            .as_ref()
+            .ok_or_else(|| anyhow!("trade {} without state", trade.id))?;
+        let (ask, bid) = trade_to_order_state(state, &trade, &self.markets, &self.tokens)?;
+        self.sign_order(&mut OrderInput::try_from(ask)?)?;
+        self.sign_order(&mut OrderInput::try_from(bid)?)
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_handle_token_msg() {
+    use crate::state::witness_generator::test_witgen;
+
+    let (witgen, _blocks) = test_witgen(2);
+    let mut processor = Processor::default();
+    let token = |token_id: u32| TokenInfo {
+        name: format!("T{}", token_id),
+        token_id,
+        decimals: 6,
+        address: String::new(),
+    };
+    // out of the balance tree of 2 levels
+    assert!(processor.handle_token_msg(&witgen, token(4)).is_err());
+    processor.handle_token_msg(&witgen, token(3)).unwrap();
+    assert_eq!(processor.token_registry().token_id("T3").unwrap(), 3);
+}
+
+// a deposit message, with a message id in its detail if `msg_id` is set
+#[cfg(test)]
+fn test_deposit_msg(user_id: u32, asset: &str, change: &str, balance: &str, msg_id: Option<u64>) -> messages::BalanceMessage {
+    let detail = msg_id.map(|id| format!(r#"{{"id": {}}}"#, id)).unwrap_or_default();
+    serde_json::from_value(serde_json::json!({
+        "timestamp": 0.0, "user_id": user_id, "asset": asset, "business": "deposit",
+        "change": change, "balance": balance, "detail": detail,
+    }))
+    .unwrap()
+}
+
+// 1 ETH at 100 USDT between two new limit orders with the id of the trade, the bid is the taker
+#[cfg(test)]
+fn test_trade(id: u64, ask_user: u32, bid_user: u32) -> messages::TradeMessage {
+    let order = |side: &str, user: u32| {
+        serde_json::json!({
+            "id": id, "market": "ETH_USDT", "type": "LIMIT", "side": side, "user": user,
+            "create_time": 0.0, "update_time": 0.0, "price": "100", "amount": "1",
+            "taker_fee": "0", "maker_fee": "0", "remain": "1", "frozen": "0",
+            "finished_base": "0", "finished_quote": "0", "finished_fee": "0",
+        })
+    };
+    serde_json::from_value(serde_json::json!({
+        "id": id, "timestamp": 0.0, "market": "ETH_USDT", "base": "ETH", "quote": "USDT",
+        "price": "100", "amount": "1", "quote_amount": "100",
+        "ask_user_id": ask_user, "ask_order_id": id, "ask_role": "MAKER", "ask_fee": "0",
+        "bid_user_id": bid_user, "bid_order_id": id, "bid_role": "TAKER", "bid_fee": "0",
+        "ask_order": order("ASK", ask_user), "bid_order": order("BID", bid_user),
+        "state_before": null, "state_after": null,
+    }))
+    .unwrap()
+}
+
+#[cfg(test)]
+#[test]
+fn test_skip_put_of_placed_orders() {
+    use crate::state::witness_generator::test_witgen;
+
+    let put = |seq: u64, id: u64, user: u32, finished_base: &str| -> messages::OrderMessage {
+        serde_json::from_value(serde_json::json!({
+            "seq": seq, "event": "PUT", "base": "ETH", "quote": "USDT",
+            "order": {
+                "id": id, "market": "ETH_USDT", "type": "LIMIT", "side": "BID", "user": user,
+                "create_time": 0.0, "update_time": 0.0, "price": "100", "amount": "2",
+                "taker_fee": "0", "maker_fee": "0", "remain": "1", "frozen": "0",
+                "finished_base": finished_base, "finished_quote": "0", "finished_fee": "0",
+            },
+        }))
+        .unwrap()
+    };
+    let (mut witgen, _blocks) = test_witgen(4);
+    let mut processor = Processor::default();
+    processor.set_handle_order(true);
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "10", "10", Some(1)))
+        .unwrap();
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(2, "USDT", "1000", "1000", Some(2)))
+        .unwrap();
+    // the taker bid is placed with its first trade, then put with the filled part
+    processor.handle_trade_msg(&mut witgen, test_trade(1, 1, 2)).unwrap();
+    let root = witgen.root();
+    processor.handle_order_msg(&mut witgen, put(3, 1, 2, "1")).unwrap();
+    processor.handle_order_msg(&mut witgen, put(4, 2, 2, "1")).unwrap();
+    assert_eq!(witgen.root(), root);
+    assert!(!witgen.has_order(2, 2));
+    assert!(processor.dedup_tracker().is_processed(MsgId::Seq(4)));
+    // an order already in the tree is not placed twice
+    processor.handle_order_msg(&mut witgen, put(5, 3, 2, "0")).unwrap();
+    assert!(witgen.has_order(2, 3));
+    let root = witgen.root();
+    processor.handle_order_msg(&mut witgen, put(6, 3, 2, "0")).unwrap();
+    assert_eq!(witgen.root(), root);
+}
+
+#[cfg(test)]
+#[test]
+fn test_trade_market_orders() {
+    use crate::state::witness_generator::test_witgen;
+    use crate::types::primitives::u64_to_fr;
+
+    let order = |id: u64, type_: &str, side: &str, user: u32, price: &str, frozen: &str| {
+        serde_json::json!({
+            "id": id, "market": "ETH_USDT", "type": type_, "side": side, "user": user,
+            "create_time": 0.0, "update_time": 0.0, "price": price, "amount": "2",
+            "taker_fee": "0", "maker_fee": "0", "remain": "2", "frozen": frozen,
+            "finished_base": "0", "finished_quote": "0", "finished_fee": "0",
+        })
+    };
+    let trade = |id: u64, price: &str, quote_amount: &str, ask: serde_json::Value, bid: serde_json::Value| -> messages::TradeMessage {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "timestamp": 0.0, "market": "ETH_USDT", "base": "ETH", "quote": "USDT",
+            "price": price, "amount": "1", "quote_amount": quote_amount,
+            "ask_user_id": ask["user"], "ask_order_id": ask["id"], "ask_role": if ask["type"] == "MARKET" { "TAKER" } else { "MAKER" }, "ask_fee": "0",
+            "bid_user_id": bid["user"], "bid_order_id": bid["id"], "bid_role": if bid["type"] == "MARKET" { "TAKER" } else { "MAKER" }, "bid_fee": "0",
+            "ask_order": ask, "bid_order": bid,
+            "state_before": null, "state_after": null,
+        }))
+        .unwrap()
+    };
+    let (mut witgen, _blocks) = test_witgen(4);
+    let mut processor = Processor::default();
+    for (msg_id, (user, asset)) in [(1, "ETH"), (2, "USDT"), (3, "ETH"), (3, "USDT")].iter().enumerate() {
+        processor
+            .handle_balance_msg(&mut witgen, test_deposit_msg(*user, asset, "1000", "1000", Some(msg_id as u64)))
+            .unwrap();
+    }
+
+    // a market bid freezing 250 USDT for 2 ETH sweeps asks at 100 and 120, below its bound of 125
+    let market_bid = order(3, "MARKET", "BID", 2, "0", "250");
+    let asks = [order(1, "LIMIT", "ASK", 1, "100", "2"), order(2, "LIMIT", "ASK", 3, "120", "2")];
+    processor
+        .handle_trade_msg(&mut witgen, trade(1, "100", "100", asks[0].clone(), market_bid.clone()))
+        .unwrap();
+    processor
+        .handle_trade_msg(&mut witgen, trade(2, "120", "120", asks[1].clone(), market_bid.clone()))
+        .unwrap();
+    let bid = witgen.get_account_order_by_id(2, 3);
+    assert_eq!((bid.filled_sell, bid.filled_buy), (u64_to_fr(220_000_000), u64_to_fr(2_000_000)));
+    assert!(bid.is_filled());
+    // the bid of user 3 cannot pay more than its bound of 100
+    let poor_bid = order(4, "MARKET", "BID", 3, "0", "200");
+    let root = witgen.root();
+    assert!(processor
+        .handle_trade_msg(&mut witgen, trade(3, "120", "120", asks[1].clone(), poor_bid))
+        .is_err());
+    assert_eq!(witgen.root(), root);
+
+    // a market ask sells at any price down to one tick
+    let market_ask = order(5, "MARKET", "ASK", 3, "0", "2");
+    let bid = order(6, "LIMIT", "BID", 2, "0.01", "0.02");
+    processor
+        .handle_trade_msg(&mut witgen, trade(4, "0.01", "0.01", market_ask, bid))
+        .unwrap();
+    assert_eq!(witgen.get_account_order_by_id(3, 5).filled_buy, u64_to_fr(10_000));
+    assert!(witgen.solvency_report().is_solvent());
+}
+
+#[cfg(test)]
+#[test]
+fn test_trade_of_genesis_accounts() {
+    use crate::config::Settings;
+    use crate::state::genesis::{Genesis, GenesisAccount, GenesisBalance};
+    use crate::types::primitives::u64_to_fr;
+
+    // keys of account 3 are not derived from its id, so the processor cannot sign for it
+    let genesis_account = |account_id: u32, key_id: u32, token_id: u32, balance: u64| {
+        let key = Account::new(key_id);
+        GenesisAccount {
+            account_id,
+            sign: key.sign(),
+            ay: key.ay(),
+            eth_addr: key.eth_addr(),
+            nonce: 0,
+            balances: vec![GenesisBalance {
+                token_id,
+                balance: u64_to_fr(balance),
+            }],
+            orders: Vec::new(),
+        }
+    };
+    let genesis = Genesis {
+        root: None,
+        accounts: vec![
+            genesis_account(1, 1, 0, 10_000_000),
+            genesis_account(2, 2, 1, 1_000_000_000),
+            genesis_account(3, 7, 1, 1_000_000_000),
+        ],
+    };
+    let (block_sender, _block_receiver) = crossbeam_channel::unbounded();
+    let state = genesis.build_state(&Settings::default()).unwrap();
+    let mut witgen = WitnessGenerator::new(state, 2, block_sender, false);
+    let mut processor = Processor::default();
+    // the genesis balances open the ledger
+    assert!(witgen.solvency_report().is_solvent());
+
+    processor.handle_trade_msg(&mut witgen, test_trade(1, 1, 2)).unwrap();
+    assert!(witgen.has_order(1, 1) && witgen.has_order(2, 1));
+    assert!(witgen.solvency_report().is_solvent());
+    // no signing key for account 3, no account 4 at all
+    assert!(processor.handle_trade_msg(&mut witgen, test_trade(2, 1, 3)).is_err());
+    assert!(processor.handle_trade_msg(&mut witgen, test_trade(3, 1, 4)).is_err());
+    assert!(!witgen.has_order(1, 2) && !witgen.has_order(1, 3));
+}
+
+#[cfg(test)]
+#[test]
+fn test_halt_before_mismatching_tx() {
+    use super::reconcile::ReconcileMode;
+    use crate::state::witness_generator::test_witgen;
+
+    let (mut witgen, block_receiver) = test_witgen(1);
+    let mut processor = Processor::default();
+    processor.set_reconciler(Reconciler::new(ReconcileMode::RecordAndHalt, None).unwrap());
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "1", "1", None))
+        .unwrap();
+    assert!(block_receiver.try_recv().is_ok());
+    // the exchange has 1 ETH more than the rollup, so the deposit is never published
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "1", "3", None))
+        .unwrap();
+    assert!(witgen.is_sealing_halted());
+    assert!(block_receiver.try_recv().is_err());
+    assert_eq!(witgen.get_block_generate_num(), 1);
+    assert_eq!(witgen.withheld_tx_num(), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_checkpoint_drops_redelivered_msgs() {
+    use crate::state::witness_generator::test_witgen;
+    use crate::state::GlobalState;
+
+    let (mut witgen, _blocks) = test_witgen(1);
+    let mut processor = Processor::default();
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "10", "10", Some(1)))
+        .unwrap();
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(2, "USDT", "1000", "1000", Some(2)))
+        .unwrap();
+    processor.handle_trade_msg(&mut witgen, test_trade(1, 1, 2)).unwrap();
+    // the ledger holds the deposits of the messages
+    assert!(witgen.solvency_report().is_solvent());
+
+    let mut checkpoint = Vec::new();
+    witgen.checkpoint(processor.dedup_tracker().processed(), &mut checkpoint).unwrap();
+    let (state, header) = GlobalState::import_snapshot(checkpoint.as_slice(), false).unwrap();
+    let processed = header.processed_msgs.clone().unwrap();
+    let (block_sender, _block_receiver) = crossbeam_channel::unbounded();
+    let mut witgen = WitnessGenerator::new(state, 1, block_sender, false);
+    witgen.resume(&header);
+    let mut processor = Processor::default();
+    processor.set_dedup_tracker(DedupTracker::from_processed(&processed, 16));
+
+    // kafka delivers the messages before the checkpoint again after the restart
+    let (root, block_num, ledger) = (witgen.root(), witgen.get_block_generate_num(), witgen.ledger().clone());
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "10", "10", Some(1)))
+        .unwrap();
+    processor.handle_trade_msg(&mut witgen, test_trade(1, 1, 2)).unwrap();
+    assert_eq!(witgen.root(), root);
+    assert_eq!(witgen.get_block_generate_num(), block_num);
+    assert_eq!(witgen.ledger(), &ledger);
+    // new messages still go through, the trade left 9 ETH to account 1
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "1", "10", Some(3)))
+        .unwrap();
+    assert_ne!(witgen.root(), root);
+    assert!(witgen.solvency_report().is_solvent());
+}
+
+#[cfg(test)]
+#[test]
+fn test_reject_unsupported_balance_msgs() {
+    use crate::state::witness_generator::test_witgen;
+
+    let (mut witgen, _blocks) = test_witgen(4);
+    let mut processor = Processor::default();
+    processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "10", "10", Some(1)))
+        .unwrap();
+    let root = witgen.root();
+    // a balance below zero before the change is rejected as a whole
+    assert!(processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "11", "10", Some(2)))
+        .is_err());
+    assert!(witgen.solvency_report().is_solvent());
+    // a withdraw cannot be applied yet, the ledger still follows the exchange
+    assert!(processor
+        .handle_balance_msg(&mut witgen, test_deposit_msg(1, "ETH", "-1", "9", Some(3)))
+        .is_err());
+    assert_eq!(witgen.root(), root);
+    assert!(!witgen.solvency_report().is_solvent());
+}
@@ -0,0 +1,168 @@
+// Captures messages as JSON lines in the format read by `parse_msg`, one line per message in
+// arrival order, so that a production stream can be replayed by gen_global_state_testcase.
+// Lines of kafka messages also carry their topic, partition and offset, which `parse_msg` ignores.
+// Messages are recorded as consumed, before decoding, so that a message which cannot be decoded
+// is captured with its error instead of going to the dead letter sink.
+use super::codec::PayloadFormats;
+use super::msg_loader::decode_message;
+use rdkafka::message::Message;
+use std::io::Write;
+
+// a kafka message before decoding
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawMessage {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    pub payload: Option<Vec<u8>>,
+}
+
+impl RawMessage {
+    pub fn from_message<M: Message>(msg: &M) -> Self {
+        RawMessage {
+            topic: msg.topic().to_string(),
+            partition: msg.partition(),
+            offset: msg.offset(),
+            key: msg.key().map(<[u8]>::to_vec),
+            payload: msg.payload().map(<[u8]>::to_vec),
+        }
+    }
+}
+
+// records until `msgs` ends or `limit` messages are written, returns the number written.
+// An undecodable message is written with its raw key and payload and the error, a line which
+// `parse_msg` rejects, so that the replay handles it like the loader did
+pub fn record_msgs<W: Write>(
+    msgs: impl IntoIterator<Item = anyhow::Result<RawMessage>>,
+    formats: &PayloadFormats,
+    mut writer: W,
+    limit: Option<usize>,
+) -> anyhow::Result<usize> {
+    let mut msgs = msgs.into_iter();
+    let mut recorded = 0;
+    while limit.map_or(true, |limit| recorded < limit) {
+        let msg = match msgs.next() {
+            Some(msg) => msg?,
+            None => break,
+        };
+        let mut line = match decode_message(msg.key.as_deref(), msg.payload.as_deref(), formats) {
+            Ok(decoded) => serde_json::json!({
+                "type": decoded.type_name(),
+                "value": decoded.value()?,
+            }),
+            Err(e) => serde_json::json!({
+                "key": msg.key.as_ref().map(|key| String::from_utf8_lossy(key).into_owned()),
+                "payload": msg.payload.as_ref().map(|payload| String::from_utf8_lossy(payload).into_owned()),
+                "error": e.to_string(),
+            }),
+        };
+        line["topic"] = msg.topic.into();
+        line["partition"] = msg.partition.into();
+        line["offset"] = msg.offset.into();
+        serde_json::to_writer(&mut writer, &line)?;
+        writer.write_all(b"\n")?;
+        // a killed recorder should lose nothing already consumed
+        writer.flush()?;
+        recorded += 1;
+    }
+    Ok(recorded)
+}
+
+#[cfg(test)]
+#[test]
+fn test_record_msgs() {
+    use super::codec;
+    use crate::test_utils::messages::{parse_msg, WrappedMessage};
+
+    let raw = |offset: i64, key: &str, payload: &[u8]| RawMessage {
+        topic: "msgs".to_string(),
+        partition: 0,
+        offset,
+        key: Some(key.as_bytes().to_vec()),
+        payload: Some(payload.to_vec()),
+    };
+    let balance: &[u8] =
+        br#"{"timestamp": 0, "user_id": 1, "asset": "ETH", "business": "deposit", "change": "1.5", "balance": "1.5", "detail": ""}"#;
+    let binary =
+        codec::encode_binary(&serde_json::from_slice::<crate::types::matchengine::messages::BalanceMessage>(balance).unwrap()).unwrap();
+    let msgs = vec![
+        raw(0, "tokens", br#"{"token_id": 3, "name": "BTC", "decimals": 6}"#),
+        raw(1, "balances", &binary),
+        raw(2, "balances", b"{"),
+        raw(3, "balances", balance),
+    ];
+    let mut output = Vec::new();
+    let consumed = msgs.iter().cloned().map(Ok);
+    assert_eq!(record_msgs(consumed, &PayloadFormats::default(), &mut output, Some(3)).unwrap(), 3);
+
+    let lines: Vec<String> = String::from_utf8(output).unwrap().lines().map(str::to_string).collect();
+    assert_eq!(lines.len(), 3);
+    assert!(matches!(parse_msg(lines[0].clone()).unwrap(), WrappedMessage::TOKEN(_)));
+    // binary payloads are recorded as json
+    match parse_msg(lines[1].clone()).unwrap() {
+        WrappedMessage::BALANCE(balance) => assert_eq!(balance.change.to_string(), "1.5"),
+        _ => panic!("expect a balance message"),
+    }
+    // the malformed message is captured raw
+    assert!(parse_msg(lines[2].clone()).is_err());
+    let malformed: serde_json::Value = serde_json::from_str(&lines[2]).unwrap();
+    assert_eq!(malformed["payload"], "{");
+    assert_eq!(malformed["key"], "balances");
+    assert_eq!(malformed["offset"], 2);
+
+    // a consumer error stops the recording
+    let failing = vec![Ok(msgs[0].clone()), Err(anyhow::anyhow!("broker down"))];
+    assert!(record_msgs(failing, &PayloadFormats::default(), Vec::new(), None).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_recorded_msgs_replay() {
+    use super::dead_letter::{DeadLetterSink, MalformedMsgPolicy};
+    use super::msg_source::{LineSource, MessageSource};
+    use crate::test_utils::messages::{sample_msgs, WrappedMessage};
+
+    // each message at its own offset, followed by a malformed one
+    let mut msgs: Vec<anyhow::Result<RawMessage>> = Vec::new();
+    for msg in sample_msgs() {
+        let key = match &msg {
+            WrappedMessage::BALANCE(_) => "balances",
+            WrappedMessage::ORDER(_) => "orders",
+            WrappedMessage::TRADE(_) => "trades",
+            WrappedMessage::TOKEN(_) => "tokens",
+        };
+        msgs.push(Ok(RawMessage {
+            topic: key.to_string(),
+            partition: 0,
+            offset: 100 + msgs.len() as i64,
+            key: Some(key.as_bytes().to_vec()),
+            payload: Some(serde_json::to_vec(&msg.value().unwrap()).unwrap()),
+        }));
+    }
+    msgs.push(Ok(RawMessage {
+        topic: "trades".to_string(),
+        partition: 0,
+        offset: 104,
+        key: Some(b"trades".to_vec()),
+        payload: Some(b"{".to_vec()),
+    }));
+
+    let mut output = Vec::new();
+    assert_eq!(record_msgs(msgs, &PayloadFormats::default(), &mut output, None).unwrap(), 5);
+    let recorded = String::from_utf8(output).unwrap();
+    let offsets: Vec<i64> = recorded
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["offset"].as_i64().unwrap())
+        .collect();
+    assert_eq!(offsets, vec![100, 101, 102, 103, 104]);
+
+    // the recording replays as a file source, which skips the malformed message
+    let mut source = LineSource::new("recorded", recorded.as_bytes()).with_dead_letters(MalformedMsgPolicy::Skip, DeadLetterSink::Log);
+    for expected in sample_msgs() {
+        let msg = source.next_msg().unwrap().unwrap();
+        assert_eq!(msg.type_name(), expected.type_name());
+        assert_eq!(msg.value().unwrap(), expected.value().unwrap());
+    }
+    assert!(source.next_msg().unwrap().is_none());
+}
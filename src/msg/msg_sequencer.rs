@@ -0,0 +1,172 @@
+// Messages of different topics arrive in no particular order, while the processor needs e.g. a
+// deposit before the trade spending it. The sequencer merges them back into the exchange order
+// using the global sequence numbers carried by the messages.
+use super::msg_source::MsgPosition;
+use crate::test_utils::messages::WrappedMessage;
+use anyhow::bail;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// what to do once a gap has been open longer than the gap timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapPolicy {
+    // give up on the missing messages and emit the buffered ones
+    Skip,
+    // stop consuming, the missing messages have to be found first
+    Halt,
+}
+
+impl Default for GapPolicy {
+    fn default() -> Self {
+        GapPolicy::Halt
+    }
+}
+
+pub enum Sequenced {
+    // the messages which are now in order, possibly none, each with its own position
+    Ready(Vec<(WrappedMessage, MsgPosition)>),
+    // already emitted or buffered
+    Duplicate(u64),
+    // the window is full and the message is not the next one, it should be pushed again later
+    WindowFull(WrappedMessage, MsgPosition),
+}
+
+pub struct MsgSequencer {
+    // sequence number of the next message to emit
+    next_seq: u64,
+    // messages arrived ahead of a gap
+    buffered: BTreeMap<u64, (WrappedMessage, MsgPosition)>,
+    // how many messages may wait for a gap to be filled
+    window: usize,
+    // when the current gap opened, reset whenever the next message is emitted
+    gap_since: Option<Instant>,
+}
+
+impl MsgSequencer {
+    pub fn new(next_seq: u64, window: usize) -> Self {
+        Self {
+            next_seq,
+            buffered: BTreeMap::new(),
+            window,
+            gap_since: None,
+        }
+    }
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+    pub fn buffered_len(&self) -> usize {
+        self.buffered.len()
+    }
+    // the missing sequence numbers [start, end) before the first buffered message
+    pub fn gap(&self) -> Option<(u64, u64)> {
+        self.buffered.keys().next().map(|first| (self.next_seq, *first))
+    }
+    // the gap, once it has been open for `timeout`
+    pub fn expired_gap(&self, timeout: Duration) -> Option<(u64, u64)> {
+        match self.gap_since {
+            Some(since) if since.elapsed() >= timeout => self.gap(),
+            _ => None,
+        }
+    }
+    // gives up on the missing messages, the buffered ones are in order up to the next gap
+    pub fn skip_gap(&mut self) -> Vec<(WrappedMessage, MsgPosition)> {
+        if let Some((_, end)) = self.gap() {
+            self.next_seq = end;
+        }
+        self.drain_ready()
+    }
+
+    // only a message without sequence number is an error
+    pub fn push(&mut self, msg: WrappedMessage, position: MsgPosition) -> anyhow::Result<Sequenced> {
+        let seq = match msg.seq() {
+            Some(seq) => seq,
+            None if matches!(msg, WrappedMessage::TOKEN(_)) => return Ok(Sequenced::Ready(vec![(msg, position)])),
+            None => bail!("message without sequence number"),
+        };
+        if seq < self.next_seq || self.buffered.contains_key(&seq) {
+            return Ok(Sequenced::Duplicate(seq));
+        }
+        if seq != self.next_seq && self.buffered.len() >= self.window {
+            return Ok(Sequenced::WindowFull(msg, position));
+        }
+        self.buffered.insert(seq, (msg, position));
+        Ok(Sequenced::Ready(self.drain_ready()))
+    }
+    fn drain_ready(&mut self) -> Vec<(WrappedMessage, MsgPosition)> {
+        let mut ready = Vec::new();
+        while let Some(msg) = self.buffered.remove(&self.next_seq) {
+            ready.push(msg);
+            self.next_seq += 1;
+        }
+        if self.buffered.is_empty() {
+            self.gap_since = None;
+        } else if !ready.is_empty() || self.gap_since.is_none() {
+            self.gap_since = Some(Instant::now());
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_msg_sequencer() {
+    use crate::test_utils::messages::parse_msg;
+
+    let balance = |seq: u64| {
+        let line = format!(
+            r#"{{"type": "BalanceMessage", "value": {{"seq": {}, "timestamp": 0, "user_id": 1, "asset": "ETH", "business": "deposit", "change": "1", "balance": "1", "detail": ""}}}}"#,
+            seq
+        );
+        parse_msg(line).unwrap()
+    };
+    // each message is read at the line of its own sequence number
+    let push = |sequencer: &mut MsgSequencer, seq: u64| sequencer.push(balance(seq), MsgPosition::Line(seq)).unwrap();
+    let ready = |sequenced: Sequenced| match sequenced {
+        Sequenced::Ready(msgs) => msgs
+            .iter()
+            .map(|(msg, position)| {
+                assert_eq!(*position, MsgPosition::Line(msg.seq().unwrap()));
+                msg.seq().unwrap()
+            })
+            .collect::<Vec<_>>(),
+        _ => panic!("messages not ready"),
+    };
+
+    let mut sequencer = MsgSequencer::new(1, 2);
+    assert_eq!(ready(push(&mut sequencer, 1)), vec![1]);
+    assert!(ready(push(&mut sequencer, 3)).is_empty());
+    assert!(ready(push(&mut sequencer, 4)).is_empty());
+    assert_eq!(sequencer.gap(), Some((2, 3)));
+    // the window is full, the message is handed back
+    match push(&mut sequencer, 5) {
+        Sequenced::WindowFull(msg, position) => {
+            assert_eq!(msg.seq(), Some(5));
+            assert_eq!(position, MsgPosition::Line(5));
+        }
+        _ => panic!("window not full"),
+    }
+    assert!(matches!(push(&mut sequencer, 4), Sequenced::Duplicate(4)));
+    assert_eq!(ready(push(&mut sequencer, 2)), vec![2, 3, 4]);
+    assert_eq!(sequencer.next_seq(), 5);
+    assert!(matches!(push(&mut sequencer, 3), Sequenced::Duplicate(3)));
+    assert_eq!(sequencer.gap(), None);
+    assert_eq!(sequencer.expired_gap(Duration::from_millis(0)), None);
+    assert_eq!(ready(push(&mut sequencer, 5)), vec![5]);
+    // only a message without sequence number is rejected
+    let unsequenced = r#"{"type": "BalanceMessage", "value": {"timestamp": 0, "user_id": 1, "asset": "ETH", "business": "deposit", "change": "1", "balance": "1", "detail": ""}}"#;
+    assert!(sequencer
+        .push(parse_msg(unsequenced.to_string()).unwrap(), MsgPosition::Line(6))
+        .is_err());
+
+    // a gap which stays open is skipped up to the next gap
+    assert!(ready(push(&mut sequencer, 8)).is_empty());
+    assert!(ready(push(&mut sequencer, 10)).is_empty());
+    assert_eq!(sequencer.expired_gap(Duration::from_secs(60)), None);
+    assert_eq!(sequencer.expired_gap(Duration::from_millis(0)), Some((6, 8)));
+    let skipped: Vec<u64> = sequencer.skip_gap().iter().map(|(msg, _)| msg.seq().unwrap()).collect();
+    assert_eq!(skipped, vec![8]);
+    assert_eq!(sequencer.gap(), Some((9, 10)));
+    assert_eq!(sequencer.buffered_len(), 1);
+}
@@ -0,0 +1,362 @@
+// Where the replay reads its messages from: kafka in production, or a captured JSONL file / stdin
+// for debugging. Every source keeps track of its position, so errors and logs can point at the
+// message involved.
+use super::dead_letter::{DeadLetter, DeadLetterSink, MalformedMsgPolicy};
+use super::msg_loader::load_msgs_from_mq;
+use crate::config::Settings;
+use crate::metrics;
+use crate::test_utils::messages::{parse_msg, WrappedMessage};
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MsgSourceKind {
+    Kafka,
+    // JSONL lines as written by the message dumper, see `parse_msg`
+    File,
+    Stdin,
+}
+
+impl Default for MsgSourceKind {
+    fn default() -> Self {
+        MsgSourceKind::Kafka
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MsgPosition {
+    // 1-based line number
+    Line(u64),
+    Kafka { topic: String, partition: i32, offset: i64 },
+}
+
+impl fmt::Display for MsgPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsgPosition::Line(line) => write!(f, "line {}", line),
+            MsgPosition::Kafka { topic, partition, offset } => write!(f, "{}/{}@{}", topic, partition, offset),
+        }
+    }
+}
+
+// the next offset to consume of each kafka partition, past the messages applied so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConsumedOffsets(BTreeMap<(String, i32), i64>);
+
+impl ConsumedOffsets {
+    pub fn record(&mut self, position: &MsgPosition) {
+        if let MsgPosition::Kafka { topic, partition, offset } = position {
+            let next = self.0.entry((topic.clone(), *partition)).or_insert(0);
+            *next = (*next).max(offset + 1);
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, i32, i64)> {
+        self.0
+            .iter()
+            .map(|((topic, partition), offset)| (topic.as_str(), *partition, *offset))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// hands the offsets of a checkpoint back to the consumer, which commits them to kafka
+#[derive(Clone)]
+pub struct OffsetCommitter(tokio::sync::mpsc::UnboundedSender<ConsumedOffsets>);
+
+impl OffsetCommitter {
+    pub fn commit(&self, offsets: &ConsumedOffsets) -> anyhow::Result<()> {
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        self.0.send(offsets.clone()).map_err(|_| anyhow!("kafka consumer stopped"))
+    }
+}
+
+pub trait MessageSource: Send {
+    fn name(&self) -> &str;
+    // blocks until the next message is available, None once the source is exhausted
+    fn next_msg(&mut self) -> anyhow::Result<Option<WrappedMessage>>;
+    // the position of the last returned message
+    fn position(&self) -> Option<MsgPosition>;
+    // set for sources which resume from committed offsets
+    fn offset_committer(&self) -> Option<OffsetCommitter> {
+        None
+    }
+}
+
+// one message per line, empty lines are skipped
+pub struct LineSource<R> {
+    name: String,
+    reader: R,
+    line: u64,
+    // without a sink, a malformed line fails the source
+    dead_letters: Option<DeadLetterSink>,
+    policy: MalformedMsgPolicy,
+}
+
+impl<R: BufRead + Send> LineSource<R> {
+    pub fn new(name: &str, reader: R) -> Self {
+        Self {
+            name: name.to_string(),
+            reader,
+            line: 0,
+            dead_letters: None,
+            policy: MalformedMsgPolicy::Halt,
+        }
+    }
+    // malformed lines are dead lettered with the source name as topic and the line number as
+    // offset, then skipped or failed on as for kafka messages
+    pub fn with_dead_letters(mut self, policy: MalformedMsgPolicy, dead_letters: DeadLetterSink) -> Self {
+        self.policy = policy;
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+}
+
+impl LineSource<BufReader<File>> {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let file = File::open(path).map_err(|e| anyhow!("open {}: {}", path, e))?;
+        Ok(Self::new(path, BufReader::new(file)))
+    }
+}
+
+impl LineSource<BufReader<std::io::Stdin>> {
+    pub fn stdin() -> Self {
+        Self::new("stdin", BufReader::new(std::io::stdin()))
+    }
+}
+
+impl<R: BufRead + Send> MessageSource for LineSource<R> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn next_msg(&mut self) -> anyhow::Result<Option<WrappedMessage>> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let e = match parse_msg(line.clone()) {
+                Ok(msg) => return Ok(Some(msg)),
+                Err(e) => e,
+            };
+            if let Some(dead_letters) = &self.dead_letters {
+                metrics::MALFORMED_MSGS.inc();
+                dead_letters.send(&DeadLetter {
+                    topic: self.name.clone(),
+                    partition: 0,
+                    offset: self.line as i64,
+                    key: None,
+                    payload: Some(line.trim_end().to_string()),
+                    error: e.to_string(),
+                });
+                if self.policy == MalformedMsgPolicy::Skip {
+                    continue;
+                }
+            }
+            bail!("invalid message at {} {}: {}", self.name, self.line, e);
+        }
+    }
+    fn position(&self) -> Option<MsgPosition> {
+        if self.line == 0 {
+            None
+        } else {
+            Some(MsgPosition::Line(self.line))
+        }
+    }
+}
+
+// the kafka consumer runs on its own thread, with the dead letter, sequencing and backpressure
+// handling of `msg_loader`, and hands the messages over through a bounded queue
+pub struct KafkaSource {
+    receiver: crossbeam_channel::Receiver<(WrappedMessage, MsgPosition)>,
+    consumer_thread: Option<std::thread::JoinHandle<anyhow::Result<()>>>,
+    position: Option<MsgPosition>,
+    committer: OffsetCommitter,
+}
+
+impl KafkaSource {
+    pub fn start(settings: &Settings) -> Self {
+        let (sender, receiver) = crossbeam_channel::bounded(settings.msg_queue_size);
+        let (commit_sender, commit_receiver) = tokio::sync::mpsc::unbounded_channel();
+        Self {
+            receiver,
+            consumer_thread: Some(load_msgs_from_mq(settings, sender, commit_receiver)),
+            position: None,
+            committer: OffsetCommitter(commit_sender),
+        }
+    }
+}
+
+impl MessageSource for KafkaSource {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+    fn next_msg(&mut self) -> anyhow::Result<Option<WrappedMessage>> {
+        if let Ok((msg, position)) = self.receiver.recv() {
+            self.position = Some(position);
+            return Ok(Some(msg));
+        }
+        // the consumer thread has stopped, either shut down or failed
+        match self.consumer_thread.take() {
+            Some(handle) => handle.join().map_err(|_| anyhow!("kafka consumer thread panicked"))?.map(|_| None),
+            None => Ok(None),
+        }
+    }
+    fn position(&self) -> Option<MsgPosition> {
+        self.position.clone()
+    }
+    fn offset_committer(&self) -> Option<OffsetCommitter> {
+        Some(self.committer.clone())
+    }
+}
+
+pub fn from_settings(settings: &Settings) -> anyhow::Result<Box<dyn MessageSource>> {
+    // the kafka loader has a sink of its own
+    let dead_letters = || {
+        DeadLetterSink::new(
+            &settings.brokers,
+            settings.dead_letter_topic.as_deref(),
+            settings.dead_letter_file.as_deref(),
+        )
+    };
+    let source: Box<dyn MessageSource> = match settings.msg_source {
+        MsgSourceKind::Kafka => Box::new(KafkaSource::start(settings)),
+        MsgSourceKind::File => match &settings.msg_source_file {
+            Some(path) => Box::new(LineSource::open(path)?.with_dead_letters(settings.malformed_msg_policy, dead_letters()?)),
+            None => bail!("msg_source_file is required for the file source"),
+        },
+        MsgSourceKind::Stdin => Box::new(LineSource::stdin().with_dead_letters(settings.malformed_msg_policy, dead_letters()?)),
+    };
+    Ok(source)
+}
+
+// feeds the replay from the source on its own thread, until the source is exhausted.
+// Each message comes with its position, so that the replay knows the offsets it has applied
+pub fn spawn_source(
+    mut source: Box<dyn MessageSource>,
+    sender: crossbeam_channel::Sender<(WrappedMessage, Option<MsgPosition>)>,
+) -> std::thread::JoinHandle<anyhow::Result<()>> {
+    std::thread::spawn(move || {
+        log::info!("loading messages from {}", source.name());
+        while let Some(msg) = source.next_msg()? {
+            // blocks while the replay thread is behind
+            sender
+                .send((msg, source.position()))
+                .map_err(|_| anyhow!("message receiver closed"))?;
+            metrics::MSG_QUEUE_DEPTH.set(sender.len());
+        }
+        match source.position() {
+            Some(position) => log::info!("{} exhausted at {}", source.name(), position),
+            None => log::info!("{} is empty", source.name()),
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test_line_source() {
+    let input = r#"{"type": "BalanceMessage", "value": {"timestamp": 0, "user_id": 1, "asset": "ETH", "business": "deposit", "change": "1", "balance": "1", "detail": ""}}
+
+{"type": "OrderMessage", "value": {}}
+"#;
+    let mut source = LineSource::new("captured", input.as_bytes());
+    assert_eq!(source.position(), None);
+    assert!(matches!(source.next_msg().unwrap(), Some(WrappedMessage::BALANCE(_))));
+    assert_eq!(source.position(), Some(MsgPosition::Line(1)));
+    // the empty line is skipped
+    let err = source.next_msg().unwrap_err();
+    assert!(err.to_string().starts_with("invalid message at captured 3"));
+    assert!(source.next_msg().unwrap().is_none());
+    assert_eq!(source.position().unwrap().to_string(), "line 3");
+}
+
+#[cfg(test)]
+#[test]
+fn test_line_source_dead_letters() {
+    let input = r#"{"type": "BalanceMessage", "value": {"timestamp": 0, "user_id": 1, "asset": "ETH", "business": "deposit", "change": "1", "balance": "1", "detail": ""}}
+{"type": "OrderMessage", "value": {}}
+{"type": "BalanceMessage", "value": {"timestamp": 1, "user_id": 2, "asset": "ETH", "business": "deposit", "change": "1", "balance": "1", "detail": ""}}
+"#;
+    let path = std::env::temp_dir().join(format!("line_dead_letters_{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    let sink = || DeadLetterSink::new("", None, Some(path)).unwrap();
+
+    // the malformed line is dead lettered and the replay goes on
+    let mut source = LineSource::new("captured", input.as_bytes()).with_dead_letters(MalformedMsgPolicy::Skip, sink());
+    assert!(matches!(source.next_msg().unwrap(), Some(WrappedMessage::BALANCE(_))));
+    assert!(matches!(source.next_msg().unwrap(), Some(WrappedMessage::BALANCE(_))));
+    assert_eq!(source.position(), Some(MsgPosition::Line(3)));
+    assert!(source.next_msg().unwrap().is_none());
+    // or it stops there
+    let mut source = LineSource::new("captured", input.as_bytes()).with_dead_letters(MalformedMsgPolicy::Halt, sink());
+    assert!(source.next_msg().unwrap().is_some());
+    assert!(source.next_msg().is_err());
+
+    let written = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let letters: Vec<DeadLetter> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(letters.len(), 2);
+    assert!(letters.iter().all(|letter| letter.topic == "captured" && letter.offset == 2));
+    assert_eq!(letters[0].payload.as_deref(), Some(r#"{"type": "OrderMessage", "value": {}}"#));
+}
+
+#[cfg(test)]
+#[test]
+fn test_consumed_offsets() {
+    let kafka = |partition: i32, offset: i64| MsgPosition::Kafka {
+        topic: "trades".to_string(),
+        partition,
+        offset,
+    };
+    let mut offsets = ConsumedOffsets::default();
+    offsets.record(&MsgPosition::Line(3));
+    assert!(offsets.is_empty());
+    offsets.record(&kafka(0, 5));
+    offsets.record(&kafka(1, 2));
+    // offsets only move forward
+    offsets.record(&kafka(0, 4));
+    assert_eq!(offsets.iter().collect::<Vec<_>>(), vec![("trades", 0, 6), ("trades", 1, 3)]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_file_source() {
+    use crate::test_utils::messages::sample_msgs;
+
+    let path = std::env::temp_dir().join(format!("file_source_{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let lines: Vec<String> = sample_msgs()
+        .iter()
+        .map(|msg| serde_json::json!({"type": msg.type_name(), "value": msg.value().unwrap()}).to_string())
+        .collect();
+    std::fs::write(&path, lines.join("\n")).unwrap();
+
+    let mut settings = Settings {
+        msg_source: MsgSourceKind::File,
+        ..Default::default()
+    };
+    assert!(from_settings(&settings).is_err());
+    settings.msg_source_file = Some(path.clone());
+    let mut source = from_settings(&settings).unwrap();
+    assert_eq!(source.name(), path);
+    for (line, expected) in sample_msgs().iter().enumerate() {
+        let msg = source.next_msg().unwrap().unwrap();
+        assert_eq!(msg.type_name(), expected.type_name());
+        assert_eq!(msg.value().unwrap(), expected.value().unwrap());
+        assert_eq!(source.position(), Some(MsgPosition::Line(line as u64 + 1)));
+    }
+    assert!(source.next_msg().unwrap().is_none());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,16 +1,19 @@
+use super::dedup::MsgId;
+use super::reconcile::Mismatch;
 use crate::account::Signature;
 use crate::state::WitnessGenerator;
-use crate::test_utils::types::{get_token_id_by_name, prec_token_id};
 use crate::types::l2::{self, OrderSide};
-use crate::types::primitives::{fr_to_decimal, u32_to_fr};
+use crate::types::market::{MarketInfo, MarketRegistry};
+use crate::types::primitives::{fr_to_decimal, u32_to_fr, Fr};
+use crate::types::token::TokenRegistry;
 use crate::types::{self, fixnum, matchengine};
+use anyhow::bail;
 use num::Zero;
 use rust_decimal::Decimal;
+use std::convert::TryFrom;
 
 #[derive(Clone, Copy)]
 pub struct TokenIdPair(pub u32, pub u32);
-#[derive(Clone, Copy)]
-pub struct TokenPair<'c>(pub &'c str, pub &'c str);
 
 pub struct OrderState {
     pub side: &'static str,
@@ -20,43 +23,83 @@ pub struct OrderState {
     pub total_buy: Decimal,
     pub filled_sell: Decimal,
     pub filled_buy: Decimal,
+    pub prec_sell: u32,
+    pub prec_buy: u32,
 
     pub order_id: u32,
     pub account_id: u32,
     pub role: matchengine::messages::MarketRole,
 }
 
-impl<'c> From<&'c str> for TokenPair<'c> {
-    fn from(origin: &'c str) -> Self {
-        let mut assets = origin.split('_');
-        let asset_1 = assets.next().unwrap();
-        let asset_2 = assets.next().unwrap();
-        TokenPair(asset_1, asset_2)
+impl TokenIdPair {
+    // (base, quote)
+    pub fn from_market(market: &MarketInfo, tokens: &TokenRegistry) -> anyhow::Result<Self> {
+        Ok(TokenIdPair(tokens.token_id(&market.base)?, tokens.token_id(&market.quote)?))
     }
 }
 
-impl<'c> From<TokenPair<'c>> for TokenIdPair {
-    fn from(origin: TokenPair<'c>) -> Self {
-        TokenIdPair(get_token_id_by_name(origin.0), get_token_id_by_name(origin.1))
-    }
+// the matching engine puts the balance message id into the json `detail` field
+pub fn balance_msg_id(msg: &matchengine::messages::BalanceMessage) -> Option<u64> {
+    let detail: serde_json::Value = serde_json::from_str(&msg.detail).ok()?;
+    detail["id"].as_u64()
+}
+
+// the ids a duplicate of the message would be detected by
+pub fn balance_dedup_ids(msg: &matchengine::messages::BalanceMessage) -> Vec<MsgId> {
+    msg.seq
+        .map(MsgId::Seq)
+        .into_iter()
+        .chain(balance_msg_id(msg).map(MsgId::Balance))
+        .collect()
+}
+pub fn trade_dedup_ids(msg: &matchengine::messages::TradeMessage) -> Vec<MsgId> {
+    msg.seq.map(MsgId::Seq).into_iter().chain(Some(MsgId::Trade(msg.id))).collect()
 }
 
-impl From<String> for TokenIdPair {
-    fn from(origin: String) -> Self {
-        let mut assets = origin.split('_');
-        let base = assets.next().unwrap();
-        let quote = assets.next().unwrap();
-        TokenIdPair(get_token_id_by_name(base), get_token_id_by_name(quote))
+// the (total_sell, total_buy) of the rollup order. A market order has no price, so it gets the worst
+// limit it can trade at, which keeps the circuit's ratio check valid for every trade: a market ask
+// sells its amount at one price tick or above, and a market bid buys its amount spending at most its
+// frozen quote
+pub fn order_totals(origin: &matchengine::messages::Order, market: &MarketInfo, tokens: &TokenRegistry) -> anyhow::Result<(Fr, Fr)> {
+    use matchengine::messages::{OrderSide as Side, OrderType};
+    let TokenIdPair(base_token_id, quote_token_id) = TokenIdPair::from_market(market, tokens)?;
+    let base_amount = fixnum::Float864::from_decimal(&origin.amount, tokens.prec(base_token_id)?)?.to_fr();
+    let quote_amount =
+        |amount: &Decimal| -> anyhow::Result<Fr> { Ok(fixnum::Float864::from_decimal(amount, tokens.prec(quote_token_id)?)?.to_fr()) };
+    match (origin.type_, origin.side) {
+        (OrderType::LIMIT, side) => {
+            market.check_price(&origin.price)?;
+            let quote_amount = quote_amount(&(origin.amount * origin.price))?;
+            match side {
+                Side::ASK => Ok((base_amount, quote_amount)),
+                Side::BID => Ok((quote_amount, base_amount)),
+            }
+        }
+        (OrderType::MARKET, Side::ASK) => {
+            let tick = Decimal::new(1, market.price_prec);
+            Ok((base_amount, quote_amount(&(origin.amount * tick))?))
+        }
+        (OrderType::MARKET, Side::BID) => {
+            if origin.frozen <= Decimal::zero() {
+                bail!("market bid order {} has no frozen quote to spend", origin.id);
+            }
+            Ok((quote_amount(&origin.frozen)?, base_amount))
+        }
     }
 }
 
-pub fn exchange_order_to_rollup_order(origin: &matchengine::messages::Order) -> l2::OrderInput {
+pub fn exchange_order_to_rollup_order(
+    origin: &matchengine::messages::Order,
+    markets: &MarketRegistry,
+    tokens: &TokenRegistry,
+) -> anyhow::Result<l2::OrderInput> {
     assert!(origin.finished_base.is_zero());
     assert!(origin.finished_quote.is_zero());
-    let TokenIdPair(base_token_id, quote_token_id) = origin.market.clone().into();
-    let base_prec = prec_token_id(base_token_id);
-    let quote_prec = prec_token_id(quote_token_id);
-    match origin.side {
+    let market = markets.get(&origin.market)?;
+    market.check_order_amount(&origin.amount)?;
+    let (total_sell, total_buy) = order_totals(origin, market, tokens)?;
+    let TokenIdPair(base_token_id, quote_token_id) = TokenIdPair::from_market(market, tokens)?;
+    let order = match origin.side {
         matchengine::messages::OrderSide::ASK => {
             l2::OrderInput {
                 order_id: origin.id as u32,
@@ -64,8 +107,8 @@ pub fn exchange_order_to_rollup_order(origin: &matchengine::messages::Order) ->
                 token_sell: types::primitives::u32_to_fr(base_token_id),
                 //filled_sell: fixnum::decimal_to_amount(&origin.finished_base, base_token_id).to_fr(),
                 //filled_buy: fixnum::decimal_to_amount(&origin.finished_quote, quote_token_id).to_fr(),
-                total_sell: fixnum::decimal_to_amount(&origin.amount, base_prec).to_fr(),
-                total_buy: fixnum::decimal_to_amount(&(origin.amount * origin.price), quote_prec).to_fr(),
+                total_sell,
+                total_buy,
                 sig: Signature::default(),
                 account_id: origin.user,
                 side: OrderSide::Sell,
@@ -78,25 +121,30 @@ pub fn exchange_order_to_rollup_order(origin: &matchengine::messages::Order) ->
                 token_sell: types::primitives::u32_to_fr(quote_token_id),
                 //filled_sell: fixnum::decimal_to_amount(&origin.finished_quote, quote_token_id).to_fr(),
                 //filled_buy: fixnum::decimal_to_amount(&origin.finished_base, base_token_id).to_fr(),
-                total_sell: fixnum::decimal_to_amount(&(origin.amount * origin.price), quote_prec).to_fr(),
-                total_buy: fixnum::decimal_to_amount(&origin.amount, base_prec).to_fr(),
+                total_sell,
+                total_buy,
                 sig: Signature::default(),
                 account_id: origin.user,
                 side: OrderSide::Buy,
             }
         }
-    }
+    };
+    Ok(order)
 }
 
 pub fn trade_to_order_state(
     state: &matchengine::messages::VerboseTradeState,
     trade: &matchengine::messages::TradeMessage,
-) -> (OrderState, OrderState) {
+    markets: &MarketRegistry,
+    tokens: &TokenRegistry,
+) -> anyhow::Result<(OrderState, OrderState)> {
     // ASK, BID
     let ask = &state.ask_order_state;
     let bid = &state.bid_order_state;
-    let id_pair = TokenIdPair::from(TokenPair::from(trade.market.as_str()));
-    (
+    let id_pair = TokenIdPair::from_market(markets.get(&trade.market)?, tokens)?;
+    let base_prec = tokens.prec(id_pair.0)?;
+    let quote_prec = tokens.prec(id_pair.1)?;
+    Ok((
         OrderState {
             side: "ASK",
             token_sell: id_pair.0,
@@ -105,6 +153,8 @@ pub fn trade_to_order_state(
             total_buy: ask.amount * ask.price,
             filled_sell: ask.finished_base,
             filled_buy: ask.finished_quote,
+            prec_sell: base_prec,
+            prec_buy: quote_prec,
             order_id: trade.ask_order_id as u32,
             account_id: trade.ask_user_id,
             role: trade.ask_role,
@@ -117,11 +167,13 @@ pub fn trade_to_order_state(
             total_buy: bid.amount,
             filled_sell: bid.finished_quote,
             filled_buy: bid.finished_base,
+            prec_sell: quote_prec,
+            prec_buy: base_prec,
             order_id: trade.bid_order_id as u32,
             account_id: trade.bid_user_id,
             role: trade.bid_role,
         },
-    )
+    ))
 }
 
 impl OrderState {
@@ -134,8 +186,11 @@ impl OrderState {
         id_pair: TokenIdPair,
         side: &'static str,
         trade: &matchengine::messages::TradeMessage,
-    ) -> Self {
-        match side {
+        tokens: &TokenRegistry,
+    ) -> anyhow::Result<Self> {
+        let base_prec = tokens.prec(id_pair.0)?;
+        let quote_prec = tokens.prec(id_pair.1)?;
+        let order_state = match side {
             "ASK" => OrderState {
                 //origin,
                 side,
@@ -146,6 +201,8 @@ impl OrderState {
                 total_buy: origin.amount * origin.price,
                 filled_sell: origin.finished_base,
                 filled_buy: origin.finished_quote,
+                prec_sell: base_prec,
+                prec_buy: quote_prec,
                 order_id: trade.ask_order_id as u32,
                 account_id: trade.ask_user_id,
                 role: trade.ask_role,
@@ -160,26 +217,30 @@ impl OrderState {
                 total_buy: origin.amount,
                 filled_sell: origin.finished_quote,
                 filled_buy: origin.finished_base,
+                prec_sell: quote_prec,
+                prec_buy: base_prec,
                 order_id: trade.bid_order_id as u32,
                 account_id: trade.bid_user_id,
                 role: trade.bid_role,
             },
             _ => unreachable!(),
-        }
+        };
+        Ok(order_state)
     }
 }
 
-impl From<OrderState> for l2::Order {
-    fn from(origin: OrderState) -> Self {
-        l2::Order {
+impl TryFrom<OrderState> for l2::Order {
+    type Error = anyhow::Error;
+    fn try_from(origin: OrderState) -> anyhow::Result<Self> {
+        Ok(l2::Order {
             order_id: origin.order_id,
             //status: types::primitives::u32_to_fr(origin.status),
             token_buy: types::primitives::u32_to_fr(origin.token_buy),
             token_sell: types::primitives::u32_to_fr(origin.token_sell),
-            filled_sell: fixnum::decimal_to_amount(&origin.filled_sell, prec_token_id(origin.token_sell)).to_fr(),
-            filled_buy: fixnum::decimal_to_amount(&origin.filled_buy, prec_token_id(origin.token_buy)).to_fr(),
-            total_sell: fixnum::decimal_to_amount(&origin.total_sell, prec_token_id(origin.token_sell)).to_fr(),
-            total_buy: fixnum::decimal_to_amount(&origin.total_buy, prec_token_id(origin.token_buy)).to_fr(),
+            filled_sell: fixnum::decimal_to_amount(&origin.filled_sell, origin.prec_sell)?.to_fr(),
+            filled_buy: fixnum::decimal_to_amount(&origin.filled_buy, origin.prec_buy)?.to_fr(),
+            total_sell: fixnum::decimal_to_amount(&origin.total_sell, origin.prec_sell)?.to_fr(),
+            total_buy: fixnum::decimal_to_amount(&origin.total_buy, origin.prec_buy)?.to_fr(),
             sig: Signature::default(),
             account_id: origin.account_id,
             side: if origin.side.to_lowercase() == "buy" || origin.side.to_lowercase() == "bid" {
@@ -187,18 +248,19 @@ impl From<OrderState> for l2::Order {
             } else {
                 OrderSide::Sell
             },
-        }
+        })
     }
 }
 
-impl From<OrderState> for l2::OrderInput {
-    fn from(order_state: OrderState) -> Self {
-        l2::OrderInput {
+impl TryFrom<OrderState> for l2::OrderInput {
+    type Error = anyhow::Error;
+    fn try_from(order_state: OrderState) -> anyhow::Result<Self> {
+        Ok(l2::OrderInput {
             order_id: order_state.order_id,
             token_sell: u32_to_fr(order_state.token_sell),
             token_buy: u32_to_fr(order_state.token_buy),
-            total_sell: fixnum::decimal_to_amount(&order_state.total_sell, prec_token_id(order_state.token_sell)).to_fr(),
-            total_buy: fixnum::decimal_to_amount(&order_state.total_buy, prec_token_id(order_state.token_buy)).to_fr(),
+            total_sell: fixnum::decimal_to_amount(&order_state.total_sell, order_state.prec_sell)?.to_fr(),
+            total_buy: fixnum::decimal_to_amount(&order_state.total_buy, order_state.prec_buy)?.to_fr(),
             sig: Signature::default(),
             account_id: order_state.account_id,
             side: if order_state.side.to_lowercase() == "buy" || order_state.side.to_lowercase() == "bid" {
@@ -206,7 +268,7 @@ impl From<OrderState> for l2::OrderInput {
             } else {
                 OrderSide::Sell
             },
-        }
+        })
     }
 }
 
@@ -248,38 +310,112 @@ impl CommonBalanceState {
         }
     }
 
-    fn build_local(witgen: &WitnessGenerator, bid_id: u32, ask_id: u32, id_pair: TokenIdPair) -> Self {
+    fn build_local(
+        witgen: &WitnessGenerator,
+        bid_id: u32,
+        ask_id: u32,
+        id_pair: TokenIdPair,
+        tokens: &TokenRegistry,
+    ) -> anyhow::Result<Self> {
         let base_id = id_pair.0;
         let quote_id = id_pair.1;
+        let base_prec = tokens.prec(base_id)?;
+        let quote_prec = tokens.prec(quote_id)?;
 
-        CommonBalanceState {
-            bid_user_base: fr_to_decimal(&witgen.get_token_balance(bid_id, base_id), prec_token_id(base_id)),
-            bid_user_quote: fr_to_decimal(&witgen.get_token_balance(bid_id, quote_id), prec_token_id(quote_id)),
-            ask_user_base: fr_to_decimal(&witgen.get_token_balance(ask_id, base_id), prec_token_id(base_id)),
-            ask_user_quote: fr_to_decimal(&witgen.get_token_balance(ask_id, quote_id), prec_token_id(quote_id)),
-        }
+        Ok(CommonBalanceState {
+            bid_user_base: fr_to_decimal(&witgen.get_token_balance(bid_id, base_id), base_prec),
+            bid_user_quote: fr_to_decimal(&witgen.get_token_balance(bid_id, quote_id), quote_prec),
+            ask_user_base: fr_to_decimal(&witgen.get_token_balance(ask_id, base_id), base_prec),
+            ask_user_quote: fr_to_decimal(&witgen.get_token_balance(ask_id, quote_id), quote_prec),
+        })
     }
 }
 
-pub fn assert_balance_state(
+// returns a mismatch for every balance of the two users differing between the exchange and the rollup
+pub fn diff_balance_state(
     balance_state: &matchengine::messages::VerboseBalanceState,
     witgen: &WitnessGenerator,
     bid_id: u32,
     ask_id: u32,
     id_pair: TokenIdPair,
-) {
-    let local_balance = CommonBalanceState::build_local(witgen, bid_id, ask_id, id_pair);
-    let parsed_state = CommonBalanceState::parse(balance_state, id_pair);
-    assert_eq!(local_balance, parsed_state);
+    tokens: &TokenRegistry,
+    msg_id: u64,
+) -> anyhow::Result<Vec<Mismatch>> {
+    let local = CommonBalanceState::build_local(witgen, bid_id, ask_id, id_pair, tokens)?;
+    let remote = CommonBalanceState::parse(balance_state, id_pair);
+    let TokenIdPair(base_id, quote_id) = id_pair;
+    let pairs = [
+        (bid_id, base_id, remote.bid_user_base, local.bid_user_base),
+        (bid_id, quote_id, remote.bid_user_quote, local.bid_user_quote),
+        (ask_id, base_id, remote.ask_user_base, local.ask_user_base),
+        (ask_id, quote_id, remote.ask_user_quote, local.ask_user_quote),
+    ];
+    Ok(pairs
+        .iter()
+        .filter(|(_, _, expected, actual)| expected != actual)
+        .map(|(account_id, token_id, expected, actual)| Mismatch {
+            msg_id: Some(msg_id),
+            account_id: *account_id,
+            token_id: Some(*token_id),
+            order_id: None,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        })
+        .collect())
 }
 
-pub fn assert_order_state(witgen: &WitnessGenerator, order_state: OrderState) {
-    if witgen.has_order(order_state.account_id, order_state.order_id) {
-        let mut order_local = witgen.get_account_order_by_id(order_state.account_id, order_state.order_id);
-        // TODO: compares the order field sig. The field sig is set to the default value of Signature for now.
-        order_local.sig = Signature::default();
-        assert_eq!(order_local, l2::Order::from(order_state));
-    } else {
-        // the only possible path reaching here, is that the order has not been put into witgen
+// orders not yet put into the rollup state are not compared
+pub fn diff_order_state(witgen: &WitnessGenerator, order_state: OrderState, msg_id: u64) -> anyhow::Result<Option<Mismatch>> {
+    let (account_id, order_id) = (order_state.account_id, order_state.order_id);
+    if !witgen.has_order(account_id, order_id) {
+        return Ok(None);
+    }
+    let mut order_local = witgen.get_account_order_by_id(account_id, order_id);
+    // TODO: compares the order field sig. The field sig is set to the default value of Signature for now.
+    order_local.sig = Signature::default();
+    // the exchange reports no price for a market order, so only the rollup knows its totals
+    let is_market = order_state.total_sell.is_zero() || order_state.total_buy.is_zero();
+    let mut order_remote = l2::Order::try_from(order_state)?;
+    if is_market {
+        order_remote.total_sell = order_local.total_sell;
+        order_remote.total_buy = order_local.total_buy;
+    }
+    if order_local == order_remote {
+        return Ok(None);
     }
+    Ok(Some(Mismatch {
+        msg_id: Some(msg_id),
+        account_id,
+        token_id: None,
+        order_id: Some(order_id),
+        expected: format!("{:?}", order_remote),
+        actual: format!("{:?}", order_local),
+    }))
+}
+
+#[cfg(test)]
+#[test]
+fn test_market_order_totals() {
+    use crate::types::primitives::u64_to_fr;
+    let (markets, tokens) = (MarketRegistry::default(), TokenRegistry::default());
+    let order = |side: &str, frozen: &str| -> matchengine::messages::Order {
+        serde_json::from_value(serde_json::json!({
+            "id": 1, "market": "ETH_USDT", "type": "MARKET", "side": side, "user": 1,
+            "create_time": 0.0, "update_time": 0.0, "price": "0", "amount": "2",
+            "taker_fee": "0", "maker_fee": "0", "remain": "2", "frozen": frozen,
+            "finished_base": "0", "finished_quote": "0", "finished_fee": "0",
+        }))
+        .unwrap()
+    };
+    // a market bid buys its amount, spending at most its frozen quote
+    let bid = exchange_order_to_rollup_order(&order("BID", "300"), &markets, &tokens).unwrap();
+    assert_eq!(bid.side, OrderSide::Buy);
+    assert_eq!(bid.total_sell, u64_to_fr(300_000_000));
+    assert_eq!(bid.total_buy, u64_to_fr(2_000_000));
+    assert!(exchange_order_to_rollup_order(&order("BID", "0"), &markets, &tokens).is_err());
+    // a market ask sells its amount at one price tick of 0.01 or above
+    let ask = exchange_order_to_rollup_order(&order("ASK", "2"), &markets, &tokens).unwrap();
+    assert_eq!(ask.side, OrderSide::Sell);
+    assert_eq!(ask.total_sell, u64_to_fr(2_000_000));
+    assert_eq!(ask.total_buy, u64_to_fr(20_000));
 }
@@ -0,0 +1,116 @@
+// Reconciliation of the exchange state carried by messages against the rollup state.
+// By default a mismatch panics; operators can instead record mismatches and keep replaying.
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconcileMode {
+    // crash on the first mismatch
+    Panic,
+    // record mismatches and continue
+    Record,
+    // record mismatches and continue, but stop publishing blocks after the first one
+    RecordAndHalt,
+}
+
+impl Default for ReconcileMode {
+    fn default() -> Self {
+        ReconcileMode::Panic
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mismatch {
+    // id of the message carrying the exchange state, if it has one
+    pub msg_id: Option<u64>,
+    pub account_id: u32,
+    // set for balance mismatches
+    pub token_id: Option<u32>,
+    // set for order mismatches
+    pub order_id: Option<u32>,
+    // exchange side
+    pub expected: String,
+    // rollup side
+    pub actual: String,
+}
+
+#[derive(Default)]
+pub struct Reconciler {
+    mode: ReconcileMode,
+    // one json line per mismatch
+    report: Option<BufWriter<File>>,
+    mismatch_num: usize,
+}
+
+impl Reconciler {
+    pub fn new(mode: ReconcileMode, report_file: Option<&str>) -> anyhow::Result<Self> {
+        let report = match report_file {
+            Some(path) => Some(BufWriter::new(std::fs::OpenOptions::new().create(true).append(true).open(path)?)),
+            None => None,
+        };
+        Ok(Self {
+            mode,
+            report,
+            mismatch_num: 0,
+        })
+    }
+    pub fn mode(&self) -> ReconcileMode {
+        self.mode
+    }
+    pub fn mismatch_num(&self) -> usize {
+        self.mismatch_num
+    }
+    pub fn should_halt_sealing(&self) -> bool {
+        self.mode == ReconcileMode::RecordAndHalt && self.mismatch_num > 0
+    }
+    pub fn record(&mut self, mismatch: Mismatch) {
+        if self.mode == ReconcileMode::Panic {
+            panic!("state mismatch {:?}", mismatch);
+        }
+        log::error!("state mismatch {:?}", mismatch);
+        self.mismatch_num += 1;
+        if let Some(report) = self.report.as_mut() {
+            // the report is best effort, the mismatch is logged anyway
+            if let Err(e) = write_line(report, &mismatch) {
+                log::error!("write reconcile report: {}", e);
+            }
+        }
+    }
+}
+
+fn write_line(report: &mut BufWriter<File>, mismatch: &Mismatch) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *report, mismatch)?;
+    report.write_all(b"\n")?;
+    report.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_reconciler_record() {
+    let path = std::env::temp_dir().join(format!("reconcile_{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut reconciler = Reconciler::new(ReconcileMode::RecordAndHalt, Some(path)).unwrap();
+    assert!(!reconciler.should_halt_sealing());
+    for msg_id in 1..=2 {
+        reconciler.record(Mismatch {
+            msg_id: Some(msg_id),
+            account_id: 3,
+            token_id: Some(0),
+            order_id: None,
+            expected: "1.5".to_string(),
+            actual: "1.4".to_string(),
+        });
+    }
+    assert_eq!(reconciler.mismatch_num(), 2);
+    assert!(reconciler.should_halt_sealing());
+
+    let report = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let lines: Vec<serde_json::Value> = report.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1]["msg_id"], 2);
+    assert_eq!(lines[1]["expected"], "1.5");
+}
@@ -0,0 +1,165 @@
+// The auditor rebuilds every tree from the raw leaves (balances and orders) and compares the result
+// with the roots cached in `AccountState` and in the live trees, so a corrupted state is found
+// before its witnesses reach the prover.
+use super::{AccountState, GlobalState};
+use crate::types::l2::Order;
+use crate::types::merkle_tree::Tree;
+use crate::types::primitives::{fr_to_string, Fr};
+use anyhow::bail;
+use ff::Field;
+use std::fmt;
+use std::io::BufRead;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditItem {
+    // `balance_root` cached in the account state
+    BalanceRoot,
+    // root of the live balance tree
+    BalanceTree,
+    // `order_root` cached in the account state
+    OrderRoot,
+    // root of the live order tree
+    OrderTree,
+    // account leaf in the account tree
+    AccountHash,
+    // root of the account tree, or the root recorded in a snapshot
+    StateRoot,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    // None for the state root
+    pub account_id: Option<u32>,
+    pub item: AuditItem,
+    pub recomputed: Fr,
+    pub stored: Fr,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.account_id {
+            Some(account_id) => write!(f, "account {} ", account_id)?,
+            None => write!(f, "state ")?,
+        }
+        write!(
+            f,
+            "{:?} diverges: recomputed {}, stored {}",
+            self.item,
+            fr_to_string(&self.recomputed),
+            fr_to_string(&self.stored)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditReport {
+    pub checked_accounts: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+impl AuditReport {
+    pub fn is_clean(&self) -> bool {
+        self.divergences.is_empty()
+    }
+    pub fn ensure_clean(&self) -> anyhow::Result<()> {
+        if let Some(first) = self.divergences.first() {
+            bail!("state audit found {} divergences, first: {}", self.divergences.len(), first);
+        }
+        Ok(())
+    }
+    fn compare(&mut self, account_id: Option<u32>, item: AuditItem, recomputed: Fr, stored: Fr) {
+        if recomputed != stored {
+            self.divergences.push(Divergence {
+                account_id,
+                item,
+                recomputed,
+                stored,
+            });
+        }
+    }
+}
+
+impl GlobalState {
+    pub fn audit(&self) -> AuditReport {
+        let mut report = AuditReport::default();
+        let default_order_leaf = Order::default().hash();
+        let mut account_tree = Tree::new(self.account_levels(), self.empty_account_hash());
+        for account_id in self.account_ids() {
+            let id = Some(account_id);
+            let cached = self.get_account(account_id);
+
+            let mut balance_tree = Tree::new(self.balance_levels(), Fr::zero());
+            for (token_id, balance) in self.token_balances(account_id) {
+                balance_tree.set_value(token_id, balance);
+            }
+            let balance_root = balance_tree.get_root();
+            report.compare(id, AuditItem::BalanceRoot, balance_root, cached.balance_root);
+            report.compare(id, AuditItem::BalanceTree, balance_root, self.balance_proof(account_id, 0).root);
+
+            let mut order_tree = Tree::new(self.order_levels(), default_order_leaf);
+            for (order_pos, order) in self.account_orders(account_id) {
+                order_tree.set_value(order_pos, order.hash());
+            }
+            let order_root = order_tree.get_root();
+            report.compare(id, AuditItem::OrderRoot, order_root, cached.order_root);
+            report.compare(id, AuditItem::OrderTree, order_root, self.order_proof(account_id, 0).root);
+
+            let account_hash = AccountState {
+                balance_root,
+                order_root,
+                ..cached
+            }
+            .hash();
+            report.compare(id, AuditItem::AccountHash, account_hash, self.account_proof(account_id).leaf);
+            account_tree.set_value(account_id, account_hash);
+            report.checked_accounts += 1;
+        }
+        report.compare(None, AuditItem::StateRoot, account_tree.get_root(), self.root());
+        report
+    }
+
+    // audits a snapshot file, also comparing the recomputed root with the one in its header
+    pub fn audit_snapshot<R: BufRead>(reader: R) -> anyhow::Result<AuditReport> {
+        let (state, header) = Self::load_snapshot(reader, false)?;
+        let mut report = state.audit();
+        report.compare(None, AuditItem::StateRoot, state.root(), header.root);
+        Ok(report)
+    }
+
+    fn empty_account_hash(&self) -> Fr {
+        let empty_balance_root = Tree::new(self.balance_levels(), Fr::zero()).get_root();
+        let empty_order_root = Tree::new(self.order_levels(), Order::default().hash()).get_root();
+        AccountState::empty(empty_balance_root, empty_order_root).hash()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_audit_state() {
+    use crate::types::primitives::u64_to_fr;
+
+    let mut state = GlobalState::new(2, 2, 2, false);
+    state.set_token_balance(1, 0, u64_to_fr(100));
+    state.set_token_balance(2, 1, u64_to_fr(5));
+    let report = state.audit();
+    assert_eq!(report.checked_accounts, 2);
+    report.ensure_clean().unwrap();
+
+    let mut snapshot = Vec::new();
+    state.export_snapshot(0, &mut snapshot).unwrap();
+    assert!(GlobalState::audit_snapshot(&snapshot[..]).unwrap().is_clean());
+
+    // a raw leaf update skips the cached roots and the account tree
+    state.set_token_balance_raw(2, 1, u64_to_fr(6));
+    let report = state.audit();
+    let items: Vec<_> = report.divergences.iter().map(|d| (d.account_id, d.item)).collect();
+    assert_eq!(
+        items,
+        vec![
+            (Some(2), AuditItem::BalanceRoot),
+            (Some(2), AuditItem::AccountHash),
+            (None, AuditItem::StateRoot)
+        ]
+    );
+    assert!(report.ensure_clean().is_err());
+}
@@ -1,11 +1,11 @@
 use crate::account::{Account, Signature};
 use crate::state::global::GlobalState;
 use crate::state::witness_generator::WitnessGenerator;
-use crate::test_utils::types::prec_token_id;
 use crate::test_utils::{CircuitTestData, L2BlockSerde};
 use crate::types::fixnum::decimal_to_amount;
 use crate::types::l2::{self, DepositTx, L2Key, Order, SpotTradeTx, TransferTx, WithdrawTx};
 use crate::types::primitives::{u32_to_fr, Fr};
+use crate::types::token::TokenRegistry;
 use ff::Field;
 use rust_decimal::Decimal;
 use serde_json::json;
@@ -57,6 +57,8 @@ impl Block {
 
         let token_id0 = 0;
         let token_id1 = 1;
+        let tokens = TokenRegistry::default();
+        let prec_token_id = |token_id| tokens.prec(token_id).unwrap();
 
         let account_id0 = witgen.create_new_account(1).unwrap();
         let account_id1 = witgen.create_new_account(1).unwrap();
@@ -80,7 +82,7 @@ impl Block {
             .deposit(DepositTx {
                 token_id: token_id0,
                 account_id: account_id0,
-                amount: decimal_to_amount(&Decimal::new(300, 0), prec_token_id(token_id0)),
+                amount: decimal_to_amount(&Decimal::new(300, 0), prec_token_id(token_id0)).unwrap(),
                 l2key: None,
             })
             .unwrap();
@@ -89,7 +91,7 @@ impl Block {
             account_id0,
             account_id1,
             token_id0,
-            decimal_to_amount(&Decimal::new(100, 0), prec_token_id(token_id0)),
+            decimal_to_amount(&Decimal::new(100, 0), prec_token_id(token_id0)).unwrap(),
         );
         transfer_tx0.l2key = Some(L2Key {
             eth_addr: account1.eth_addr(),
@@ -99,29 +101,29 @@ impl Block {
         transfer_tx0.from_nonce = witgen.get_account_nonce(account_id0);
         let hash = transfer_tx0.hash();
         transfer_tx0.sig = account0.sign_hash(hash).unwrap();
-        witgen.transfer(transfer_tx0);
+        witgen.transfer(transfer_tx0).unwrap();
 
         let mut transfer_tx1 = TransferTx::new(
             account_id1,
             account_id0,
             token_id0,
-            decimal_to_amount(&Decimal::new(50, 0), prec_token_id(token_id0)),
+            decimal_to_amount(&Decimal::new(50, 0), prec_token_id(token_id0)).unwrap(),
         );
         transfer_tx1.from_nonce = witgen.get_account_nonce(account_id1);
         let hash = transfer_tx1.hash();
         transfer_tx1.sig = account1.sign_hash(hash).unwrap();
-        witgen.transfer(transfer_tx1);
+        witgen.transfer(transfer_tx1).unwrap();
 
         let mut withdraw_tx = WithdrawTx::new(
             account_id0,
             token_id0,
-            decimal_to_amount(&Decimal::new(150, 0), prec_token_id(token_id0)),
+            decimal_to_amount(&Decimal::new(150, 0), prec_token_id(token_id0)).unwrap(),
         );
         witgen.fill_withdraw_tx(&mut withdraw_tx);
         let hash = withdraw_tx.hash();
         // hash = common.hashWithdraw(fullWithdrawTx);
         withdraw_tx.sig = account0.sign_hash(hash).unwrap();
-        witgen.withdraw(withdraw_tx);
+        witgen.withdraw(withdraw_tx).unwrap();
 
         // trade amount
         let amount_1to2 = 120;
@@ -131,7 +133,7 @@ impl Block {
             .deposit(DepositTx {
                 account_id: account_id1,
                 token_id: token_id0,
-                amount: decimal_to_amount(&Decimal::new(199, 0), prec_token_id(token_id0)),
+                amount: decimal_to_amount(&Decimal::new(199, 0), prec_token_id(token_id0)).unwrap(),
                 l2key: None,
             })
             .unwrap();
@@ -139,7 +141,7 @@ impl Block {
             .deposit(DepositTx {
                 account_id: account_id2,
                 token_id: token_id1,
-                amount: decimal_to_amount(&Decimal::new(1990, 0), prec_token_id(token_id1)),
+                amount: decimal_to_amount(&Decimal::new(1990, 0), prec_token_id(token_id1)).unwrap(),
                 l2key: Some(L2Key {
                     eth_addr: account2.eth_addr(),
                     sign: account2.sign(),
@@ -154,8 +156,10 @@ impl Block {
             order_id: order_id1,
             token_buy: u32_to_fr(token_id1),
             token_sell: u32_to_fr(token_id0),
-            total_buy: decimal_to_amount(&Decimal::new(10000, 0), prec_token_id(token_id1)).to_fr(),
-            total_sell: decimal_to_amount(&Decimal::new(1000, 0), prec_token_id(token_id0)).to_fr(),
+            total_buy: decimal_to_amount(&Decimal::new(10000, 0), prec_token_id(token_id1))
+                .unwrap()
+                .to_fr(),
+            total_sell: decimal_to_amount(&Decimal::new(1000, 0), prec_token_id(token_id0)).unwrap().to_fr(),
             filled_buy: Fr::zero(),
             filled_sell: Fr::zero(),
             sig: Signature::default(),
@@ -174,8 +178,10 @@ impl Block {
             order_id: order_id2,
             token_buy: u32_to_fr(token_id0),
             token_sell: u32_to_fr(token_id1),
-            total_buy: decimal_to_amount(&Decimal::new(1000, 0), prec_token_id(token_id0)).to_fr(),
-            total_sell: decimal_to_amount(&Decimal::new(10000, 0), prec_token_id(token_id1)).to_fr(),
+            total_buy: decimal_to_amount(&Decimal::new(1000, 0), prec_token_id(token_id0)).unwrap().to_fr(),
+            total_sell: decimal_to_amount(&Decimal::new(10000, 0), prec_token_id(token_id1))
+                .unwrap()
+                .to_fr(),
             filled_buy: Fr::zero(),
             filled_sell: Fr::zero(),
             sig: Signature::default(),
@@ -189,8 +195,8 @@ impl Block {
             order2_account_id: account_id2,
             token_id_1to2: token_id0,
             token_id_2to1: token_id1,
-            amount_1to2: decimal_to_amount(&Decimal::new(amount_1to2, 0), prec_token_id(token_id0)),
-            amount_2to1: decimal_to_amount(&Decimal::new(amount_2to1, 0), prec_token_id(token_id1)),
+            amount_1to2: decimal_to_amount(&Decimal::new(amount_1to2, 0), prec_token_id(token_id0)).unwrap(),
+            amount_2to1: decimal_to_amount(&Decimal::new(amount_2to1, 0), prec_token_id(token_id1)).unwrap(),
             order1_id: order_id1,
             order2_id: order_id2,
         };
@@ -201,9 +207,9 @@ impl Block {
             maker_order: Some(order1),
             taker_order: Some(order2),
         };
-        witgen.full_spot_trade(full_trade);
+        witgen.full_spot_trade(full_trade).unwrap();
 
-        witgen.flush_with_nop();
+        witgen.force_seal();
         receiver
             .try_iter()
             .enumerate()
@@ -222,7 +228,7 @@ impl Block {
         // we need to have at least 1 account
         witgen.create_new_account(1).unwrap();
         witgen.nop();
-        witgen.flush_with_nop();
+        witgen.force_seal();
         let block = receiver.recv().unwrap();
         CircuitTestData {
             name: "empty_block".to_owned(),
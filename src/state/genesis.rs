@@ -0,0 +1,210 @@
+// Genesis describes the state before the first block: accounts with their L2 keys, nonces,
+// balances and open orders. Amounts are raw rollup integers, not decimals.
+use super::GlobalState;
+use crate::config::Settings;
+use crate::types::amount::Amount;
+use crate::types::l2::{Order, OrderSide};
+use crate::types::primitives::{fr_str, fr_to_string, u32_to_fr, u64_to_fr, Fr};
+use anyhow::{anyhow, bail};
+use ff::Field;
+use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+
+fn zero() -> Fr {
+    Fr::zero()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisBalance {
+    pub token_id: u32,
+    #[serde(with = "fr_str")]
+    pub balance: Fr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisOrder {
+    pub order_id: u32,
+    pub side: OrderSide,
+    pub token_sell: u32,
+    pub token_buy: u32,
+    #[serde(with = "fr_str")]
+    pub total_sell: Fr,
+    #[serde(with = "fr_str")]
+    pub total_buy: Fr,
+    #[serde(with = "fr_str", default = "zero")]
+    pub filled_sell: Fr,
+    #[serde(with = "fr_str", default = "zero")]
+    pub filled_buy: Fr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisAccount {
+    pub account_id: u32,
+    #[serde(with = "fr_str")]
+    pub sign: Fr,
+    #[serde(with = "fr_str")]
+    pub ay: Fr,
+    #[serde(with = "fr_str")]
+    pub eth_addr: Fr,
+    #[serde(default)]
+    pub nonce: u64,
+    #[serde(default)]
+    pub balances: Vec<GenesisBalance>,
+    #[serde(default)]
+    pub orders: Vec<GenesisOrder>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Genesis {
+    // decimal string of the expected state root, checked after loading if present
+    #[serde(default)]
+    pub root: Option<String>,
+    pub accounts: Vec<GenesisAccount>,
+}
+
+impl Genesis {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path).map_err(|e| anyhow!("open genesis file {}: {}", path, e))?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    // builds the initial state with the tree heights of `settings`
+    pub fn build_state(&self, settings: &Settings) -> anyhow::Result<GlobalState> {
+        let mut state = GlobalState::new(
+            settings.balance_levels,
+            settings.order_levels,
+            settings.account_levels,
+            settings.verbose,
+        );
+        let mut account_ids = FnvHashSet::default();
+        for account in &self.accounts {
+            if !account_ids.insert(account.account_id) {
+                bail!("duplicated genesis account {}", account.account_id);
+            }
+            Self::check_account(account, settings)?;
+            Self::add_account(&mut state, account)?;
+        }
+        let root = fr_to_string(&state.root());
+        if let Some(expected) = &self.root {
+            if *expected != root {
+                bail!("genesis root mismatch, expected {}, got {}", expected, root);
+            }
+        }
+        Ok(state)
+    }
+
+    fn check_account(account: &GenesisAccount, settings: &Settings) -> anyhow::Result<()> {
+        let account_id = account.account_id;
+        // accounts without a L2 key are treated as empty by the state
+        if account.ay.is_zero() {
+            bail!("genesis account {} has no L2 key", account_id);
+        }
+        let max_token_id = 1u64 << settings.balance_levels;
+        let mut token_ids = FnvHashSet::default();
+        for balance in &account.balances {
+            if balance.token_id as u64 >= max_token_id {
+                bail!(
+                    "token {} of genesis account {} exceeds the balance tree",
+                    balance.token_id,
+                    account_id
+                );
+            }
+            if !token_ids.insert(balance.token_id) {
+                bail!("duplicated token {} of genesis account {}", balance.token_id, account_id);
+            }
+            Amount::new(balance.balance)?;
+        }
+        if account.orders.len() as u64 > 1u64 << settings.order_levels {
+            bail!("too many orders for genesis account {}", account_id);
+        }
+        let mut order_ids = FnvHashSet::default();
+        for order in &account.orders {
+            // order id 0 marks empty order slots
+            if order.order_id == 0 || !order_ids.insert(order.order_id) {
+                bail!("invalid order id {} of genesis account {}", order.order_id, account_id);
+            }
+            if order.token_sell as u64 >= max_token_id || order.token_buy as u64 >= max_token_id {
+                bail!("order {} of genesis account {} has invalid tokens", order.order_id, account_id);
+            }
+            // like in the circuit, an order with a zero total is filled already
+            if order.total_sell.is_zero() || order.total_buy.is_zero() {
+                bail!("order {} of genesis account {} has a zero total", order.order_id, account_id);
+            }
+            // filling the order from zero runs the same overflow checks as trades
+            let mut filled = Self::to_order(account_id, order);
+            filled.filled_sell = Fr::zero();
+            filled.filled_buy = Fr::zero();
+            filled.trade_with(&order.filled_sell, &order.filled_buy)?;
+        }
+        Ok(())
+    }
+
+    fn add_account(state: &mut GlobalState, account: &GenesisAccount) -> anyhow::Result<()> {
+        let account_id = account.account_id;
+        state.init_account(account_id, 1)?;
+        state.set_account_l2_addr(account_id, account.sign, account.ay, account.eth_addr);
+        state.set_account_nonce(account_id, u64_to_fr(account.nonce));
+        for balance in &account.balances {
+            state.set_token_balance(account_id, balance.token_id, balance.balance);
+        }
+        for order in &account.orders {
+            let order = Self::to_order(account_id, order);
+            let (order_pos, _) = state.find_or_insert_order(account_id, &order)?;
+            state.set_account_order(account_id, order_pos, order);
+        }
+        Ok(())
+    }
+
+    fn to_order(account_id: u32, order: &GenesisOrder) -> Order {
+        Order {
+            account_id,
+            order_id: order.order_id,
+            side: order.side,
+            token_sell: u32_to_fr(order.token_sell),
+            token_buy: u32_to_fr(order.token_buy),
+            total_sell: order.total_sell,
+            total_buy: order.total_buy,
+            filled_sell: order.filled_sell,
+            filled_buy: order.filled_buy,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_genesis_state() {
+    let json = r#"{
+        "accounts": [{
+            "account_id": 1,
+            "sign": "1",
+            "ay": "987657654765",
+            "eth_addr": "1223232332323233",
+            "nonce": 3,
+            "balances": [{"token_id": 0, "balance": "1000000"}, {"token_id": 1, "balance": "20"}],
+            "orders": [{"order_id": 5, "side": "Sell", "token_sell": 0, "token_buy": 1, "total_sell": "100", "total_buy": "200", "filled_sell": "50"}]
+        }]
+    }"#;
+    let settings = Settings::default();
+    let mut genesis: Genesis = serde_json::from_str(json).unwrap();
+    let mut state = genesis.build_state(&settings).unwrap();
+    assert!(state.has_account(1));
+    assert_eq!(state.get_account_nonce(1), u64_to_fr(3));
+    assert_eq!(state.get_token_balance(1, 0), u64_to_fr(1000000));
+    assert_eq!(state.get_account_order_by_id(1, 5).filled_sell, u64_to_fr(50));
+    // ids are sparse without account 0, new accounts never collide with genesis ones
+    assert_eq!(state.create_new_account(1).unwrap(), 2);
+    assert_eq!(state.create_new_account(1).unwrap(), 3);
+    assert_eq!(state.get_token_balance(1, 0), u64_to_fr(1000000));
+
+    // the root is deterministic, and a wrong one is rejected
+    let state = genesis.build_state(&settings).unwrap();
+    genesis.root = Some(fr_to_string(&state.root()));
+    genesis.build_state(&settings).unwrap();
+    genesis.root = Some("1".to_string());
+    assert!(genesis.build_state(&settings).is_err());
+
+    genesis.root = None;
+    genesis.accounts[0].orders[0].filled_sell = u64_to_fr(101);
+    assert!(genesis.build_state(&settings).is_err());
+}
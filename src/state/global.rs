@@ -117,6 +117,49 @@ impl GlobalState {
     pub fn root(&self) -> Fr {
         self.account_tree.lock().unwrap().get_root()
     }
+    pub fn balance_levels(&self) -> usize {
+        self.balance_levels
+    }
+    pub fn order_levels(&self) -> usize {
+        self.order_levels
+    }
+    pub fn account_levels(&self) -> usize {
+        self.account_levels
+    }
+    // ids of all initialized accounts, in ascending order
+    pub fn account_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.accounts.keys().cloned().collect();
+        ids.sort_unstable();
+        ids
+    }
+    // non-zero balances of an account, in ascending token order
+    pub fn token_balances(&self, account_id: u32) -> Vec<(u32, Fr)> {
+        let mut balances: Vec<(u32, Fr)> = match self.balance_trees.get(&account_id) {
+            Some(tree) => tree
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, balance)| !balance.is_zero())
+                .map(|(token_id, balance)| (token_id, *balance))
+                .collect(),
+            None => Vec::new(),
+        };
+        balances.sort_unstable_by_key(|(token_id, _)| *token_id);
+        balances
+    }
+    // (order_pos, order) of all orders stored for an account, in ascending position order
+    pub fn account_orders(&self, account_id: u32) -> Vec<(u32, Order)> {
+        self.order_map
+            .get(&account_id)
+            .map(|orders| orders.iter().map(|(pos, order)| (*pos, *order)).collect())
+            .unwrap_or_default()
+    }
+    pub fn next_order_pos(&self, account_id: u32) -> u32 {
+        self.next_order_positions
+            .get(&account_id)
+            .cloned()
+            .unwrap_or(self.default_next_order_id)
+    }
     fn recalculate_account_state_hash(&mut self, account_id: u32) -> Fr {
         let mut acc = self.accounts.get_mut(&account_id).unwrap();
         // TODO: for balance_root/order_root, we maintain two 'truth' here
@@ -168,7 +211,7 @@ impl GlobalState {
 
     // find a position range 0..2**n where the slot is either empty or occupied by a close order
     // so we can place the new order here
-    fn get_next_order_pos_for_user(&mut self, account_id: u32, order_id: u32) -> u32 {
+    fn get_next_order_pos_for_user(&self, account_id: u32, order_id: u32) -> anyhow::Result<u32> {
         let start_pos = *self.next_order_positions.get(&account_id).unwrap();
         for i in 0..2u32.pow(self.order_levels as u32) {
             let candidate_pos = (start_pos + i) % 2u32.pow(self.order_levels as u32);
@@ -178,24 +221,38 @@ impl GlobalState {
                 // the order is already in the tree, so why here...
                 assert_ne!(order_id, order.order_id);
                 if order.order_id < order_id {
-                    self.next_order_positions.insert(account_id, candidate_pos + 1);
-                    return candidate_pos;
+                    return Ok(candidate_pos);
                 }
             }
         }
-        panic!("Cannot find order pos, please use larger order tree height");
+        bail!("no free order slot for order {} of account {}", order_id, account_id);
+    }
+    // the position of the order, or the one it would be placed at, without placing it
+    pub fn find_order_pos(&self, account_id: u32, order_id: u32) -> anyhow::Result<u32> {
+        match self.get_order_pos_by_id(account_id, order_id) {
+            Some(pos) => Ok(pos),
+            None => self.get_next_order_pos_for_user(account_id, order_id),
+        }
     }
+    // ids loaded from a genesis file or a snapshot may be sparse, so the next id follows the largest one
     pub fn get_next_account_id(&self) -> anyhow::Result<u32> {
-        let account_id = self.balance_trees.len() as u32;
+        let account_id = self.accounts.keys().max().map(|id| id + 1).unwrap_or(0);
         if account_id >= 2u32.pow(self.account_levels as u32) {
             bail!("account_id {} overflows for account_levels {}", account_id, self.account_levels);
         }
         Ok(account_id)
     }
-    fn init_account(&mut self, account_id: u32, next_order_id: u32) -> anyhow::Result<u32> {
+    // does nothing for an existing account
+    pub(crate) fn init_account(&mut self, account_id: u32, next_order_id: u32) -> anyhow::Result<u32> {
         if self.accounts.contains_key(&account_id) {
             return Ok(account_id);
         }
+        self.insert_account(account_id, next_order_id)
+    }
+    fn insert_account(&mut self, account_id: u32, next_order_id: u32) -> anyhow::Result<u32> {
+        if self.accounts.contains_key(&account_id) {
+            bail!("account {} exists already", account_id);
+        }
         if account_id >= 2u32.pow(self.account_levels as u32) {
             bail!("account_id {} overflows for account_levels {}", account_id, self.account_levels);
         }
@@ -214,7 +271,7 @@ impl GlobalState {
     }
     pub fn create_new_account(&mut self, next_order_id: u32) -> anyhow::Result<u32> {
         let account_id = self.get_next_account_id()?;
-        self.init_account(account_id, next_order_id)
+        self.insert_account(account_id, next_order_id)
     }
     pub fn get_order_pos_by_id(&self, account_id: u32, order_id: u32) -> Option<u32> {
         self.order_id_to_pos.get(&(account_id, order_id)).cloned()
@@ -243,16 +300,17 @@ impl GlobalState {
     pub fn update_order_state(&mut self, account_id: u32, order_pos: u32, order: Order) {
         self.order_map.get_mut(&account_id).unwrap().insert(order_pos, order);
     }
-    pub fn find_or_insert_order(&mut self, account_id: u32, order: &Order) -> (u32, Order) {
+    pub fn find_or_insert_order(&mut self, account_id: u32, order: &Order) -> anyhow::Result<(u32, Order)> {
         let order_id = order.order_id;
         match self.get_order_pos_by_id(account_id, order_id) {
-            Some(pos) => (pos, self.get_account_order_by_pos(account_id, pos)),
+            Some(pos) => Ok((pos, self.get_account_order_by_pos(account_id, pos))),
             None => {
-                let pos = self.get_next_order_pos_for_user(account_id, order_id);
+                let pos = self.get_next_order_pos_for_user(account_id, order_id)?;
+                self.next_order_positions.insert(account_id, pos + 1);
                 // old_order may be empty
                 let old_order = self.get_account_order_by_pos(account_id, pos);
                 self.link_order_pos_and_id(account_id, pos, order_id);
-                (pos, old_order)
+                Ok((pos, old_order))
             }
         }
     }
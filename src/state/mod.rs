@@ -1,9 +1,19 @@
 pub mod account;
+pub mod audit;
 pub mod block;
+pub mod genesis;
 pub mod global;
+pub mod pending_queue;
+pub mod snapshot;
+pub mod solvency;
 pub mod witness_generator;
 
 pub use account::AccountState;
+pub use audit::AuditReport;
 pub use block::Block;
+pub use genesis::Genesis;
 pub use global::GlobalState;
-pub use witness_generator::WitnessGenerator;
+pub use pending_queue::PendingQueue;
+pub use snapshot::SnapshotHeader;
+pub use solvency::{Ledger, SolvencyReport};
+pub use witness_generator::{SealPolicy, WitnessGenerator};
@@ -0,0 +1,109 @@
+use anyhow::bail;
+use fnv::FnvHashMap;
+use std::collections::BTreeMap;
+
+// PendingQueue holds user txs whose nonce is ahead of the account nonce,
+// so they can be applied once the nonce gap is filled
+pub struct PendingQueue<T> {
+    max_per_account: usize,
+    // account_id -> nonce -> tx
+    queues: FnvHashMap<u32, BTreeMap<u64, T>>,
+}
+
+impl<T> PendingQueue<T> {
+    pub fn new(max_per_account: usize) -> Self {
+        Self {
+            max_per_account,
+            queues: FnvHashMap::default(),
+        }
+    }
+    pub fn push(&mut self, account_id: u32, nonce: u64, tx: T) -> anyhow::Result<()> {
+        let queue = self.queues.entry(account_id).or_default();
+        if queue.contains_key(&nonce) {
+            bail!("tx with nonce {} already pending for account {}", nonce, account_id);
+        }
+        if queue.len() >= self.max_per_account {
+            bail!("too many pending txs for account {}", account_id);
+        }
+        queue.insert(nonce, tx);
+        Ok(())
+    }
+    // take the tx with exactly `nonce`; txs with smaller nonces can never be applied, so they are dropped
+    pub fn pop(&mut self, account_id: u32, nonce: u64) -> Option<T> {
+        self.drain_below(account_id, nonce);
+        let queue = self.queues.get_mut(&account_id)?;
+        let tx = queue.remove(&nonce);
+        if queue.is_empty() {
+            self.queues.remove(&account_id);
+        }
+        tx
+    }
+    // remove the txs of the account with a nonce smaller than `nonce`
+    pub fn drain_below(&mut self, account_id: u32, nonce: u64) -> Vec<T> {
+        let queue = match self.queues.get_mut(&account_id) {
+            Some(queue) => queue,
+            None => return Vec::new(),
+        };
+        let remained = queue.split_off(&nonce);
+        let drained = std::mem::replace(queue, remained);
+        if queue.is_empty() {
+            self.queues.remove(&account_id);
+        }
+        drained.into_iter().map(|(_, tx)| tx).collect()
+    }
+    pub fn remove_account(&mut self, account_id: u32) -> Vec<T> {
+        self.queues
+            .remove(&account_id)
+            .map(|queue| queue.into_iter().map(|(_, tx)| tx).collect())
+            .unwrap_or_default()
+    }
+    // remove the txs matching `expired` from every account
+    pub fn drain_expired(&mut self, expired: impl Fn(&T) -> bool) -> Vec<T> {
+        let mut drained = Vec::new();
+        for queue in self.queues.values_mut() {
+            let nonces: Vec<u64> = queue.iter().filter(|(_, tx)| expired(tx)).map(|(nonce, _)| *nonce).collect();
+            drained.extend(nonces.iter().filter_map(|nonce| queue.remove(nonce)));
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+        drained
+    }
+    pub fn account_len(&self, account_id: u32) -> usize {
+        self.queues.get(&account_id).map(BTreeMap::len).unwrap_or(0)
+    }
+    pub fn len(&self) -> usize {
+        self.queues.values().map(BTreeMap::len).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_pending_queue() {
+    let mut queue = PendingQueue::new(2);
+    queue.push(1, 3, "tx3").unwrap();
+    queue.push(1, 5, "tx5").unwrap();
+    assert!(queue.push(1, 3, "dup").is_err());
+    assert!(queue.push(1, 4, "full").is_err());
+    queue.push(2, 1, "other").unwrap();
+    assert_eq!(queue.len(), 3);
+
+    assert_eq!(queue.pop(1, 2), None);
+    assert_eq!(queue.pop(1, 3), Some("tx3"));
+    assert_eq!(queue.pop(1, 4), None);
+    // nonce 5 is still reachable
+    assert_eq!(queue.account_len(1), 1);
+    assert_eq!(queue.pop(1, 6), None);
+    assert_eq!(queue.account_len(1), 0);
+    assert_eq!(queue.pop(2, 1), Some("other"));
+    assert!(queue.is_empty());
+
+    queue.push(1, 3, "tx3").unwrap();
+    queue.push(1, 5, "tx5").unwrap();
+    queue.push(2, 1, "other").unwrap();
+    assert_eq!(queue.drain_below(1, 5), vec!["tx3"]);
+    assert_eq!(queue.drain_expired(|tx| *tx == "other"), vec!["other"]);
+    assert_eq!(queue.remove_account(1), vec!["tx5"]);
+    assert!(queue.is_empty());
+}
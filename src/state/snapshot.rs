@@ -0,0 +1,283 @@
+// A portable snapshot of the GlobalState, as JSON lines: the first line is a `SnapshotHeader`,
+// followed by one `SnapshotRecord` per line. Records of an account always start with its
+// `Account` record, so a snapshot can be streamed without holding it in memory.
+use super::{GlobalState, Ledger};
+use crate::account::Signature;
+use crate::msg::dedup::ProcessedMsgs;
+use crate::types::l2::{Order, OrderSide};
+use crate::types::primitives::{fr_str, fr_to_string, Fr};
+use anyhow::{anyhow, bail};
+use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub version: u32,
+    pub balance_levels: usize,
+    pub order_levels: usize,
+    pub account_levels: usize,
+    #[serde(with = "fr_str")]
+    pub root: Fr,
+    // number of blocks generated before the snapshot
+    pub block_number: u64,
+    // hash of the last generated block, only set in replay checkpoints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_hash: Option<String>,
+    // messages applied to the state, only set in replay checkpoints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processed_msgs: Option<ProcessedMsgs>,
+    // deposits and withdrawals seen up to the snapshot, only set in replay checkpoints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ledger: Option<Ledger>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SnapshotRecord {
+    Account {
+        account_id: u32,
+        #[serde(with = "fr_str")]
+        nonce: Fr,
+        #[serde(with = "fr_str")]
+        sign: Fr,
+        #[serde(with = "fr_str")]
+        ay: Fr,
+        #[serde(with = "fr_str")]
+        eth_addr: Fr,
+        next_order_pos: u32,
+    },
+    Balance {
+        account_id: u32,
+        token_id: u32,
+        #[serde(with = "fr_str")]
+        balance: Fr,
+    },
+    Order {
+        account_id: u32,
+        order_pos: u32,
+        order_id: u32,
+        side: OrderSide,
+        #[serde(with = "fr_str")]
+        token_sell: Fr,
+        #[serde(with = "fr_str")]
+        token_buy: Fr,
+        #[serde(with = "fr_str")]
+        total_sell: Fr,
+        #[serde(with = "fr_str")]
+        total_buy: Fr,
+        #[serde(with = "fr_str")]
+        filled_sell: Fr,
+        #[serde(with = "fr_str")]
+        filled_buy: Fr,
+        sig: Signature,
+    },
+}
+
+fn write_line<W: Write, T: Serialize>(writer: &mut W, value: &T) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *writer, value)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+impl GlobalState {
+    pub fn snapshot_header(&self, block_number: u64) -> SnapshotHeader {
+        SnapshotHeader {
+            version: SNAPSHOT_VERSION,
+            balance_levels: self.balance_levels(),
+            order_levels: self.order_levels(),
+            account_levels: self.account_levels(),
+            root: self.root(),
+            block_number,
+            parent_hash: None,
+            processed_msgs: None,
+            ledger: None,
+        }
+    }
+    pub fn export_snapshot<W: Write>(&self, block_number: u64, writer: W) -> anyhow::Result<()> {
+        self.export_snapshot_with_header(&self.snapshot_header(block_number), writer)
+    }
+    // the header should come from `snapshot_header`, optionally extended with the replay position
+    pub fn export_snapshot_with_header<W: Write>(&self, header: &SnapshotHeader, mut writer: W) -> anyhow::Result<()> {
+        write_line(&mut writer, header)?;
+        for account_id in self.account_ids() {
+            let account = self.get_account(account_id);
+            let record = SnapshotRecord::Account {
+                account_id,
+                nonce: account.nonce,
+                sign: account.sign,
+                ay: account.ay,
+                eth_addr: account.eth_addr,
+                next_order_pos: self.next_order_pos(account_id),
+            };
+            write_line(&mut writer, &record)?;
+            for (token_id, balance) in self.token_balances(account_id) {
+                write_line(
+                    &mut writer,
+                    &SnapshotRecord::Balance {
+                        account_id,
+                        token_id,
+                        balance,
+                    },
+                )?;
+            }
+            for (order_pos, order) in self.account_orders(account_id) {
+                let record = SnapshotRecord::Order {
+                    account_id,
+                    order_pos,
+                    order_id: order.order_id,
+                    side: order.side,
+                    token_sell: order.token_sell,
+                    token_buy: order.token_buy,
+                    total_sell: order.total_sell,
+                    total_buy: order.total_buy,
+                    filled_sell: order.filled_sell,
+                    filled_buy: order.filled_buy,
+                    sig: order.sig,
+                };
+                write_line(&mut writer, &record)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // the imported root must match the root recorded in the header
+    pub fn import_snapshot<R: BufRead>(reader: R, verbose: bool) -> anyhow::Result<(GlobalState, SnapshotHeader)> {
+        let (state, header) = Self::load_snapshot(reader, verbose)?;
+        if state.root() != header.root {
+            bail!(
+                "snapshot root mismatch, expected {}, got {}",
+                fr_to_string(&header.root),
+                fr_to_string(&state.root())
+            );
+        }
+        Ok((state, header))
+    }
+
+    // loads all records without checking the root, so the auditor can inspect a corrupted snapshot
+    pub(crate) fn load_snapshot<R: BufRead>(reader: R, verbose: bool) -> anyhow::Result<(GlobalState, SnapshotHeader)> {
+        let mut lines = reader.lines();
+        let header_line = lines.next().ok_or_else(|| anyhow!("empty snapshot"))??;
+        let header: SnapshotHeader = serde_json::from_str(&header_line)?;
+        if header.version != SNAPSHOT_VERSION {
+            bail!("unsupported snapshot version {}, expected {}", header.version, SNAPSHOT_VERSION);
+        }
+        let mut state = GlobalState::new(header.balance_levels, header.order_levels, header.account_levels, verbose);
+        let mut account_ids = FnvHashSet::default();
+        for (idx, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: SnapshotRecord = serde_json::from_str(&line).map_err(|e| anyhow!("invalid snapshot record {}: {}", idx + 1, e))?;
+            state.apply_snapshot_record(record, &mut account_ids)?;
+        }
+        Ok((state, header))
+    }
+
+    // records of an account must follow its account record
+    fn apply_snapshot_record(&mut self, record: SnapshotRecord, account_ids: &mut FnvHashSet<u32>) -> anyhow::Result<()> {
+        match record {
+            SnapshotRecord::Account {
+                account_id,
+                nonce,
+                sign,
+                ay,
+                eth_addr,
+                next_order_pos,
+            } => {
+                if !account_ids.insert(account_id) {
+                    bail!("duplicated snapshot account {}", account_id);
+                }
+                self.init_account(account_id, next_order_pos)?;
+                self.set_account_l2_addr(account_id, sign, ay, eth_addr);
+                self.set_account_nonce(account_id, nonce);
+            }
+            SnapshotRecord::Balance {
+                account_id,
+                token_id,
+                balance,
+            } => {
+                if !account_ids.contains(&account_id) {
+                    bail!("snapshot record of unknown account {}", account_id);
+                }
+                if token_id >= 1u32 << self.balance_levels() {
+                    bail!("token {} exceeds the balance tree", token_id);
+                }
+                self.set_token_balance(account_id, token_id, balance);
+            }
+            SnapshotRecord::Order {
+                account_id,
+                order_pos,
+                order_id,
+                side,
+                token_sell,
+                token_buy,
+                total_sell,
+                total_buy,
+                filled_sell,
+                filled_buy,
+                sig,
+            } => {
+                if !account_ids.contains(&account_id) {
+                    bail!("snapshot record of unknown account {}", account_id);
+                }
+                if order_pos >= 1u32 << self.order_levels() {
+                    bail!("order position {} exceeds the order tree", order_pos);
+                }
+                let order = Order {
+                    account_id,
+                    order_id,
+                    side,
+                    token_sell,
+                    token_buy,
+                    total_sell,
+                    total_buy,
+                    filled_sell,
+                    filled_buy,
+                    sig,
+                };
+                self.link_order_pos_and_id(account_id, order_pos, order_id);
+                self.set_account_order(account_id, order_pos, order);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot_round_trip() {
+    use crate::config::Settings;
+    use crate::state::Genesis;
+    use crate::types::primitives::u64_to_fr;
+
+    let json = r#"{
+        "accounts": [{
+            "account_id": 2,
+            "sign": "1",
+            "ay": "987657654765",
+            "eth_addr": "1223232332323233",
+            "nonce": 7,
+            "balances": [{"token_id": 1, "balance": "1000000"}],
+            "orders": [{"order_id": 3, "side": "Buy", "token_sell": 1, "token_buy": 0, "total_sell": "100", "total_buy": "2", "filled_buy": "1"}]
+        }]
+    }"#;
+    let genesis: Genesis = serde_json::from_str(json).unwrap();
+    let state = genesis.build_state(&Settings::default()).unwrap();
+
+    let mut exported = Vec::new();
+    state.export_snapshot(5, &mut exported).unwrap();
+    let (imported, header) = GlobalState::import_snapshot(&exported[..], false).unwrap();
+    assert_eq!(header.block_number, 5);
+    assert_eq!(imported.root(), state.root());
+    assert_eq!(imported.get_account_nonce(2), u64_to_fr(7));
+    assert_eq!(imported.get_account_order_by_id(2, 3), state.get_account_order_by_id(2, 3));
+
+    // a tampered balance no longer matches the root
+    let text = String::from_utf8(exported).unwrap().replace("1000000", "1000001");
+    assert!(GlobalState::import_snapshot(text.as_bytes(), false).is_err());
+}
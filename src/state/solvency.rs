@@ -0,0 +1,219 @@
+// Proof of liabilities: the sum of all rollup balances of a token should equal the deposits minus
+// the withdrawals of the token seen by the exchange, and every user can check their own balances
+// against the published root with a merkle inclusion proof.
+use super::{AccountState, GlobalState};
+use crate::types::primitives::{fr_str, fr_to_bigint, fr_to_string, hash, Fr};
+use num_bigint::BigInt;
+use num_traits::Zero;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// deposits and withdrawals per token, in raw rollup amounts
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ledger {
+    #[serde(with = "bigint_map")]
+    deposited: BTreeMap<u32, BigInt>,
+    #[serde(with = "bigint_map")]
+    withdrawn: BTreeMap<u32, BigInt>,
+}
+
+// totals as decimal strings, they may exceed the range of json numbers
+mod bigint_map {
+    use num_bigint::BigInt;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(value: &BTreeMap<u32, BigInt>, serializer: S) -> Result<S::Ok, S::Error> {
+        let totals: BTreeMap<u32, String> = value.iter().map(|(token_id, total)| (*token_id, total.to_string())).collect();
+        totals.serialize(serializer)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<u32, BigInt>, D::Error> {
+        BTreeMap::<u32, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(token_id, total)| Ok((token_id, total.parse().map_err(D::Error::custom)?)))
+            .collect()
+    }
+}
+
+impl Ledger {
+    pub fn record_deposit(&mut self, token_id: u32, amount: &Fr) {
+        *self.deposited.entry(token_id).or_insert_with(BigInt::zero) += fr_to_bigint(amount);
+    }
+    pub fn record_withdraw(&mut self, token_id: u32, amount: &Fr) {
+        *self.withdrawn.entry(token_id).or_insert_with(BigInt::zero) += fr_to_bigint(amount);
+    }
+    // deposits minus withdrawals, negative only if the ledger itself is broken
+    pub fn net(&self, token_id: u32) -> BigInt {
+        let zero = BigInt::zero();
+        self.deposited.get(&token_id).unwrap_or(&zero) - self.withdrawn.get(&token_id).unwrap_or(&zero)
+    }
+    pub fn token_ids(&self) -> impl Iterator<Item = &u32> {
+        self.deposited.keys().chain(self.withdrawn.keys())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenLiability {
+    pub token_id: u32,
+    // sum of the balances in the state
+    pub state_total: String,
+    // deposits minus withdrawals in the ledger
+    pub ledger_total: String,
+    pub matched: bool,
+}
+
+// everything needed to recompute the state root from a single balance
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceInclusion {
+    pub account_id: u32,
+    pub token_id: u32,
+    #[serde(with = "fr_str")]
+    pub balance: Fr,
+    #[serde(serialize_with = "serialize_path")]
+    pub balance_path: Vec<Fr>,
+    #[serde(with = "fr_str")]
+    pub nonce: Fr,
+    #[serde(with = "fr_str")]
+    pub sign: Fr,
+    #[serde(with = "fr_str")]
+    pub ay: Fr,
+    #[serde(with = "fr_str")]
+    pub eth_addr: Fr,
+    #[serde(with = "fr_str")]
+    pub order_root: Fr,
+    #[serde(serialize_with = "serialize_path")]
+    pub account_path: Vec<Fr>,
+}
+
+impl BalanceInclusion {
+    // recomputes the state root from the balance leaf
+    pub fn root(&self) -> Fr {
+        let balance_root = path_root(self.balance, self.token_id, &self.balance_path);
+        let account = AccountState {
+            nonce: self.nonce,
+            sign: self.sign,
+            balance_root,
+            ay: self.ay,
+            eth_addr: self.eth_addr,
+            order_root: self.order_root,
+        };
+        path_root(account.hash(), self.account_id, &self.account_path)
+    }
+}
+
+fn serialize_path<S: serde::Serializer>(path: &[Fr], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(path.iter().map(fr_to_string))
+}
+
+fn path_root(leaf: Fr, index: u32, path: &[Fr]) -> Fr {
+    let mut node = leaf;
+    let mut index = index;
+    for sibling in path {
+        node = if index % 2 == 0 {
+            hash(&[node, *sibling])
+        } else {
+            hash(&[*sibling, node])
+        };
+        index >>= 1;
+    }
+    node
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SolvencyReport {
+    #[serde(with = "fr_str")]
+    pub root: Fr,
+    pub tokens: Vec<TokenLiability>,
+    pub proofs: Vec<BalanceInclusion>,
+}
+
+impl SolvencyReport {
+    pub fn is_solvent(&self) -> bool {
+        self.tokens.iter().all(|token| token.matched)
+    }
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+impl GlobalState {
+    // the balances the state starts with, from a genesis file or a snapshot without a ledger,
+    // are deposits made before the ledger was kept
+    pub fn opening_ledger(&self) -> Ledger {
+        let mut ledger = Ledger::default();
+        for account_id in self.account_ids() {
+            for (token_id, balance) in self.token_balances(account_id) {
+                ledger.record_deposit(token_id, &balance);
+            }
+        }
+        ledger
+    }
+    pub fn solvency_report(&self, ledger: &Ledger) -> SolvencyReport {
+        let mut totals: BTreeMap<u32, BigInt> = ledger.token_ids().map(|token_id| (*token_id, BigInt::zero())).collect();
+        let mut proofs = Vec::new();
+        for account_id in self.account_ids() {
+            let account = self.get_account(account_id);
+            for (token_id, balance) in self.token_balances(account_id) {
+                *totals.entry(token_id).or_insert_with(BigInt::zero) += fr_to_bigint(&balance);
+                let proof = self.balance_full_proof(account_id, token_id);
+                proofs.push(BalanceInclusion {
+                    account_id,
+                    token_id,
+                    balance,
+                    balance_path: proof.balance_path.iter().map(|p| p[0]).collect(),
+                    nonce: account.nonce,
+                    sign: account.sign,
+                    ay: account.ay,
+                    eth_addr: account.eth_addr,
+                    order_root: account.order_root,
+                    account_path: proof.account_path.iter().map(|p| p[0]).collect(),
+                });
+            }
+        }
+        let tokens = totals
+            .into_iter()
+            .map(|(token_id, state_total)| {
+                let ledger_total = ledger.net(token_id);
+                TokenLiability {
+                    token_id,
+                    matched: state_total == ledger_total,
+                    state_total: state_total.to_string(),
+                    ledger_total: ledger_total.to_string(),
+                }
+            })
+            .collect();
+        SolvencyReport {
+            root: self.root(),
+            tokens,
+            proofs,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_solvency_report() {
+    use crate::types::primitives::u64_to_fr;
+
+    let mut state = GlobalState::new(2, 2, 2, false);
+    let mut ledger = Ledger::default();
+    state.set_token_balance(1, 0, u64_to_fr(70));
+    state.set_token_balance(2, 0, u64_to_fr(20));
+    state.set_token_balance(2, 1, u64_to_fr(5));
+    ledger.record_deposit(0, &u64_to_fr(100));
+    ledger.record_withdraw(0, &u64_to_fr(10));
+    ledger.record_deposit(1, &u64_to_fr(5));
+
+    let report = state.solvency_report(&ledger);
+    assert!(report.is_solvent());
+    assert!(state.solvency_report(&state.opening_ledger()).is_solvent());
+    assert_eq!(report.proofs.len(), 3);
+    assert!(report.proofs.iter().all(|proof| proof.root() == state.root()));
+
+    ledger.record_withdraw(1, &u64_to_fr(1));
+    let report = state.solvency_report(&ledger);
+    assert!(!report.is_solvent());
+    assert_eq!(report.tokens[1].ledger_total, "4");
+}
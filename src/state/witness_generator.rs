@@ -2,21 +2,76 @@
 #![allow(clippy::vec_init_then_push)]
 
 use super::global::{AccountUpdates, GlobalState};
-use crate::types::l2::{tx_detail_idx, DepositTx, FullSpotTradeTx, L2Block, Order, RawTx, TransferTx, TxType, WithdrawTx, TX_LENGTH};
+use super::pending_queue::PendingQueue;
+use super::snapshot::SnapshotHeader;
+use super::solvency::{Ledger, SolvencyReport};
+use super::AccountState;
+use crate::config::Settings;
+use crate::metrics;
+use crate::msg::dedup::ProcessedMsgs;
+use crate::types::amount::Amount;
+use crate::types::l2::{
+    block_circuit_name, tx_detail_idx, txs_digest, BlockHeader, DepositTx, FullSpotTradeTx, L2Block, L2Tx, Order, RawTx, SealReason,
+    SourceId, TransferTx, TxType, WithdrawTx, GENESIS_PARENT_HASH, TX_LENGTH,
+};
 use crate::types::merkle_tree::Tree;
-use crate::types::primitives::{fr_add, fr_sub, u32_to_fr, Fr};
-use anyhow::bail;
+use crate::types::primitives::{fr_add, fr_to_bigint, fr_to_string, fr_to_u32, fr_to_u64, u32_to_fr, Fr};
+use anyhow::{anyhow, bail};
 use ff::Field;
+use num_traits::ToPrimitive;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// when to seal the buffered txs into a block, padding with nop txs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SealPolicy {
+    // seal once so many txs are buffered, no larger than the block size
+    pub max_txs: usize,
+    // seal once the oldest buffered tx has waited so long
+    pub max_wait: Option<Duration>,
+}
+
+impl SealPolicy {
+    // only seal full blocks
+    pub fn full_block(n_tx: usize) -> Self {
+        Self {
+            max_txs: n_tx,
+            max_wait: None,
+        }
+    }
+}
 
 // TODO: too many unwrap here
 pub struct WitnessGenerator {
     state: GlobalState,
+    // the largest block size
     n_tx: usize,
+    // supported block sizes in ascending order, each one has its own circuit
+    block_sizes: Vec<usize>,
     // 0 <= len(buffered_txs) < n_tx
     buffered_txs: Vec<RawTx>,
     block_sender: crossbeam_channel::Sender<L2Block>,
     block_generate_num: usize,
+    tx_generate_num: usize,
+    // hash of the last emitted block
+    parent_hash: String,
+    // sources of buffered_txs
+    buffered_sources: Vec<SourceId>,
+    // source of the txs being applied, recorded once the first of them is added
+    pending_source: Option<SourceId>,
+    seal_policy: SealPolicy,
+    // when the first tx of buffered_txs is added
+    buffered_since: Option<Instant>,
+    // set while padding the buffered txs with nop
+    sealing: Option<SealReason>,
     //buffered_blocks: Vec<L2Block>,
+    // user txs waiting for their nonce gap to be filled, disabled when None
+    pending_txs: Option<PendingQueue<L2Tx>>,
+    // deposits and withdrawals seen by the exchange and on L1, kept apart from the txs
+    ledger: Ledger,
+    // once halted, txs are still applied to the state but no block is published any more
+    sealing_halted: bool,
+    // txs applied after halting, kept for the operator instead of being published
+    withheld_txs: Vec<RawTx>,
     verbose: bool,
     verify_sig: bool,
 }
@@ -26,17 +81,122 @@ impl WitnessGenerator {
         Tree::print_config();
     }
     pub fn new(state: GlobalState, n_tx: usize, block_sender: crossbeam_channel::Sender<L2Block>, verbose: bool) -> Self {
+        let ledger = state.opening_ledger();
         Self {
             state,
             n_tx,
+            block_sizes: vec![n_tx],
             buffered_txs: Vec::new(),
             block_sender,
             block_generate_num: 0,
+            tx_generate_num: 0,
+            parent_hash: GENESIS_PARENT_HASH.to_string(),
+            buffered_sources: Vec::new(),
+            pending_source: None,
+            seal_policy: SealPolicy::full_block(n_tx),
+            buffered_since: None,
+            sealing: None,
             //buffered_blocks: Vec::new(),
+            pending_txs: None,
+            ledger,
+            sealing_halted: false,
+            withheld_txs: Vec::new(),
             verbose,
             verify_sig: true,
         }
     }
+    // builds the state trees and the block layout from validated settings
+    pub fn from_settings(settings: &Settings, block_sender: crossbeam_channel::Sender<L2Block>) -> Self {
+        let state = GlobalState::new(
+            settings.balance_levels,
+            settings.order_levels,
+            settings.account_levels,
+            settings.verbose,
+        );
+        Self::from_settings_with_state(settings, state, block_sender)
+    }
+    // starts from a prepared state, e.g. a genesis state
+    pub fn from_settings_with_state(settings: &Settings, state: GlobalState, block_sender: crossbeam_channel::Sender<L2Block>) -> Self {
+        let mut witgen = Self::new(state, settings.max_block_size(), block_sender, settings.verbose);
+        witgen.set_block_sizes(settings.block_sizes.clone());
+        witgen.set_seal_policy(settings.seal_policy());
+        witgen
+    }
+    // should be called before set_seal_policy, since the seal policy is reset
+    pub fn set_block_sizes(&mut self, mut block_sizes: Vec<usize>) {
+        assert!(self.buffered_txs.is_empty(), "cannot change block sizes with buffered txs");
+        block_sizes.sort_unstable();
+        block_sizes.dedup();
+        assert!(
+            !block_sizes.is_empty() && block_sizes[0] > 0,
+            "invalid block sizes {:?}",
+            block_sizes
+        );
+        self.n_tx = *block_sizes.last().unwrap();
+        self.block_sizes = block_sizes;
+        self.seal_policy = SealPolicy::full_block(self.n_tx);
+    }
+    pub fn set_seal_policy(&mut self, policy: SealPolicy) {
+        assert!(
+            policy.max_txs > 0 && policy.max_txs <= self.n_tx,
+            "invalid seal policy {:?}",
+            policy
+        );
+        self.seal_policy = policy;
+    }
+    pub fn enable_pending_queue(&mut self, max_per_account: usize) {
+        self.pending_txs = Some(PendingQueue::new(max_per_account));
+    }
+    // stop publishing blocks, e.g. after the state drifts from the exchange. Cannot be undone.
+    pub fn halt_sealing(&mut self) {
+        if !self.sealing_halted {
+            log::warn!("block sealing halted after block {}", self.block_generate_num);
+            self.sealing_halted = true;
+        }
+    }
+    pub fn is_sealing_halted(&self) -> bool {
+        self.sealing_halted
+    }
+    pub fn withheld_tx_num(&self) -> usize {
+        self.withheld_txs.len()
+    }
+    pub fn pending_tx_num(&self) -> usize {
+        self.pending_txs.as_ref().map(PendingQueue::len).unwrap_or(0)
+    }
+    // only taken between blocks, since buffered txs are already applied to the state but not yet published
+    pub fn checkpoint<W: std::io::Write>(&self, processed_msgs: ProcessedMsgs, writer: W) -> anyhow::Result<()> {
+        if !self.buffered_txs.is_empty() {
+            bail!("cannot checkpoint with {} buffered txs", self.buffered_txs.len());
+        }
+        // the state holds txs which are never published
+        if !self.withheld_txs.is_empty() {
+            bail!("cannot checkpoint with {} withheld txs", self.withheld_txs.len());
+        }
+        let header = SnapshotHeader {
+            parent_hash: Some(self.parent_hash.clone()),
+            processed_msgs: Some(processed_msgs),
+            ledger: Some(self.ledger.clone()),
+            ..self.state.snapshot_header(self.block_generate_num as u64)
+        };
+        self.state.export_snapshot_with_header(&header, writer)
+    }
+    // continue the block chain of the checkpoint the state is imported from
+    pub fn resume(&mut self, header: &SnapshotHeader) {
+        assert!(self.block_generate_num == 0, "cannot resume after generating blocks");
+        self.block_generate_num = header.block_number as usize;
+        if let Some(parent_hash) = &header.parent_hash {
+            self.parent_hash = parent_hash.clone();
+        }
+        if let Some(ledger) = &header.ledger {
+            self.ledger = ledger.clone();
+        }
+    }
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+    pub fn ledger_mut(&mut self) -> &mut Ledger {
+        &mut self.ledger
+    }
 
     /////////////////// forward method call to self.state //////////////////////////////////
     pub fn root(&self) -> Fr {
@@ -48,6 +208,12 @@ impl WitnessGenerator {
     pub fn has_account(&self, account_id: u32) -> bool {
         self.state.has_account(account_id)
     }
+    pub fn balance_levels(&self) -> usize {
+        self.state.balance_levels()
+    }
+    pub fn get_account(&self, account_id: u32) -> AccountState {
+        self.state.get_account(account_id)
+    }
     pub fn get_token_balance(&self, account_id: u32, token_id: u32) -> Fr {
         self.state.get_token_balance(account_id, token_id)
     }
@@ -75,10 +241,20 @@ impl WitnessGenerator {
     pub fn set_token_balance(&mut self, account_id: u32, token_id: u32, balance: Fr) {
         self.state.set_token_balance(account_id, token_id, balance);
     }
+    pub fn solvency_report(&self) -> SolvencyReport {
+        self.state.solvency_report(&self.ledger)
+    }
 
-    pub fn forge_with_txs(buffered_txs: &[RawTx]) -> L2Block {
+    pub fn forge_with_txs(
+        buffered_txs: &[RawTx],
+        block_number: u64,
+        parent_hash: String,
+        sources: Vec<SourceId>,
+        seal_reason: SealReason,
+        circuit: String,
+    ) -> L2Block {
         let txs_type = buffered_txs.iter().map(|tx| tx.tx_type).collect();
-        let encoded_txs = buffered_txs.iter().map(|tx| tx.payload.clone()).collect();
+        let encoded_txs: Vec<Vec<Fr>> = buffered_txs.iter().map(|tx| tx.payload.clone()).collect();
         let balance_path_elements = buffered_txs
             .iter()
             .map(|tx| {
@@ -101,9 +277,23 @@ impl WitnessGenerator {
             .collect();
         let old_account_roots: Vec<Fr> = buffered_txs.iter().map(|tx| tx.root_before).collect();
         let new_account_roots: Vec<Fr> = buffered_txs.iter().map(|tx| tx.root_after).collect();
+        let old_root = *old_account_roots.first().unwrap();
+        let new_root = *new_account_roots.last().unwrap();
+        let header = BlockHeader {
+            block_number,
+            parent_hash,
+            old_root,
+            new_root,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            circuit,
+            seal_reason,
+            sources,
+            txs_digest: txs_digest(&encoded_txs),
+        };
         L2Block {
-            old_root: *old_account_roots.first().unwrap(),
-            new_root: *new_account_roots.last().unwrap(),
+            header,
+            old_root,
+            new_root,
             txs_type,
             encoded_txs,
             balance_path_elements,
@@ -116,17 +306,73 @@ impl WitnessGenerator {
     }
     pub fn add_raw_tx(&mut self, raw_tx: RawTx) {
         debug_assert!(self.buffered_txs.len() < self.n_tx);
+        if self.buffered_txs.is_empty() {
+            self.buffered_since = Some(Instant::now());
+        }
+        if !matches!(raw_tx.tx_type, TxType::Nop) {
+            if let Some(source) = self.pending_source.take() {
+                self.buffered_sources.push(source);
+            }
+        }
         self.buffered_txs.push(raw_tx);
+        // while sealing, the block is emitted by `seal` once padded to the chosen size
+        if self.sealing.is_some() {
+            return;
+        }
         if self.buffered_txs.len() == self.n_tx {
-            let block = Self::forge_with_txs(&self.buffered_txs);
-            self.block_sender.try_send(block).unwrap();
-            self.block_generate_num += 1;
-            self.buffered_txs.clear();
+            self.emit_block(SealReason::Full);
+        } else if self.buffered_txs.len() >= self.seal_policy.max_txs {
+            self.seal(SealReason::MaxTxs);
         }
     }
+    fn emit_block(&mut self, seal_reason: SealReason) {
+        if self.sealing_halted {
+            log::warn!(
+                "withhold {} txs of {:?} after halting, {} withheld in total",
+                self.buffered_txs.len(),
+                self.buffered_sources,
+                self.withheld_txs.len() + self.buffered_txs.len()
+            );
+            self.withheld_txs.append(&mut self.buffered_txs);
+            self.buffered_sources.clear();
+            self.buffered_since = None;
+            return;
+        }
+        let block = Self::forge_with_txs(
+            &self.buffered_txs,
+            self.block_generate_num as u64,
+            self.parent_hash.clone(),
+            std::mem::take(&mut self.buffered_sources),
+            seal_reason,
+            block_circuit_name(self.buffered_txs.len(), &self.block_sizes),
+        );
+        self.parent_hash = block.header.hash();
+        // blocks while the db writer is behind
+        self.block_sender.send(block).expect("block receiver closed");
+        metrics::BLOCK_QUEUE_DEPTH.set(self.block_sender.len());
+        self.block_generate_num += 1;
+        self.tx_generate_num += self.buffered_txs.len();
+        self.buffered_txs.clear();
+        self.buffered_since = None;
+    }
+    // runs `apply` with `source` recorded as where its txs come from. The source goes with the block
+    // holding its first tx, and is dropped if no tx is added, e.g. for a rejected or skipped message
+    pub fn with_block_source<T>(&mut self, source: Option<SourceId>, apply: impl FnOnce(&mut Self) -> T) -> T {
+        self.pending_source = source;
+        let result = apply(self);
+        self.pending_source = None;
+        result
+    }
+    pub fn buffered_tx_num(&self) -> usize {
+        self.buffered_txs.len()
+    }
     pub fn get_block_generate_num(&self) -> usize {
         self.block_generate_num
     }
+    // including nop txs
+    pub fn get_tx_generate_num(&self) -> usize {
+        self.tx_generate_num
+    }
     pub fn deposit(&mut self, tx: DepositTx) -> anyhow::Result<()> {
         let deposit_to_new = tx.l2key.is_some();
         if deposit_to_new && self.has_account(tx.account_id) {
@@ -139,6 +385,7 @@ impl WitnessGenerator {
         let proof = self.state.balance_full_proof(tx.account_id, tx.token_id);
         let acc = self.state.get_account(tx.account_id);
         let old_balance = self.state.get_token_balance(tx.account_id, tx.token_id);
+        let new_balance = Amount::new(old_balance)?.checked_add(&tx.amount.to_amount()?)?.to_fr();
         let nonce = acc.nonce;
 
         let mut encoded_tx = [Fr::zero(); TX_LENGTH];
@@ -154,7 +401,7 @@ impl WitnessGenerator {
 
         encoded_tx[tx_detail_idx::TOKEN_ID2] = u32_to_fr(tx.token_id);
         encoded_tx[tx_detail_idx::ACCOUNT_ID2] = u32_to_fr(tx.account_id);
-        encoded_tx[tx_detail_idx::BALANCE2] = fr_add(&old_balance, &tx.amount.to_fr());
+        encoded_tx[tx_detail_idx::BALANCE2] = new_balance;
         encoded_tx[tx_detail_idx::NONCE2] = nonce;
         if deposit_to_new {
             let l2key = tx.l2key.clone().unwrap();
@@ -188,9 +435,7 @@ impl WitnessGenerator {
             root_after: Fr::zero(),
         };
 
-        let mut balance = old_balance;
-        balance.add_assign(&tx.amount.to_fr());
-        self.state.set_token_balance(tx.account_id, tx.token_id, balance);
+        self.state.set_token_balance(tx.account_id, tx.token_id, new_balance);
         if deposit_to_new {
             let l2key = tx.l2key.unwrap();
             self.state.set_account_l2_addr(tx.account_id, l2key.sign, l2key.ay, l2key.eth_addr);
@@ -200,16 +445,89 @@ impl WitnessGenerator {
         self.add_raw_tx(raw_tx);
         Ok(())
     }
+    pub fn check_nonce(&self, account_id: u32, nonce: &Fr) -> anyhow::Result<()> {
+        let expected = self.state.get_account_nonce(account_id);
+        if *nonce != expected {
+            bail!(
+                "invalid nonce for account {}: expected {}, got {}",
+                account_id,
+                fr_to_string(&expected),
+                fr_to_string(nonce)
+            );
+        }
+        Ok(())
+    }
+    // submit a user signed tx. When the pending queue is enabled, a tx with a future nonce
+    // is held until all the txs before it are applied, instead of being rejected.
+    pub fn submit_user_tx(&mut self, tx: L2Tx) -> anyhow::Result<()> {
+        let (account_id, nonce) = match &tx {
+            L2Tx::Transfer(tx) => (tx.from, tx.from_nonce),
+            L2Tx::Withdraw(tx) => (tx.account_id, tx.nonce),
+            _ => bail!("only transfer and withdraw can be submitted by users"),
+        };
+        let expected = self.state.get_account_nonce(account_id);
+        if nonce != expected {
+            if let Some(pending_txs) = self.pending_txs.as_mut() {
+                if nonce > expected {
+                    let nonce = fr_to_bigint(&nonce).to_u64().ok_or_else(|| anyhow!("nonce overflow"))?;
+                    return pending_txs.push(account_id, nonce, tx);
+                }
+            }
+        }
+        self.apply_user_tx(tx)?;
+        self.apply_pending_txs(account_id);
+        Ok(())
+    }
+    fn apply_user_tx(&mut self, tx: L2Tx) -> anyhow::Result<()> {
+        let hash = match &tx {
+            L2Tx::Transfer(tx) => tx.hash(),
+            L2Tx::Withdraw(tx) => tx.hash(),
+            _ => bail!("only transfer and withdraw can be submitted by users"),
+        };
+        self.with_block_source(Some(SourceId::UserTx(fr_to_string(&hash))), |witgen| match tx {
+            L2Tx::Transfer(tx) => witgen.transfer(tx),
+            L2Tx::Withdraw(tx) => {
+                // a user withdrawal leaves the rollup on L1, the exchange sends no message for it
+                let (token_id, amount) = (tx.token_id, tx.amount.to_fr());
+                witgen.withdraw(tx)?;
+                witgen.ledger.record_withdraw(token_id, &amount);
+                Ok(())
+            }
+            _ => unreachable!(),
+        })
+    }
+    fn apply_pending_txs(&mut self, account_id: u32) {
+        loop {
+            let next_nonce = fr_to_u64(&self.state.get_account_nonce(account_id));
+            let next_tx = match self.pending_txs.as_mut() {
+                Some(pending_txs) => pending_txs.pop(account_id, next_nonce),
+                None => None,
+            };
+            match next_tx {
+                Some(tx) => {
+                    if let Err(e) = self.apply_user_tx(tx) {
+                        log::warn!("drop pending tx of account {} with nonce {}: {}", account_id, next_nonce, e);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
     pub fn fill_withdraw_tx(&self, tx: &mut WithdrawTx) {
         tx.nonce = self.state.get_account(tx.account_id).nonce;
         tx.old_balance = self.get_token_balance(tx.account_id, tx.token_id);
     }
-    pub fn transfer(&mut self, tx: TransferTx) {
+    pub fn transfer(&mut self, tx: TransferTx) -> anyhow::Result<()> {
         if !self.state.has_account(tx.from) {
-            panic!("invalid account {:?}", tx);
+            bail!("invalid account {:?}", tx);
         }
+        self.check_nonce(tx.from, &tx.from_nonce)?;
 
         let transfer_to_new = tx.l2key.is_some();
+        // checked before the sender is debited
+        if transfer_to_new == self.state.has_account(tx.to) {
+            bail!("invalid transfer target account {}", tx.to);
+        }
         let proof_from = self.state.balance_full_proof(tx.from, tx.token_id);
         let from_account = self.state.get_account(tx.from);
         // when transfer_to_new, `to_account` will be an empty account
@@ -217,9 +535,12 @@ impl WitnessGenerator {
 
         let from_old_balance = self.get_token_balance(tx.from, tx.token_id);
         let to_old_balance = self.get_token_balance(tx.to, tx.token_id);
-        assert!(from_old_balance > tx.amount.to_fr(), "Transfer balance not enough");
-        let from_new_balance = fr_sub(&from_old_balance, &tx.amount.to_fr());
-        let to_new_balance = fr_add(&to_old_balance, &tx.amount.to_fr());
+        if from_old_balance < tx.amount.to_fr() {
+            bail!("transfer balance not enough");
+        }
+        let amount = tx.amount.to_amount()?;
+        let from_new_balance = Amount::new(from_old_balance)?.checked_sub(&amount)?.to_fr();
+        let to_new_balance = Amount::new(to_old_balance)?.checked_add(&amount)?.to_fr();
 
         let mut encoded_tx = [Fr::zero(); TX_LENGTH];
         encoded_tx[tx_detail_idx::ACCOUNT_ID1] = u32_to_fr(tx.from);
@@ -283,18 +604,24 @@ impl WitnessGenerator {
         };
 
         self.add_raw_tx(raw_tx);
+        Ok(())
     }
-    pub fn withdraw(&mut self, tx: WithdrawTx) {
-        // assert(this.accounts.get(tx.accountID).ethAddr != 0n, 'Withdraw');
+    pub fn withdraw(&mut self, tx: WithdrawTx) -> anyhow::Result<()> {
         let account_id = tx.account_id;
         let token_id = tx.token_id;
+        if !self.state.has_account(account_id) {
+            bail!("invalid account {:?}", tx);
+        }
+        self.check_nonce(account_id, &tx.nonce)?;
         let proof = self.state.balance_full_proof(account_id, token_id);
 
         let acc = self.state.get_account(account_id);
         let old_balance = self.get_token_balance(account_id, token_id);
-        let new_balance = fr_sub(&old_balance, &tx.amount.to_fr());
+        if old_balance < tx.amount.to_fr() {
+            bail!("withdraw balance not enough");
+        }
+        let new_balance = Amount::new(old_balance)?.checked_sub(&tx.amount.to_amount()?)?.to_fr();
         let nonce = acc.nonce;
-        // assert(oldBalance > tx.amount, 'Withdraw balance');
 
         // first, generate the tx
         let mut encoded_tx = [Fr::zero(); TX_LENGTH];
@@ -348,6 +675,7 @@ impl WitnessGenerator {
 
         raw_tx.root_after = self.state.root();
         self.add_raw_tx(raw_tx);
+        Ok(())
     }
 
     // case1: old order is empty
@@ -355,17 +683,18 @@ impl WitnessGenerator {
     // case3: old order has same order id, we will modify it
     // tx.xxx_order is_none: xxx_order should be already put into the GlobalState tree
     // tx.xxx_order is_some: xxx_order should be new for the GlobalState
-    pub fn full_spot_trade(&mut self, full_tx: FullSpotTradeTx) {
+    pub fn full_spot_trade(&mut self, full_tx: FullSpotTradeTx) -> anyhow::Result<()> {
         // Step1: basic tx check
         // check account ids exist
         let trade = full_tx.trade;
         let acc_id1 = trade.order1_account_id;
         let acc_id2 = trade.order2_account_id;
         if acc_id1 == acc_id2 {
-            panic!("self trade no allowed");
+            bail!("self trade no allowed");
+        }
+        if !self.state.has_account(acc_id1) || !self.state.has_account(acc_id2) {
+            bail!("invalid accounts {} {} of trade", acc_id1, acc_id2);
         }
-        assert!(self.state.has_account(acc_id1));
-        assert!(self.state.has_account(acc_id2));
 
         // Step2: retrive old state first for later use
 
@@ -380,33 +709,63 @@ impl WitnessGenerator {
         // Step3: handle new order
         let mut order1 = if let Some(maker_order) = full_tx.maker_order {
             // new order
-            assert!(!self.has_order(maker_order.account_id, maker_order.order_id));
-            assert_eq!(maker_order.filled_buy, Fr::zero());
-            assert_eq!(maker_order.filled_sell, Fr::zero());
+            if self.has_order(maker_order.account_id, maker_order.order_id) {
+                bail!("new order1 {} already existed", maker_order.order_id);
+            }
+            if !maker_order.filled_buy.is_zero() || !maker_order.filled_sell.is_zero() {
+                bail!("new order1 {} already filled", maker_order.order_id);
+            }
             //self.state.update_order_state(maker_order.account_id, maker_order);
             maker_order
         } else {
             // order1 means maker, order2 means taker
-            assert!(self.state.has_order(acc_id1, trade.order1_id), "unknown order1 {}", trade.order1_id);
+            if !self.state.has_order(acc_id1, trade.order1_id) {
+                bail!("unknown order1 {}", trade.order1_id);
+            }
             self.state.get_account_order_by_id(acc_id1, trade.order1_id)
         };
 
         let mut order2 = if let Some(taker_order) = full_tx.taker_order {
             // new order
-            assert!(!self.has_order(taker_order.account_id, taker_order.order_id));
-            assert_eq!(taker_order.filled_buy, Fr::zero());
-            assert_eq!(taker_order.filled_sell, Fr::zero());
+            if self.has_order(taker_order.account_id, taker_order.order_id) {
+                bail!("new order2 {} already existed", taker_order.order_id);
+            }
+            if !taker_order.filled_buy.is_zero() || !taker_order.filled_sell.is_zero() {
+                bail!("new order2 {} already filled", taker_order.order_id);
+            }
             //self.state.update_order_state(taker_order.account_id, taker_order);
             taker_order
         } else {
-            assert!(self.state.has_order(acc_id2, trade.order2_id), "unknown order2 {}", trade.order2_id);
+            if !self.state.has_order(acc_id2, trade.order2_id) {
+                bail!("unknown order2 {}", trade.order2_id);
+            }
             self.state.get_account_order_by_id(acc_id2, trade.order2_id)
         };
 
+        // Step4: check balances and filled amounts before any state change
+        let amount_1to2 = trade.amount_1to2.to_amount()?;
+        let amount_2to1 = trade.amount_2to1.to_amount()?;
+        let acc1_balance_sell = self.state.get_token_balance(acc_id1, trade.token_id_1to2);
+        let acc1_balance_sell_new = Amount::new(acc1_balance_sell)?.checked_sub(&amount_1to2)?.to_fr();
+        let acc1_balance_buy = self.state.get_token_balance(acc_id1, trade.token_id_2to1);
+        let acc1_balance_buy_new = Amount::new(acc1_balance_buy)?.checked_add(&amount_2to1)?.to_fr();
+
+        let acc2_balance_sell = self.state.get_token_balance(acc_id2, trade.token_id_2to1);
+        let acc2_balance_sell_new = Amount::new(acc2_balance_sell)?.checked_sub(&amount_2to1)?.to_fr();
+        let acc2_balance_buy = self.state.get_token_balance(acc_id2, trade.token_id_1to2);
+        let acc2_balance_buy_new = Amount::new(acc2_balance_buy)?.checked_add(&amount_1to2)?.to_fr();
+
+        // order1/order2 are copies, the tree is updated later
+        order1.trade_with(&amount_1to2.to_fr(), &amount_2to1.to_fr())?;
+        order2.trade_with(&amount_2to1.to_fr(), &amount_1to2.to_fr())?;
+
         // old_order1 is same as old_order1_in_tree when case3
         // not same when case1 and case2
-        let (order1_pos, old_order1_in_tree) = self.state.find_or_insert_order(acc_id1, &order1);
-        let (order2_pos, old_order2_in_tree) = self.state.find_or_insert_order(acc_id2, &order2);
+        // both orders need a slot before any of them is linked
+        self.state.find_order_pos(acc_id1, order1.order_id)?;
+        self.state.find_order_pos(acc_id2, order2.order_id)?;
+        let (order1_pos, old_order1_in_tree) = self.state.find_or_insert_order(acc_id1, &order1)?;
+        let (order2_pos, old_order2_in_tree) = self.state.find_or_insert_order(acc_id2, &order2)?;
 
         // first, generate the tx
 
@@ -452,18 +811,6 @@ impl WitnessGenerator {
         encoded_tx[tx_detail_idx::ORDER1_POS] = u32_to_fr(order1_pos);
         encoded_tx[tx_detail_idx::ORDER2_POS] = u32_to_fr(order2_pos);
 
-        let acc1_balance_sell = self.state.get_token_balance(acc_id1, trade.token_id_1to2);
-        assert!(acc1_balance_sell > trade.amount_1to2.to_fr(), "balance_1to2");
-        let acc1_balance_sell_new = fr_sub(&acc1_balance_sell, &trade.amount_1to2.to_fr());
-        let acc1_balance_buy = self.state.get_token_balance(acc_id1, trade.token_id_2to1);
-        let acc1_balance_buy_new = fr_add(&acc1_balance_buy, &trade.amount_2to1.to_fr());
-
-        let acc2_balance_sell = self.state.get_token_balance(acc_id2, trade.token_id_2to1);
-        assert!(acc2_balance_sell > trade.amount_2to1.to_fr(), "balance_2to1");
-        let acc2_balance_sell_new = fr_sub(&acc2_balance_sell, &trade.amount_2to1.to_fr());
-        let acc2_balance_buy = self.state.get_token_balance(acc_id2, trade.token_id_1to2);
-        let acc2_balance_buy_new = fr_add(&acc2_balance_buy, &trade.amount_1to2.to_fr());
-
         encoded_tx[tx_detail_idx::BALANCE1] = acc1_balance_sell;
         encoded_tx[tx_detail_idx::BALANCE2] = acc2_balance_buy_new;
         encoded_tx[tx_detail_idx::BALANCE3] = acc2_balance_sell;
@@ -491,9 +838,7 @@ impl WitnessGenerator {
             root_after: Default::default(),
         };
 
-        order1.trade_with(&trade.amount_1to2.to_fr(), &trade.amount_2to1.to_fr());
         self.state.update_order_state(acc_id1, order1_pos, order1);
-        order2.trade_with(&trade.amount_2to1.to_fr(), &trade.amount_1to2.to_fr());
         self.state.update_order_state(acc_id2, order2_pos, order2);
 
         let acc1_updates = AccountUpdates {
@@ -542,6 +887,88 @@ impl WitnessGenerator {
         raw_tx.payload = encoded_tx.to_vec();
         raw_tx.root_after = self.state.root();
         self.add_raw_tx(raw_tx);
+        Ok(())
+    }
+
+    // puts a signed order into the order tree before it is matched, so that its commitment is
+    // published. A finished order may be replaced in its slot, like for a new order of a trade.
+    pub fn place_order(&mut self, order: Order) -> anyhow::Result<()> {
+        let account_id = order.account_id;
+        if !self.state.has_account(account_id) {
+            bail!("place order {} of unknown account {}", order.order_id, account_id);
+        }
+        if self.state.has_order(account_id, order.order_id) {
+            bail!("order {} of account {} already placed", order.order_id, account_id);
+        }
+        if !order.filled_buy.is_zero() || !order.filled_sell.is_zero() {
+            bail!("new order {} already filled", order.order_id);
+        }
+
+        let token_sell = fr_to_u32(&order.token_sell);
+        let balance_sell = self.state.get_token_balance(account_id, token_sell);
+        if balance_sell < order.total_sell {
+            bail!("balance not enough for order {} of account {}", order.order_id, account_id);
+        }
+        let proof = self.state.balance_full_proof(account_id, token_sell);
+        let account = self.state.get_account(account_id);
+        let (order_pos, old_order) = self.state.find_or_insert_order(account_id, &order)?;
+        let order_path = self.state.order_proof(account_id, order_pos).path_elements;
+
+        let mut encoded_tx = [Fr::zero(); TX_LENGTH];
+        encoded_tx[tx_detail_idx::ACCOUNT_ID1] = u32_to_fr(account_id);
+        encoded_tx[tx_detail_idx::TOKEN_ID1] = order.token_sell;
+        encoded_tx[tx_detail_idx::BALANCE1] = balance_sell;
+        encoded_tx[tx_detail_idx::ETH_ADDR1] = account.eth_addr;
+        encoded_tx[tx_detail_idx::SIGN1] = account.sign;
+        encoded_tx[tx_detail_idx::AY1] = account.ay;
+        encoded_tx[tx_detail_idx::NONCE1] = account.nonce;
+
+        encoded_tx[tx_detail_idx::ENABLE_SIG_CHECK1] = Fr::one();
+        encoded_tx[tx_detail_idx::SIG_L2_HASH1] = order.sig.hash;
+        encoded_tx[tx_detail_idx::S1] = order.sig.s;
+        encoded_tx[tx_detail_idx::R8X1] = order.sig.r8x;
+        encoded_tx[tx_detail_idx::R8Y1] = order.sig.r8y;
+
+        encoded_tx[tx_detail_idx::ORDER1_POS] = u32_to_fr(order_pos);
+        encoded_tx[tx_detail_idx::OLD_ORDER1_ID] = u32_to_fr(old_order.order_id);
+        encoded_tx[tx_detail_idx::OLD_ORDER1_TOKEN_SELL] = old_order.token_sell;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_FILLED_SELL] = old_order.filled_sell;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_AMOUNT_SELL] = old_order.total_sell;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_TOKEN_BUY] = old_order.token_buy;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_FILLED_BUY] = old_order.filled_buy;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_AMOUNT_BUY] = old_order.total_buy;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_ID] = u32_to_fr(order.order_id);
+        encoded_tx[tx_detail_idx::NEW_ORDER1_TOKEN_SELL] = order.token_sell;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_FILLED_SELL] = order.filled_sell;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_AMOUNT_SELL] = order.total_sell;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_TOKEN_BUY] = order.token_buy;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_FILLED_BUY] = order.filled_buy;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_AMOUNT_BUY] = order.total_buy;
+
+        // no balance changes, the paths only bind the account leaf
+        let mut raw_tx = RawTx {
+            tx_type: TxType::PlaceOrder,
+            payload: encoded_tx.to_vec(),
+            balance_path0: proof.balance_path.clone(),
+            balance_path1: proof.balance_path.clone(),
+            balance_path2: proof.balance_path.clone(),
+            balance_path3: proof.balance_path,
+            order_path0: order_path.clone(),
+            order_path1: order_path,
+            order_root0: account.order_root,
+            order_root1: Fr::zero(),
+            account_path0: proof.account_path.clone(),
+            account_path1: proof.account_path,
+            root_before: proof.root,
+            root_after: Fr::zero(),
+        };
+
+        self.state.set_account_order(account_id, order_pos, order);
+
+        raw_tx.order_root1 = self.state.get_account(account_id).order_root;
+        raw_tx.root_after = self.state.root();
+        self.add_raw_tx(raw_tx);
+        Ok(())
     }
 
     pub fn nop(&mut self) {
@@ -567,13 +994,42 @@ impl WitnessGenerator {
         self.add_raw_tx(raw_tx);
     }
 
-    pub fn flush_with_nop(&mut self) {
+    // pad the buffered txs with nop to the smallest block size that fits, then emit the block
+    fn seal(&mut self, reason: SealReason) {
+        let block_size = self.block_size_for(self.buffered_txs.len());
+        self.sealing = Some(reason);
         let mut cnt = 0;
-        while self.buffered_txs.len() % self.n_tx != 0 {
+        while self.buffered_txs.len() < block_size {
             self.nop();
             cnt += 1;
         }
-        log::debug!("flush with {} nop", cnt);
+        self.sealing = None;
+        self.emit_block(reason);
+        log::debug!("seal block ({:?}) of size {} with {} nop", reason, block_size, cnt);
+    }
+    fn block_size_for(&self, n_tx: usize) -> usize {
+        *self.block_sizes.iter().find(|&&size| size >= n_tx).unwrap()
+    }
+    pub fn force_seal(&mut self) {
+        if !self.buffered_txs.is_empty() {
+            self.seal(SealReason::Forced);
+        }
+    }
+    // time left before the buffered txs must be sealed, None if there is no deadline
+    pub fn time_to_deadline(&self) -> Option<Duration> {
+        let max_wait = self.seal_policy.max_wait?;
+        let buffered_since = self.buffered_since?;
+        Some(max_wait.checked_sub(buffered_since.elapsed()).unwrap_or_default())
+    }
+    // returns true if a block is sealed because of timeout
+    pub fn seal_if_due(&mut self) -> bool {
+        match self.time_to_deadline() {
+            Some(left) if left == Duration::from_secs(0) => {
+                self.seal(SealReason::Timeout);
+                true
+            }
+            _ => false,
+        }
     }
 
     #[cfg(feature = "persist_sled")]
@@ -587,3 +1043,294 @@ impl WitnessGenerator {
         self.state.save_order_trees(&order_trees);
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_place_order() {
+    use crate::account::{Account, Signature};
+    use crate::types::l2::{OrderInput, OrderSide};
+
+    let (mut witgen, block_receiver) = test_witgen(2);
+    test_deposit(&mut witgen, 1, 100);
+    let account = Account::new(1);
+
+    let mut order_input = OrderInput {
+        account_id: 1,
+        side: OrderSide::Sell,
+        order_id: 7,
+        token_buy: u32_to_fr(1),
+        token_sell: u32_to_fr(0),
+        total_sell: u32_to_fr(10),
+        total_buy: u32_to_fr(20),
+        sig: Signature::default(),
+    };
+    order_input.sig = account.sign_hash(order_input.hash()).unwrap();
+    let order = Order::from_order_input(&order_input);
+    let root = witgen.root();
+    witgen.place_order(order).unwrap();
+    assert!(witgen.has_order(1, 7));
+    assert_ne!(witgen.root(), root);
+    // the deposit and the placed order fill a block
+    assert_eq!(witgen.get_block_generate_num(), 1);
+    assert!(block_receiver.try_recv().is_ok());
+    assert!(witgen.place_order(order).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_place_order_rejected() {
+    use crate::account::Signature;
+    use crate::types::l2::{OrderInput, OrderSide};
+
+    let order = |order_id: u32, total_sell: u32| {
+        Order::from_order_input(&OrderInput {
+            account_id: 1,
+            side: OrderSide::Sell,
+            order_id,
+            token_buy: u32_to_fr(1),
+            token_sell: u32_to_fr(0),
+            total_sell: u32_to_fr(total_sell),
+            total_buy: u32_to_fr(20),
+            sig: Signature::default(),
+        })
+    };
+    let (mut witgen, _blocks) = test_witgen(2);
+    test_deposit(&mut witgen, 1, 100);
+    let root = witgen.root();
+    assert!(witgen.place_order(order(1, 101)).is_err());
+    assert!(!witgen.has_order(1, 1));
+    // the order tree of 2 levels holds 4 open orders
+    for order_id in 1..=4 {
+        witgen.place_order(order(order_id, 100)).unwrap();
+    }
+    let root_full = witgen.root();
+    assert_ne!(root_full, root);
+    assert!(witgen.place_order(order(5, 10)).is_err());
+    assert!(!witgen.has_order(1, 5));
+    assert_eq!(witgen.root(), root_full);
+}
+
+#[cfg(test)]
+pub(crate) fn test_witgen(n_tx: usize) -> (WitnessGenerator, crossbeam_channel::Receiver<L2Block>) {
+    let (block_sender, block_receiver) = crossbeam_channel::unbounded();
+    let witgen = WitnessGenerator::new(GlobalState::new(2, 2, 2, false), n_tx, block_sender, false);
+    (witgen, block_receiver)
+}
+
+// creates the account with the keys of `Account::new(account_id)` and deposits token 0
+#[cfg(test)]
+pub(crate) fn test_deposit(witgen: &mut WitnessGenerator, account_id: u32, amount: i64) {
+    use crate::account::Account;
+    use crate::types::fixnum::Float864;
+    use crate::types::l2::L2Key;
+    use rust_decimal::Decimal;
+
+    let account = Account::new(account_id);
+    witgen
+        .deposit(DepositTx {
+            account_id,
+            token_id: 0,
+            amount: Float864::from_decimal(&Decimal::new(amount, 0), 0).unwrap(),
+            l2key: Some(L2Key {
+                eth_addr: account.eth_addr(),
+                sign: account.sign(),
+                ay: account.ay(),
+            }),
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_user_tx_nonce() {
+    use crate::types::fixnum::Float864;
+    use rust_decimal::Decimal;
+
+    let transfer = |nonce: u32| {
+        let mut tx = TransferTx::new(1, 2, 0, Float864::from_decimal(&Decimal::new(1, 0), 0).unwrap());
+        tx.from_nonce = u32_to_fr(nonce);
+        L2Tx::Transfer(tx)
+    };
+
+    let (mut witgen, _blocks) = test_witgen(4);
+    test_deposit(&mut witgen, 1, 100);
+    test_deposit(&mut witgen, 2, 100);
+    // without the pending queue, a future nonce is rejected
+    assert!(witgen.submit_user_tx(transfer(1)).is_err());
+
+    witgen.enable_pending_queue(4);
+    witgen.submit_user_tx(transfer(1)).unwrap();
+    assert_eq!(witgen.pending_tx_num(), 1);
+    assert_eq!(witgen.get_account_nonce(1), u32_to_fr(0));
+    // the queued tx is applied once its nonce becomes current
+    witgen.submit_user_tx(transfer(0)).unwrap();
+    assert_eq!(witgen.pending_tx_num(), 0);
+    assert_eq!(witgen.get_account_nonce(1), u32_to_fr(2));
+    assert_eq!(witgen.get_token_balance(2, 0), u32_to_fr(102));
+    // a stale nonce is rejected, also with the pending queue
+    assert!(witgen.submit_user_tx(transfer(1)).is_err());
+    assert_eq!(witgen.pending_tx_num(), 0);
+    assert_eq!(witgen.get_account_nonce(1), u32_to_fr(2));
+}
+
+#[cfg(test)]
+#[test]
+fn test_spend_whole_balance() {
+    use crate::types::fixnum::Float864;
+    use rust_decimal::Decimal;
+
+    let amount = |amount: i64| Float864::from_decimal(&Decimal::new(amount, 0), 0).unwrap();
+    let (mut witgen, _blocks) = test_witgen(4);
+    test_deposit(&mut witgen, 1, 100);
+    test_deposit(&mut witgen, 2, 100);
+    assert!(witgen.transfer(TransferTx::new(1, 2, 0, amount(101))).is_err());
+    witgen.transfer(TransferTx::new(1, 2, 0, amount(100))).unwrap();
+    assert_eq!(witgen.get_token_balance(1, 0), Fr::zero());
+    let withdraw = |value: i64, nonce: u32| {
+        let mut tx = WithdrawTx::new(2, 0, amount(value));
+        tx.nonce = u32_to_fr(nonce);
+        tx
+    };
+    assert!(witgen.withdraw(withdraw(201, 0)).is_err());
+    witgen.withdraw(withdraw(199, 0)).unwrap();
+    witgen.withdraw(withdraw(1, 1)).unwrap();
+    assert_eq!(witgen.get_token_balance(2, 0), Fr::zero());
+}
+
+#[cfg(test)]
+#[test]
+fn test_ledger_records_user_withdrawals() {
+    use crate::types::fixnum::Float864;
+    use num_bigint::BigInt;
+    use rust_decimal::Decimal;
+
+    let amount = |amount: i64| Float864::from_decimal(&Decimal::new(amount, 0), 0).unwrap();
+    let (mut witgen, _blocks) = test_witgen(4);
+    witgen.enable_pending_queue(4);
+    test_deposit(&mut witgen, 1, 100);
+    test_deposit(&mut witgen, 2, 100);
+    // deposit txs are not recorded, the exchange reports its deposits
+    assert_eq!(witgen.ledger().net(0), BigInt::from(0));
+    assert!(!witgen.solvency_report().is_solvent());
+    witgen.ledger_mut().record_deposit(0, &u32_to_fr(200));
+    assert!(witgen.solvency_report().is_solvent());
+    // a queued withdrawal is not recorded until it is applied
+    let mut withdraw = WithdrawTx::new(1, 0, amount(5));
+    withdraw.nonce = u32_to_fr(1);
+    witgen.submit_user_tx(L2Tx::Withdraw(withdraw)).unwrap();
+    assert_eq!(witgen.pending_tx_num(), 1);
+    assert_eq!(witgen.ledger().net(0), BigInt::from(200));
+    witgen.submit_user_tx(L2Tx::Transfer(TransferTx::new(1, 2, 0, amount(1)))).unwrap();
+    assert_eq!(witgen.pending_tx_num(), 0);
+    assert_eq!(witgen.ledger().net(0), BigInt::from(195));
+    assert!(witgen.solvency_report().is_solvent());
+
+    // the ledger is restored with the checkpoint
+    assert_eq!(witgen.buffered_tx_num(), 0);
+    let mut checkpoint = Vec::new();
+    witgen.checkpoint(ProcessedMsgs::default(), &mut checkpoint).unwrap();
+    let (state, header) = GlobalState::import_snapshot(checkpoint.as_slice(), false).unwrap();
+    let (block_sender, _block_receiver) = crossbeam_channel::unbounded();
+    let mut resumed = WitnessGenerator::new(state, 4, block_sender, false);
+    resumed.resume(&header);
+    assert_eq!(resumed.ledger(), witgen.ledger());
+    assert!(resumed.solvency_report().is_solvent());
+
+    // a snapshot without a ledger counts its balances as deposits
+    let mut snapshot = Vec::new();
+    witgen.state.export_snapshot(0, &mut snapshot).unwrap();
+    let (state, _header) = GlobalState::import_snapshot(snapshot.as_slice(), false).unwrap();
+    let (block_sender, _block_receiver) = crossbeam_channel::unbounded();
+    let opened = WitnessGenerator::new(state, 4, block_sender, false);
+    assert_eq!(opened.ledger().net(0), BigInt::from(195));
+}
+
+#[cfg(test)]
+#[test]
+fn test_halt_sealing_keeps_txs() {
+    let (mut witgen, blocks) = test_witgen(2);
+    test_deposit(&mut witgen, 1, 100);
+    witgen.halt_sealing();
+    // the tx buffered before halting is not published either
+    test_deposit(&mut witgen, 2, 100);
+    assert!(blocks.try_recv().is_err());
+    assert_eq!(witgen.get_block_generate_num(), 0);
+    assert_eq!(witgen.buffered_tx_num(), 0);
+    assert_eq!(witgen.withheld_tx_num(), 2);
+    assert!(witgen.checkpoint(ProcessedMsgs::default(), Vec::new()).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_seal_policy() {
+    let (mut witgen, blocks) = test_witgen(4);
+    witgen.set_seal_policy(SealPolicy {
+        max_txs: 2,
+        max_wait: Some(Duration::from_secs(3600)),
+    });
+    test_deposit(&mut witgen, 1, 100);
+    assert!(blocks.try_recv().is_err());
+    assert!(witgen.time_to_deadline().unwrap() > Duration::from_secs(0));
+    assert!(!witgen.seal_if_due());
+    // sealed before the block is full, padded with nop txs
+    test_deposit(&mut witgen, 2, 100);
+    let block = blocks.try_recv().unwrap();
+    assert_eq!(block.header.seal_reason, SealReason::MaxTxs);
+    let txs_type: Vec<u8> = block.txs_type.iter().map(|tx_type| *tx_type as u8).collect();
+    assert_eq!(
+        txs_type,
+        vec![TxType::Deposit as u8, TxType::Deposit as u8, TxType::Nop as u8, TxType::Nop as u8]
+    );
+    assert_eq!(witgen.time_to_deadline(), None);
+
+    test_deposit(&mut witgen, 3, 100);
+    witgen.force_seal();
+    assert_eq!(blocks.try_recv().unwrap().header.seal_reason, SealReason::Forced);
+    // nothing to seal
+    witgen.force_seal();
+    assert!(blocks.try_recv().is_err());
+
+    witgen.set_seal_policy(SealPolicy {
+        max_txs: 4,
+        max_wait: Some(Duration::from_secs(0)),
+    });
+    test_deposit(&mut witgen, 0, 100);
+    assert!(witgen.seal_if_due());
+    let block = blocks.try_recv().unwrap();
+    assert_eq!(block.header.seal_reason, SealReason::Timeout);
+    assert_eq!(block.header.block_number, 2);
+    assert_eq!(witgen.get_block_generate_num(), 3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_block_size_selection() {
+    let (mut witgen, blocks) = test_witgen(4);
+    witgen.set_block_sizes(vec![4, 2]);
+    // 3 txs only fit the largest size
+    for account_id in 1..=3 {
+        test_deposit(&mut witgen, account_id, 100);
+    }
+    witgen.force_seal();
+    let block = blocks.try_recv().unwrap();
+    assert_eq!(block.header.circuit, "block_4");
+    assert_eq!(block.encoded_txs.len(), 4);
+    // a partial batch is sealed into the smallest size that fits
+    test_deposit(&mut witgen, 0, 100);
+    witgen.force_seal();
+    let block = blocks.try_recv().unwrap();
+    assert_eq!(block.header.circuit, "block_2");
+    assert_eq!(block.encoded_txs.len(), 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_block_sources() {
+    let (mut witgen, blocks) = test_witgen(4);
+    // e.g. a rejected message
+    witgen.with_block_source(Some(SourceId::Trade(1)), |_| ());
+    witgen.with_block_source(Some(SourceId::Trade(2)), |witgen| test_deposit(witgen, 1, 100));
+    witgen.with_block_source(None, |witgen| test_deposit(witgen, 2, 100));
+    witgen.force_seal();
+    assert_eq!(blocks.try_recv().unwrap().header.sources, vec![SourceId::Trade(2)]);
+}
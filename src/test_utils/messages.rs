@@ -6,6 +6,38 @@ pub enum WrappedMessage {
     BALANCE(types::matchengine::messages::BalanceMessage),
     TRADE(types::matchengine::messages::TradeMessage),
     ORDER(types::matchengine::messages::OrderMessage),
+    TOKEN(types::token::TokenInfo),
+}
+
+impl WrappedMessage {
+    // token registrations are not sequenced
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            WrappedMessage::BALANCE(balance) => balance.seq,
+            WrappedMessage::TRADE(trade) => trade.seq,
+            WrappedMessage::ORDER(order) => order.seq,
+            WrappedMessage::TOKEN(_) => None,
+        }
+    }
+    // the `type` field read by `parse_msg`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            WrappedMessage::BALANCE(_) => "BalanceMessage",
+            WrappedMessage::TRADE(_) => "TradeMessage",
+            WrappedMessage::ORDER(_) => "OrderMessage",
+            WrappedMessage::TOKEN(_) => "TokenMessage",
+        }
+    }
+    // the `value` field read by `parse_msg`
+    pub fn value(&self) -> Result<Value> {
+        let value = match self {
+            WrappedMessage::BALANCE(balance) => serde_json::to_value(balance)?,
+            WrappedMessage::TRADE(trade) => serde_json::to_value(trade)?,
+            WrappedMessage::ORDER(order) => serde_json::to_value(order)?,
+            WrappedMessage::TOKEN(token) => serde_json::to_value(token)?,
+        };
+        Ok(value)
+    }
 }
 
 pub fn parse_msg(line: String) -> Result<WrappedMessage> {
@@ -26,9 +58,55 @@ pub fn parse_msg(line: String) -> Result<WrappedMessage> {
                 let data = serde_json::from_value(val).map_err(|e| anyhow!("wrong trade: {}", e))?;
                 Ok(WrappedMessage::TRADE(data))
             }
+            "TokenMessage" => {
+                let data = serde_json::from_value(val).map_err(|e| anyhow!("wrong token: {}", e))?;
+                Ok(WrappedMessage::TOKEN(data))
+            }
             other => Err(anyhow!("unrecognized type field {}", other)),
         }
     } else {
         Err(anyhow!("missed or unexpected type field: {}", line))
     }
 }
+
+// one message of each type, with fractional amounts and nested orders
+#[cfg(test)]
+pub(crate) fn sample_msgs() -> Vec<WrappedMessage> {
+    let order = |id: u64, side: &str, user: u32| {
+        serde_json::json!({
+            "id": id, "market": "ETH_USDT", "type": "LIMIT", "side": side, "user": user,
+            "create_time": 1.5, "update_time": 2.5, "price": "100.25", "amount": "1.5",
+            "taker_fee": "0.001", "maker_fee": "0", "remain": "1.5", "frozen": "150.375",
+            "finished_base": "0", "finished_quote": "0", "finished_fee": "0",
+        })
+    };
+    let lines = vec![
+        serde_json::json!({
+            "type": "TokenMessage",
+            "value": {"token_id": 3, "name": "BTC", "decimals": 6},
+        }),
+        serde_json::json!({
+            "type": "BalanceMessage",
+            "value": {
+                "seq": 1, "timestamp": 0.5, "user_id": 1, "asset": "ETH", "business": "deposit",
+                "change": "1.25", "balance": "2.5", "detail": r#"{"id": 7}"#,
+            },
+        }),
+        serde_json::json!({
+            "type": "OrderMessage",
+            "value": {"seq": 2, "event": "PUT", "order": order(5, "ASK", 1), "base": "ETH", "quote": "USDT"},
+        }),
+        serde_json::json!({
+            "type": "TradeMessage",
+            "value": {
+                "seq": 3, "id": 9, "timestamp": 3.5, "market": "ETH_USDT", "base": "ETH", "quote": "USDT",
+                "price": "100.25", "amount": "0.5", "quote_amount": "50.125",
+                "ask_user_id": 1, "ask_order_id": 5, "ask_role": "MAKER", "ask_fee": "0",
+                "bid_user_id": 2, "bid_order_id": 6, "bid_role": "TAKER", "bid_fee": "0.0005",
+                "ask_order": null, "bid_order": order(6, "BID", 2),
+                "state_before": null, "state_after": null,
+            },
+        }),
+    ];
+    lines.into_iter().map(|line| parse_msg(line.to_string()).unwrap()).collect()
+}
@@ -1,6 +1,5 @@
 pub mod circuit;
 pub mod messages;
-pub mod types;
 
 pub use crate::types::l2;
 pub use crate::types::merkle_tree::MerklePath;
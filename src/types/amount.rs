@@ -0,0 +1,61 @@
+use super::primitives::{bigint_to_fr, fr_add, fr_sub, fr_to_string, Fr};
+use anyhow::{bail, Result};
+use ff::{Field, PrimeField, PrimeFieldRepr};
+use num_bigint::BigInt;
+
+// balances and filled amounts are range checked with this bit width in the circuits,
+// so a sum of two amounts never wraps around the field modulus
+pub const BALANCE_BITS: u32 = 192;
+
+// Amount is a non-negative field element fitting into BALANCE_BITS.
+// Use it whenever a balance or a filled amount is updated, instead of raw `fr_add`/`fr_sub`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Amount(Fr);
+
+impl Amount {
+    pub fn zero() -> Self {
+        Self(Fr::zero())
+    }
+    pub fn max() -> Self {
+        Self(bigint_to_fr((BigInt::from(1) << BALANCE_BITS as usize) - BigInt::from(1)))
+    }
+    pub fn new(value: Fr) -> Result<Self> {
+        if value.into_repr().num_bits() > BALANCE_BITS {
+            bail!("amount {} exceeds {} bits", fr_to_string(&value), BALANCE_BITS);
+        }
+        Ok(Self(value))
+    }
+    pub fn to_fr(&self) -> Fr {
+        self.0
+    }
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+    pub fn checked_add(&self, other: &Self) -> Result<Self> {
+        Self::new(fr_add(&self.0, &other.0))
+    }
+    pub fn checked_sub(&self, other: &Self) -> Result<Self> {
+        if self.0 < other.0 {
+            bail!("amount {} less than {}", fr_to_string(&self.0), fr_to_string(&other.0));
+        }
+        Ok(Self(fr_sub(&self.0, &other.0)))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_checked_amount() {
+    use super::primitives::u64_to_fr;
+    use num_traits::pow::Pow;
+
+    let one = Amount::new(Fr::one()).unwrap();
+    let two = one.checked_add(&one).unwrap();
+    assert_eq!(two.to_fr(), u64_to_fr(2));
+    assert!(two.checked_sub(&two).unwrap().is_zero());
+    assert!(one.checked_sub(&two).is_err());
+
+    let max = Amount::new(bigint_to_fr(BigInt::from(2).pow(BALANCE_BITS) - BigInt::from(1))).unwrap();
+    assert_eq!(max, Amount::max());
+    assert!(max.checked_add(&one).is_err());
+    assert!(Amount::new(fr_sub(&Fr::zero(), &Fr::one())).is_err());
+}
@@ -1,41 +1,69 @@
+use super::amount::Amount;
 use super::primitives::{bigint_to_fr, u64_to_fr, Fr};
 use num_traits::pow::Pow;
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::prelude::Zero;
 use rust_decimal::Decimal;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
-use anyhow::bail;
 use anyhow::Result;
-use num_bigint::BigInt;
+use anyhow::{anyhow, bail};
+use num_bigint::{BigInt, Sign};
 
-pub fn decimal_to_u64(num: &Decimal, prec: u32) -> u64 {
+// fails instead of truncating digits beyond `prec`
+pub fn decimal_to_u64(num: &Decimal, prec: u32) -> Result<u64> {
+    if num.is_sign_negative() {
+        bail!("negative amount {}", num);
+    }
     let prec_mul = Decimal::new(10, 0).powi(prec as u64);
-    let adjusted = num * prec_mul;
-    adjusted.floor().to_u64().unwrap()
+    let adjusted = num
+        .checked_mul(prec_mul)
+        .ok_or_else(|| anyhow!("amount {} overflows with precision {}", num, prec))?;
+    if adjusted != adjusted.floor() {
+        bail!("decimal precision error {} {}", num, prec);
+    }
+    adjusted.to_u64().ok_or_else(|| anyhow!("amount {} overflows u64", adjusted))
 }
 
-pub fn decimal_to_fr(num: &Decimal, prec: u32) -> Fr {
-    // TODO: is u64 enough?
-    u64_to_fr(decimal_to_u64(num, prec))
+// amounts beyond u64 are rejected, use Float864 for larger ones
+pub fn decimal_to_fr(num: &Decimal, prec: u32) -> Result<Fr> {
+    Ok(u64_to_fr(decimal_to_u64(num, prec)?))
 }
 
-pub fn decimal_to_amount(num: &Decimal, prec: u32) -> Float864 {
-    Float864::from_decimal(num, prec).unwrap()
+pub fn decimal_to_amount(num: &Decimal, prec: u32) -> Result<Float864> {
+    Float864::from_decimal(num, prec)
 }
 
 #[cfg(test)]
 #[test]
 fn test_decimal_to_fr() {
     let pi = Decimal::new(3141, 3);
-    let out = decimal_to_fr(&pi, 3);
+    let out = decimal_to_fr(&pi, 3).unwrap();
     assert_eq!(
         "Fr(0x0000000000000000000000000000000000000000000000000000000000000c45)",
         out.to_string()
     );
+    assert!(decimal_to_fr(&pi, 2).is_err());
+    assert!(decimal_to_fr(&-pi, 3).is_err());
+    assert!(decimal_to_fr(&Decimal::new(std::i64::MAX, 0), 6).is_err());
+}
+
+// how to handle digits beyond the token precision
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    Floor,
+    Ceil,
+    Reject,
 }
 
-#[derive(Debug, Clone, Copy)]
+// kept below u64::MAX, so a significand always fits into an i64
+pub const MAX_SIGNIFICAND: u64 = std::u64::MAX / 4;
+
+// A value may have several (significand, exponent) representations, e.g. (10, 0) and (1, 1).
+// The canonical one has no trailing zeros in the significand unless the exponent is saturated,
+// and zero is always (0, 0). All constructors return canonical values, except `decode` which keeps
+// the representation of the pubdata, e.g. of an L1 deposit, as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Float864 {
     pub exponent: u8,
     // 5 bytes seems enough?
@@ -43,6 +71,26 @@ pub struct Float864 {
 }
 
 impl Float864 {
+    pub fn zero() -> Self {
+        Self {
+            exponent: 0,
+            significand: 0,
+        }
+    }
+    pub fn normalize(&self) -> Self {
+        if self.significand == 0 {
+            return Self::zero();
+        }
+        let mut result = *self;
+        while result.significand % 10 == 0 && result.exponent < std::u8::MAX {
+            result.significand /= 10;
+            result.exponent += 1;
+        }
+        result
+    }
+    pub fn is_canonical(&self) -> bool {
+        *self == self.normalize()
+    }
     pub fn to_bigint(&self) -> BigInt {
         let s = BigInt::from(self.significand);
         s * BigInt::from(10).pow(self.exponent)
@@ -50,35 +98,69 @@ impl Float864 {
     pub fn to_fr(&self) -> Fr {
         bigint_to_fr(self.to_bigint())
     }
+    // the exponent can make the value too large for a balance
+    pub fn to_amount(&self) -> Result<Amount> {
+        Amount::new(self.to_fr())
+    }
+    // the representation is encoded as it is, so decoded pubdata is encoded to the same bytes again
     pub fn encode(&self) -> Vec<u8> {
         let mut result = self.exponent.to_be_bytes().to_vec();
         result.append(&mut self.significand.to_be_bytes().to_vec());
         result
     }
+    // equal values always have equal canonical encodings
+    pub fn encode_canonical(&self) -> Vec<u8> {
+        self.normalize().encode()
+    }
     pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() != 9 {
+            bail!("invalid amount length {}", data.len());
+        }
         let exponent = u8::from_be_bytes(data[0..1].try_into()?);
         let significand = u64::from_be_bytes(data[1..9].try_into()?);
         Ok(Self { exponent, significand })
     }
-    pub fn to_decimal(&self, prec: u32) -> Decimal {
+    pub fn to_decimal(&self, prec: u32) -> Result<Decimal> {
         // for example, (significand:1, exponent:17) means 10**17, when prec is 18,
         // it is 0.1 (ETH)
-        Decimal::new(self.significand as i64, 0) * Decimal::new(10, 0).powi(self.exponent as u64) / Decimal::new(10, 0).powi(prec as u64)
+        let ten = Decimal::new(10, 0);
+        let shift = self.exponent as i64 - prec as i64;
+        // a decimal has at most 28 digits
+        if shift.abs() > 28 {
+            bail!("amount {:?} with precision {} out of decimal range", self, prec);
+        }
+        let significand = Decimal::from(self.significand);
+        let result = if shift >= 0 {
+            significand.checked_mul(ten.powi(shift as u64))
+        } else {
+            significand.checked_div(ten.powi(-shift as u64))
+        };
+        result.ok_or_else(|| anyhow!("amount {:?} with precision {} out of decimal range", self, prec))
     }
     pub fn from_decimal(d: &Decimal, prec: u32) -> Result<Self> {
+        Self::from_decimal_rounded(d, prec, RoundingMode::Reject)
+    }
+    pub fn from_decimal_rounded(d: &Decimal, prec: u32, mode: RoundingMode) -> Result<Self> {
         // if d is "0.1" and prec is 18, result is (significand:1, exponent:17)
-        if d.is_zero() {
-            return Ok(Self {
-                exponent: 0,
-                significand: 0,
-            });
+        if d.is_sign_negative() {
+            bail!("negative amount {}", d);
         }
         let ten = Decimal::new(10, 0);
         let exp = ten.powi(prec as u64);
-        let mut n = d * exp;
+        let mut n = d
+            .checked_mul(exp)
+            .ok_or_else(|| anyhow!("amount {} overflows with precision {}", d, prec))?;
         if n != n.floor() {
-            bail!("decimal precision error {} {}", d, prec);
+            n = match mode {
+                RoundingMode::Floor => n.floor(),
+                RoundingMode::Ceil => n.ceil(),
+                RoundingMode::Reject => bail!("decimal precision error {} {}", d, prec),
+            };
         }
+        if n.is_zero() {
+            return Ok(Self::zero());
+        }
+        // a decimal has at most 29 digits, so the exponent never saturates here
         let mut exponent = 0;
         loop {
             let next = n / ten;
@@ -89,15 +171,37 @@ impl Float864 {
                 break;
             }
         }
-        if n > Decimal::new((std::u64::MAX / 4) as i64, 0) {
-            bail!("invalid precision {} {} {}", d, prec, n);
-        }
-        // TODO: a better way...
-        let significand: u64 = n.floor().to_string().parse::<u64>()?;
+        let significand = n
+            .to_u64()
+            .filter(|s| *s <= MAX_SIGNIFICAND)
+            .ok_or_else(|| anyhow!("invalid precision {} {} {}", d, prec, n))?;
         Ok(Float864 { exponent, significand })
     }
 }
 
+impl TryFrom<BigInt> for Float864 {
+    type Error = anyhow::Error;
+    fn try_from(mut n: BigInt) -> Result<Self> {
+        if n.sign() == Sign::Minus {
+            bail!("negative amount {}", n);
+        }
+        if n.is_zero() {
+            return Ok(Self::zero());
+        }
+        let ten = BigInt::from(10);
+        let mut exponent: u8 = 0;
+        while exponent < std::u8::MAX && (&n % &ten).is_zero() {
+            n = n / &ten;
+            exponent += 1;
+        }
+        let significand = n
+            .to_u64()
+            .filter(|s| *s <= MAX_SIGNIFICAND)
+            .ok_or_else(|| anyhow!("significand {} out of range", n))?;
+        Ok(Self { exponent, significand })
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn test_float864() {
@@ -107,9 +211,91 @@ fn test_float864() {
     let f = Float864::from_decimal(&d0, 18).unwrap();
     assert_eq!(f.exponent, 13);
     assert_eq!(f.significand, 123456);
-    let d = f.to_decimal(18);
+    let d = f.to_decimal(18).unwrap();
     assert_eq!(d, Decimal::from_str("1.23456").unwrap());
     let f2 = Float864::decode(&f.encode()).unwrap();
     assert_eq!(f2.exponent, 13);
     assert_eq!(f2.significand, 123456);
+    assert!(Float864::from_decimal(&d0, 4).is_err());
+    assert!(Float864::from_decimal(&-d0, 18).is_err());
+
+    let floor = Float864::from_decimal_rounded(&d0, 3, RoundingMode::Floor).unwrap();
+    assert_eq!(floor.to_decimal(3).unwrap(), Decimal::from_str("1.234").unwrap());
+    let ceil = Float864::from_decimal_rounded(&d0, 3, RoundingMode::Ceil).unwrap();
+    assert_eq!(ceil.to_decimal(3).unwrap(), Decimal::from_str("1.235").unwrap());
+    let tiny = Decimal::from_str("0.0000001").unwrap();
+    assert_eq!(
+        Float864::from_decimal_rounded(&tiny, 6, RoundingMode::Floor).unwrap(),
+        Float864::zero()
+    );
+
+    let non_canonical = Float864 {
+        exponent: 0,
+        significand: 1000,
+    };
+    assert_eq!(
+        non_canonical.normalize(),
+        Float864 {
+            exponent: 3,
+            significand: 1
+        }
+    );
+    assert_eq!(Float864::decode(&non_canonical.encode()).unwrap(), non_canonical);
+    assert_eq!(
+        Float864::decode(&non_canonical.encode_canonical()).unwrap(),
+        non_canonical.normalize()
+    );
+    // non canonical pubdata is accepted, and encoded to the same bytes again
+    let pubdata = [0, 0, 0, 0, 0, 0, 0, 3, 232];
+    let decoded = Float864::decode(&pubdata).unwrap();
+    assert_eq!(decoded, non_canonical);
+    assert_eq!(decoded.encode(), pubdata);
+    assert_eq!(decoded.encode_canonical(), non_canonical.normalize().encode());
+    assert!(Float864::decode(&[0, 0, 0]).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_float864_round_trip() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    // seeded, so that a failure can be reproduced
+    let mut rng = StdRng::seed_from_u64(864);
+    let rand_float = |rng: &mut StdRng| Float864 {
+        exponent: rng.gen(),
+        significand: rng.gen(),
+    };
+    for _ in 0..10000 {
+        let f = rand_float(&mut rng);
+        let canonical = f.normalize();
+        assert_eq!(Float864::decode(&f.encode()).unwrap(), f);
+        assert_eq!(Float864::decode(&f.encode_canonical()).unwrap(), canonical);
+        assert_eq!(canonical.to_bigint(), f.to_bigint());
+        match Float864::try_from(f.to_bigint()) {
+            Ok(converted) => assert_eq!(converted, canonical),
+            Err(_) => assert!(canonical.significand > MAX_SIGNIFICAND),
+        }
+    }
+    // decimals keep 96 bits, values out of their range are rejected
+    for _ in 0..10000 {
+        let f = rand_float(&mut rng);
+        let prec = rng.gen_range(0..=18);
+        if let Ok(d) = f.to_decimal(prec) {
+            let canonical = f.normalize();
+            match Float864::from_decimal(&d, prec) {
+                Ok(converted) => assert_eq!(converted, canonical),
+                Err(_) => assert!(canonical.significand > MAX_SIGNIFICAND),
+            }
+        }
+    }
+    // the edges of both ranges
+    for &exponent in &[0, 1, std::u8::MAX] {
+        for &significand in &[0, 1, 10, MAX_SIGNIFICAND, std::u64::MAX] {
+            let f = Float864 { exponent, significand };
+            assert_eq!(Float864::decode(&f.encode()).unwrap(), f);
+            assert_eq!(f.normalize().to_bigint(), f.to_bigint());
+        }
+    }
+    assert!(Float864::try_from(BigInt::from(-1)).is_err());
+    assert!(Float864::try_from(BigInt::from(std::u64::MAX)).is_err());
 }
@@ -1,9 +1,81 @@
 use super::tx::TxType;
 use crate::types::merkle_tree::MerklePath;
-use crate::types::primitives::Fr;
+use crate::types::primitives::{fr_str, fr_to_vec, Fr};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// why a block is sealed, kept in block metadata only, not part of the circuit input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SealReason {
+    // the largest block size is reached
+    Full,
+    // the seal policy's max txs are buffered, before the block is full
+    MaxTxs,
+    // the oldest buffered tx has waited longer than the max wait time
+    Timeout,
+    // sealed on demand
+    Forced,
+}
+
+// the message or user tx a block's txs come from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", content = "id")]
+pub enum SourceId {
+    Trade(u64),
+    Balance(u64),
+    Order(u64),
+    // decimal string of the tx hash
+    UserTx(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockHeader {
+    pub block_number: u64,
+    // hex encoded, all zeros for the first block
+    pub parent_hash: String,
+    #[serde(with = "fr_str")]
+    pub old_root: Fr,
+    #[serde(with = "fr_str")]
+    pub new_root: Fr,
+    // unix epoch millis, excluded from the block hash
+    pub timestamp: u64,
+    pub circuit: String,
+    pub seal_reason: SealReason,
+    pub sources: Vec<SourceId>,
+    // hex encoded digest of all the encoded txs
+    pub txs_digest: String,
+}
+
+pub const GENESIS_PARENT_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+impl BlockHeader {
+    // deterministic: replaying the same messages always gives the same hash
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.block_number.to_be_bytes());
+        hasher.update(self.parent_hash.as_bytes());
+        hasher.update(fr_to_vec(&self.old_root));
+        hasher.update(fr_to_vec(&self.new_root));
+        hasher.update(self.circuit.as_bytes());
+        hasher.update(self.txs_digest.as_bytes());
+        hasher.update(serde_json::to_vec(&self.sources).unwrap());
+        hex::encode(hasher.finalize())
+    }
+}
+
+pub fn txs_digest(encoded_txs: &[Vec<Fr>]) -> String {
+    let mut hasher = Sha256::new();
+    for tx in encoded_txs {
+        for f in tx {
+            hasher.update(fr_to_vec(f));
+        }
+    }
+    hex::encode(hasher.finalize())
+}
 
 #[derive(Clone)]
 pub struct L2Block {
+    pub header: BlockHeader,
     pub old_root: Fr,
     pub new_root: Fr,
     pub txs_type: Vec<TxType>,
@@ -15,3 +87,43 @@ pub struct L2Block {
     pub old_account_roots: Vec<Fr>,
     pub new_account_roots: Vec<Fr>,
 }
+
+// name of the circuit proving a block. A single block size keeps the "block" circuit the provers
+// already run, several sizes need a circuit for each size
+pub fn block_circuit_name(n_tx: usize, block_sizes: &[usize]) -> String {
+    if block_sizes.len() == 1 {
+        "block".to_string()
+    } else {
+        format!("block_{}", n_tx)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_block_hash_ignores_timestamp() {
+    use ff::Field;
+    let header = BlockHeader {
+        block_number: 1,
+        parent_hash: GENESIS_PARENT_HASH.to_string(),
+        old_root: Fr::zero(),
+        new_root: Fr::one(),
+        timestamp: 1624180714000,
+        circuit: block_circuit_name(2, &[2]),
+        seal_reason: SealReason::Full,
+        sources: vec![SourceId::Balance(1624180714300), SourceId::Trade(1)],
+        txs_digest: txs_digest(&[vec![Fr::one()], vec![Fr::zero()]]),
+    };
+    let mut later = header.clone();
+    later.timestamp += 1000;
+    assert_eq!(header.hash(), later.hash());
+    let mut other = header.clone();
+    other.sources.pop();
+    assert_ne!(header.hash(), other.hash());
+}
+
+#[cfg(test)]
+#[test]
+fn test_block_circuit_name() {
+    assert_eq!(block_circuit_name(2, &[2]), "block");
+    assert_eq!(block_circuit_name(2, &[2, 8]), "block_2");
+}
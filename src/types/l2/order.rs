@@ -1,11 +1,14 @@
 #![allow(clippy::let_and_return)]
+use crate::types::amount::Amount;
 use crate::types::primitives::{self, hash, shl, u32_to_fr, Fr};
+use anyhow::bail;
 
 use crate::account::{Account, Signature};
 
 use ff::Field;
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum OrderSide {
     Buy,
     Sell,
@@ -99,29 +102,69 @@ impl Order {
         data.add_assign(&shl(&self.token_sell, 64));
         hash(&[data, self.filled_sell, self.filled_buy, self.total_sell, self.total_buy])
     }
+    // one side fill is enough, exactly like the circuit
+    // https://github.com/Fluidex/circuits/blob/4f952f63aa411529c466de2f6e9f8ceeac9ceb00/src/spot_trade.circom#L42
     pub fn is_filled(&self) -> bool {
-        //debug_assert!(self.filled_buy <= self.total_buy, "too much filled buy");
-        //debug_assert!(self.filled_sell <= self.total_sell, "too much filled sell");
-        // TODO: one side fill is enough
-        // https://github.com/Fluidex/circuits/blob/4f952f63aa411529c466de2f6e9f8ceeac9ceb00/src/spot_trade.circom#L42
-        //self.filled_buy >= self.total_buy || self.filled_sell >= self.total_sell
-        (self.side == OrderSide::Buy && self.filled_buy >= self.total_buy)
-            || (self.side == OrderSide::Sell && self.filled_sell >= self.total_sell)
+        self.filled_buy >= self.total_buy || self.filled_sell >= self.total_sell
     }
     pub fn is_default(&self) -> bool {
-        self.total_sell.is_zero()
+        self.total_sell.is_zero() && self.total_buy.is_zero()
+    }
+    // whether trading `sell` for `buy` is at least the limit ratio total_buy / total_sell
+    fn is_within_limit(&self, sell: &Fr, buy: &Fr) -> bool {
+        let (sell, buy) = (primitives::fr_to_bigint(sell), primitives::fr_to_bigint(buy));
+        buy * primitives::fr_to_bigint(&self.total_sell) >= sell * primitives::fr_to_bigint(&self.total_buy)
     }
     pub fn sign_with(&mut self, account: &Account) -> Result<(), String> {
         self.sig = account.sign_hash(self.hash())?;
         Ok(())
     }
-    pub fn trade_with(&mut self, sell: &Fr, buy: &Fr) {
-        // TODO: check overflow?
-        self.filled_buy.add_assign(buy);
-        self.filled_sell.add_assign(sell);
+    // the order is left untouched if the trade is rejected
+    pub fn trade_with(&mut self, sell: &Fr, buy: &Fr) -> anyhow::Result<()> {
+        if self.is_filled() {
+            bail!("order {} already filled", self.order_id);
+        }
+        if !self.is_within_limit(sell, buy) {
+            bail!("trade of order {} exceeds its limit ratio", self.order_id);
+        }
+        let filled_sell = Amount::new(self.filled_sell)?.checked_add(&Amount::new(*sell)?)?.to_fr();
+        let filled_buy = Amount::new(self.filled_buy)?.checked_add(&Amount::new(*buy)?)?.to_fr();
+        // only the side limiting the order is bounded, the other side depends on the trade price
+        if self.side == OrderSide::Sell {
+            if filled_sell > self.total_sell {
+                bail!("order {} overfilled on sell side", self.order_id);
+            }
+        } else if filled_buy > self.total_buy {
+            bail!("order {} overfilled on buy side", self.order_id);
+        }
+        self.filled_sell = filled_sell;
+        self.filled_buy = filled_buy;
+        Ok(())
     }
 }
 
+#[cfg(test)]
+#[test]
+fn test_order_trade_with() {
+    use crate::types::primitives::u64_to_fr;
+    let mut order = Order {
+        side: OrderSide::Sell,
+        total_sell: u64_to_fr(10),
+        total_buy: u64_to_fr(20),
+        ..Default::default()
+    };
+    order.trade_with(&u64_to_fr(6), &u64_to_fr(13)).unwrap();
+    assert!(order.trade_with(&u64_to_fr(6), &u64_to_fr(13)).is_err());
+    assert_eq!(order.filled_sell, u64_to_fr(6));
+    // below the limit ratio of 2
+    assert!(order.trade_with(&u64_to_fr(4), &u64_to_fr(7)).is_err());
+    order.trade_with(&u64_to_fr(4), &u64_to_fr(9)).unwrap();
+    assert!(order.is_filled());
+    assert!(order.trade_with(&u64_to_fr(0), &u64_to_fr(0)).is_err());
+
+    assert!(Order::default().is_filled());
+}
+
 #[cfg(test)]
 #[test]
 fn bench_order_sign() {
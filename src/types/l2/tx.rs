@@ -274,6 +274,11 @@ fn test_deposit_to_old_pubdata() {
     assert_eq!(tx.token_id, tx2.token_id);
     assert_eq!(tx.amount.to_bigint(), tx2.amount.to_bigint());
     assert!(tx2.l2key.is_none());
+
+    // a non canonical amount from L1 is kept in the pubdata
+    let mut pubdata = pubdata1;
+    pubdata[7..16].copy_from_slice(&AmountType::decode(&[0, 0, 0, 0, 0, 0, 0, 3, 232]).unwrap().encode());
+    assert_eq!(DepositTx::from_pubdata(&pubdata).unwrap().to_pubdata(), pubdata);
 }
 
 #[cfg(test)]
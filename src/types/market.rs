@@ -0,0 +1,134 @@
+use super::token::TokenRegistry;
+use anyhow::{anyhow, bail};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketInfo {
+    pub base: String,
+    pub quote: String,
+    // max decimal places of base amounts
+    pub amount_prec: u32,
+    // max decimal places of prices
+    pub price_prec: u32,
+    // min base amount of an order or a trade
+    pub min_amount: Decimal,
+}
+
+impl MarketInfo {
+    // market names are like "ETH_USDT"
+    pub fn name(&self) -> String {
+        format!("{}_{}", self.base, self.quote)
+    }
+    pub fn check_amount(&self, amount: &Decimal) -> anyhow::Result<()> {
+        if amount.is_sign_negative() {
+            bail!("negative amount {} in market {}", amount, self.name());
+        }
+        if amount.normalize().scale() > self.amount_prec {
+            bail!("amount {} exceeds precision {} of market {}", amount, self.amount_prec, self.name());
+        }
+        Ok(())
+    }
+    // a trade may fill less than the min amount, but an order may not
+    pub fn check_order_amount(&self, amount: &Decimal) -> anyhow::Result<()> {
+        self.check_amount(amount)?;
+        if *amount < self.min_amount {
+            bail!(
+                "amount {} less than min amount {} of market {}",
+                amount,
+                self.min_amount,
+                self.name()
+            );
+        }
+        Ok(())
+    }
+    pub fn check_price(&self, price: &Decimal) -> anyhow::Result<()> {
+        if *price <= Decimal::new(0, 0) {
+            bail!("invalid price {} in market {}", price, self.name());
+        }
+        if price.normalize().scale() > self.price_prec {
+            bail!("price {} exceeds precision {} of market {}", price, self.price_prec, self.name());
+        }
+        Ok(())
+    }
+    // the base and quote amounts must be representable with the token precisions
+    pub fn check_tokens(&self, tokens: &TokenRegistry) -> anyhow::Result<()> {
+        let base_prec = tokens.prec(tokens.token_id(&self.base)?)?;
+        let quote_prec = tokens.prec(tokens.token_id(&self.quote)?)?;
+        if self.amount_prec > base_prec {
+            bail!("amount precision of market {} exceeds precision of {}", self.name(), self.base);
+        }
+        if self.amount_prec + self.price_prec > quote_prec {
+            bail!("quote precision of market {} exceeds precision of {}", self.name(), self.quote);
+        }
+        Ok(())
+    }
+}
+
+// a stand-in for tests and local setups, deployments list the exchange's markets in the config file
+pub fn default_markets() -> Vec<MarketInfo> {
+    vec![MarketInfo {
+        base: "ETH".to_string(),
+        quote: "USDT".to_string(),
+        amount_prec: 4,
+        price_prec: 2,
+        min_amount: Decimal::new(1, 3),
+    }]
+}
+
+#[derive(Debug, Clone)]
+pub struct MarketRegistry {
+    markets: HashMap<String, MarketInfo>,
+}
+
+impl Default for MarketRegistry {
+    fn default() -> Self {
+        Self::from_markets(default_markets()).unwrap()
+    }
+}
+
+impl MarketRegistry {
+    pub fn from_markets(markets: Vec<MarketInfo>) -> anyhow::Result<Self> {
+        let mut registry = Self { markets: HashMap::new() };
+        for market in markets {
+            let name = market.name();
+            if registry.markets.insert(name.clone(), market).is_some() {
+                bail!("duplicated market {}", name);
+            }
+        }
+        Ok(registry)
+    }
+    pub fn get(&self, name: &str) -> anyhow::Result<&MarketInfo> {
+        self.markets.get(name).ok_or_else(|| anyhow!("unknown market {}", name))
+    }
+    pub fn check_tokens(&self, tokens: &TokenRegistry) -> anyhow::Result<()> {
+        for market in self.markets.values() {
+            market.check_tokens(tokens)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_market_registry() {
+    use std::str::FromStr;
+    let markets = MarketRegistry::default();
+    markets.check_tokens(&TokenRegistry::default()).unwrap();
+    assert!(markets.get("BTC_USDT").is_err());
+
+    let market = markets.get("ETH_USDT").unwrap();
+    market.check_amount(&Decimal::from_str("2.2911").unwrap()).unwrap();
+    market.check_amount(&Decimal::from_str("2.29110000").unwrap()).unwrap();
+    assert!(market.check_amount(&Decimal::from_str("2.29111").unwrap()).is_err());
+    market.check_amount(&Decimal::from_str("0.0001").unwrap()).unwrap();
+    assert!(market.check_order_amount(&Decimal::from_str("0.0001").unwrap()).is_err());
+    market.check_price(&Decimal::from_str("2148.17").unwrap()).unwrap();
+    assert!(market.check_price(&Decimal::from_str("2148.171").unwrap()).is_err());
+    assert!(market.check_price(&Decimal::new(0, 0)).is_err());
+
+    let mut wide = market.clone();
+    wide.price_prec = 4;
+    assert!(wide.check_tokens(&TokenRegistry::default()).is_err());
+}
@@ -4,6 +4,28 @@ use serde::{Deserialize, Serialize};
 
 // TODO: reuse related types def in dingir-exchange
 
+// Decimals stay text in json, and are their 16 byte representation in binary formats like bincode.
+// Every field is always serialized, since binary formats cannot skip fields.
+mod decimal_compact {
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            Serialize::serialize(value, serializer)
+        } else {
+            Serialize::serialize(&value.serialize(), serializer)
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        if deserializer.is_human_readable() {
+            <Decimal as Deserialize>::deserialize(deserializer)
+        } else {
+            Ok(Decimal::deserialize(<[u8; 16]>::deserialize(deserializer)?))
+        }
+    }
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub enum MarketRole {
     MAKER = 1,
@@ -40,19 +62,31 @@ pub struct Order {
     pub user: u32,
     pub create_time: f64,
     pub update_time: f64,
+    #[serde(with = "decimal_compact")]
     pub price: Decimal,
+    #[serde(with = "decimal_compact")]
     pub amount: Decimal,
+    #[serde(with = "decimal_compact")]
     pub taker_fee: Decimal,
+    #[serde(with = "decimal_compact")]
     pub maker_fee: Decimal,
+    #[serde(with = "decimal_compact")]
     pub remain: Decimal,
+    #[serde(with = "decimal_compact")]
     pub frozen: Decimal,
+    #[serde(with = "decimal_compact")]
     pub finished_base: Decimal,
+    #[serde(with = "decimal_compact")]
     pub finished_quote: Decimal,
+    #[serde(with = "decimal_compact")]
     pub finished_fee: Decimal,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OrderMessage {
+    // global sequence number across all message topics, if the exchange assigns one
+    #[serde(default)]
+    pub seq: Option<u64>,
     pub event: OrderEventType,
     pub order: Order,
     pub base: String,
@@ -61,17 +95,25 @@ pub struct OrderMessage {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct VerboseOrderState {
+    #[serde(with = "decimal_compact")]
     pub price: Decimal,
+    #[serde(with = "decimal_compact")]
     pub amount: Decimal,
+    #[serde(with = "decimal_compact")]
     pub finished_base: Decimal,
+    #[serde(with = "decimal_compact")]
     pub finished_quote: Decimal,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct VerboseBalanceState {
+    #[serde(with = "decimal_compact")]
     pub bid_user_base: Decimal,
+    #[serde(with = "decimal_compact")]
     pub bid_user_quote: Decimal,
+    #[serde(with = "decimal_compact")]
     pub ask_user_base: Decimal,
+    #[serde(with = "decimal_compact")]
     pub ask_user_quote: Decimal,
 }
 
@@ -85,23 +127,30 @@ pub struct VerboseTradeState {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TradeMessage {
+    #[serde(default)]
+    pub seq: Option<u64>,
     pub id: u64,
     pub timestamp: f64, // unix epoch timestamp,
     pub market: String,
     pub base: String,
     pub quote: String,
+    #[serde(with = "decimal_compact")]
     pub price: Decimal,
+    #[serde(with = "decimal_compact")]
     pub amount: Decimal,
+    #[serde(with = "decimal_compact")]
     pub quote_amount: Decimal,
 
     pub ask_user_id: u32,
     pub ask_order_id: u64,
     pub ask_role: MarketRole, // take/make
+    #[serde(with = "decimal_compact")]
     pub ask_fee: Decimal,
 
     pub bid_user_id: u32,
     pub bid_order_id: u64,
     pub bid_role: MarketRole,
+    #[serde(with = "decimal_compact")]
     pub bid_fee: Decimal,
 
     pub bid_order: Option<Order>,
@@ -112,11 +161,15 @@ pub struct TradeMessage {
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct BalanceMessage {
+    #[serde(default)]
+    pub seq: Option<u64>,
     pub timestamp: f64,
     pub user_id: u32,
     pub asset: String,
     pub business: String,
+    #[serde(with = "decimal_compact")]
     pub change: Decimal,
+    #[serde(with = "decimal_compact")]
     pub balance: Decimal,
     pub detail: String,
 }
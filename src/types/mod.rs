@@ -1,5 +1,8 @@
+pub mod amount;
 pub mod fixnum;
 pub mod l2;
+pub mod market;
 pub mod matchengine;
 pub mod merkle_tree;
 pub mod primitives;
+pub mod token;
@@ -96,6 +96,9 @@ pub fn vec_to_fr(arr: &[u8]) -> Result<Fr> {
 pub fn fr_to_u32(f: &Fr) -> u32 {
     fr_to_string(f).parse::<u32>().unwrap()
 }
+pub fn fr_to_u64(f: &Fr) -> u64 {
+    fr_to_string(f).parse::<u64>().unwrap()
+}
 pub fn fr_to_i64(f: &Fr) -> i64 {
     fr_to_string(f).parse::<i64>().unwrap()
 }
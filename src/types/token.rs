@@ -0,0 +1,120 @@
+use anyhow::{anyhow, bail};
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub name: String,
+    pub token_id: u32,
+    // decimals of amounts inside the rollup, may be less than the decimals on L1
+    pub decimals: u32,
+    // L1 contract address, empty for the native token
+    #[serde(default)]
+    pub address: String,
+}
+
+// TokenRegistry resolves token names and precisions. Tokens can be loaded from config,
+// or registered at runtime, but never changed once registered.
+#[derive(Debug, Clone)]
+pub struct TokenRegistry {
+    tokens: FnvHashMap<u32, TokenInfo>,
+    // name -> token_id
+    ids: FnvHashMap<String, u32>,
+}
+
+impl Default for TokenRegistry {
+    fn default() -> Self {
+        Self::from_tokens(default_tokens()).unwrap()
+    }
+}
+
+pub fn default_tokens() -> Vec<TokenInfo> {
+    vec![
+        TokenInfo {
+            name: "ETH".to_string(),
+            token_id: 0,
+            decimals: 6,
+            address: String::new(),
+        },
+        TokenInfo {
+            name: "USDT".to_string(),
+            token_id: 1,
+            decimals: 6,
+            address: String::new(),
+        },
+    ]
+}
+
+impl TokenRegistry {
+    pub fn empty() -> Self {
+        Self {
+            tokens: FnvHashMap::default(),
+            ids: FnvHashMap::default(),
+        }
+    }
+    pub fn from_tokens(tokens: Vec<TokenInfo>) -> anyhow::Result<Self> {
+        let mut registry = Self::empty();
+        for token in tokens {
+            registry.register(token)?;
+        }
+        Ok(registry)
+    }
+    // registering the same token again is a no-op, so replayed registrations are harmless
+    pub fn register(&mut self, token: TokenInfo) -> anyhow::Result<()> {
+        if let Some(existing) = self.tokens.get(&token.token_id) {
+            if *existing == token {
+                return Ok(());
+            }
+            bail!("token id {} already registered as {:?}", token.token_id, existing);
+        }
+        if let Some(token_id) = self.ids.get(&token.name) {
+            bail!("token {} already registered with id {}", token.name, token_id);
+        }
+        self.ids.insert(token.name.clone(), token.token_id);
+        self.tokens.insert(token.token_id, token);
+        Ok(())
+    }
+    pub fn token_id(&self, name: &str) -> anyhow::Result<u32> {
+        self.ids.get(name).cloned().ok_or_else(|| anyhow!("unknown token {}", name))
+    }
+    pub fn prec(&self, token_id: u32) -> anyhow::Result<u32> {
+        self.get(token_id).map(|token| token.decimals)
+    }
+    pub fn get(&self, token_id: u32) -> anyhow::Result<&TokenInfo> {
+        self.tokens.get(&token_id).ok_or_else(|| anyhow!("unknown token id {}", token_id))
+    }
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_token_registry() {
+    let mut registry = TokenRegistry::default();
+    assert_eq!(registry.token_id("ETH").unwrap(), 0);
+    assert_eq!(registry.prec(1).unwrap(), 6);
+    assert!(registry.token_id("BTC").is_err());
+
+    let btc = TokenInfo {
+        name: "BTC".to_string(),
+        token_id: 2,
+        decimals: 8,
+        address: "0x2260fac5e5542a773aa44fbcfedf7c193bc2c599".to_string(),
+    };
+    registry.register(btc.clone()).unwrap();
+    registry.register(btc.clone()).unwrap();
+    assert_eq!(registry.token_id("BTC").unwrap(), 2);
+    assert_eq!(registry.prec(2).unwrap(), 8);
+
+    let mut renamed = btc.clone();
+    renamed.name = "WBTC".to_string();
+    assert!(registry.register(renamed).is_err());
+    let mut moved = btc;
+    moved.token_id = 3;
+    assert!(registry.register(moved).is_err());
+    assert_eq!(registry.len(), 3);
+}
@@ -6,7 +6,7 @@ use std::path::{Path, PathBuf};
 // from https://github1s.com/Fluidex/circuits/blob/HEAD/test/binary_merkle_tree.ts
 mod test_case {
     use ff::{Field, PrimeField};
-    use rollup_state_manager::params;
+    use rollup_state_manager::config::Settings;
     use rollup_state_manager::state::block::Block;
     use rollup_state_manager::test_utils::{fr_to_string, Fr};
     use rollup_state_manager::test_utils::{CircuitSource, CircuitTestCase, CircuitTestData};
@@ -14,19 +14,18 @@ mod test_case {
     use serde_json::json;
 
     pub fn blocks() -> Vec<CircuitTestCase> {
+        let settings = Settings::from_env().unwrap();
+        let n_txs = settings.max_block_size();
         let main = format!(
             "Block({}, {}, {}, {})",
-            *params::NTXS,
-            *params::BALANCELEVELS,
-            *params::ORDERLEVELS,
-            *params::ACCOUNTLEVELS
+            n_txs, settings.balance_levels, settings.order_levels, settings.account_levels
         );
         let test_data = Block::new(
-            *params::NTXS,
-            *params::BALANCELEVELS,
-            *params::ORDERLEVELS,
-            *params::ACCOUNTLEVELS,
-            *params::VERBOSE,
+            n_txs,
+            settings.balance_levels,
+            settings.order_levels,
+            settings.account_levels,
+            settings.verbose,
         )
         .test_data();
         test_data
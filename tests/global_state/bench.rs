@@ -4,7 +4,7 @@ use anyhow::Result;
 use ethers::prelude::coins_bip39::English;
 use pprof::protos::Message;
 use rollup_state_manager::account::{self, Account};
-use rollup_state_manager::params;
+use rollup_state_manager::config::Settings;
 use rollup_state_manager::state::{GlobalState, WitnessGenerator};
 use rollup_state_manager::test_utils::messages::{parse_msg, WrappedMessage};
 use rollup_state_manager::types::l2;
@@ -28,21 +28,21 @@ fn bench_global_state(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
         .map(Result::unwrap)
         .map(parse_msg)
         .map(Result::unwrap)
-        .filter(|msg| matches!(msg, WrappedMessage::BALANCE(_) | WrappedMessage::ORDER(_)))
+        .filter(|msg| {
+            matches!(
+                msg,
+                WrappedMessage::BALANCE(_) | WrappedMessage::ORDER(_) | WrappedMessage::TOKEN(_)
+            )
+        })
         .collect();
 
     println!("prepare bench: {} records", messages.len());
 
     GlobalState::print_config();
-    let state = GlobalState::new(
-        *params::BALANCELEVELS,
-        *params::ORDERLEVELS,
-        *params::ACCOUNTLEVELS,
-        *params::VERBOSE,
-    );
+    let settings = Settings::from_env()?;
 
     //amplify the records: in each iter we run records on a group of new accounts
-    let mut processor = msg_processor::Processor::default();
+    let mut processor = msg_processor::Processor::new(settings.token_registry()?, settings.market_registry()?);
 
     // TODO: max(user id)
     let account_num = 10;
@@ -68,14 +68,14 @@ fn bench_global_state(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
         }
         for msg in messages.iter() {
             if let WrappedMessage::TRADE(trade) = msg {
-                processor.sign_orders(trade.clone());
+                processor.sign_orders(trade.clone()).unwrap();
             }
         }
     }
 
     let (sender, receiver) = crossbeam_channel::unbounded();
 
-    let mut witgen = WitnessGenerator::new(state, *params::NTXS, sender, *params::VERBOSE);
+    let mut witgen = WitnessGenerator::from_settings(&settings, sender);
 
     let timing = Instant::now();
     let mut inner_timing = Instant::now();
@@ -83,24 +83,29 @@ fn bench_global_state(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
     for i in 0..loop_num {
         let account_offset = i * account_num;
         for msg in messages.iter() {
-            match msg {
+            let result = match msg {
                 WrappedMessage::BALANCE(balance) => {
                     let mut balance = balance.clone();
                     balance.user_id += account_offset;
-                    processor.handle_balance_msg(&mut witgen, balance);
+                    processor.handle_balance_msg(&mut witgen, balance)
                 }
                 WrappedMessage::TRADE(trade) => {
                     let mut trade = trade.clone();
                     trade.ask_user_id += account_offset;
                     trade.bid_user_id += account_offset;
-                    processor.handle_trade_msg(&mut witgen, trade);
+                    processor.handle_trade_msg(&mut witgen, trade)
                 }
                 WrappedMessage::ORDER(order) => {
                     let mut order = order.clone();
                     order.order.user += account_offset;
-                    processor.handle_order_msg(&mut witgen, order);
+                    processor.handle_order_msg(&mut witgen, order)
                 }
+                WrappedMessage::TOKEN(token) => processor.handle_token_msg(&witgen, token.clone()),
                 _ => unreachable!(),
+            };
+            // messages breaking the configured token and market rules are reported and skipped
+            if let Err(e) = result {
+                eprintln!("reject message: {}", e);
             }
         }
 
@@ -117,12 +122,13 @@ fn bench_global_state(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
             inner_timing = Instant::now();
         }
     }
+    let tx_num = witgen.get_tx_generate_num();
     drop(witgen); // to close sender
     let blocks: Vec<_> = receiver.iter().collect();
     println!(
         "bench for {} blocks (TPS: {})",
         blocks.len(),
-        (*params::NTXS * blocks.len()) as f32 / timing.elapsed().as_secs_f32()
+        tx_num as f32 / timing.elapsed().as_secs_f32()
     );
     Ok(blocks)
 }
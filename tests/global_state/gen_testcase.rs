@@ -5,8 +5,8 @@
 #![allow(clippy::unnecessary_wraps)]
 
 use anyhow::Result;
-use rollup_state_manager::params;
-use rollup_state_manager::state::{GlobalState, WitnessGenerator};
+use rollup_state_manager::config::Settings;
+use rollup_state_manager::state::WitnessGenerator;
 use rollup_state_manager::test_utils;
 use rollup_state_manager::test_utils::l2::L2Block;
 use rollup_state_manager::test_utils::messages::WrappedMessage;
@@ -15,82 +15,83 @@ use std::path::PathBuf;
 use std::time::Instant;
 
 mod export_circuit;
-use rollup_state_manager::msg::{self, msg_consumer, msg_loader, msg_processor};
+use rollup_state_manager::msg::{self, msg_consumer, msg_processor, msg_source};
 
 fn replay_msgs(
-    msg_receiver: crossbeam_channel::Receiver<WrappedMessage>,
+    msg_receiver: crossbeam_channel::Receiver<(WrappedMessage, Option<msg_source::MsgPosition>)>,
     block_sender: crossbeam_channel::Sender<L2Block>,
+    settings: Settings,
 ) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
     Some(std::thread::spawn(move || {
-        let state = GlobalState::new(
-            *params::BALANCELEVELS,
-            *params::ORDERLEVELS,
-            *params::ACCOUNTLEVELS,
-            *params::VERBOSE,
-        );
-        let mut witgen = WitnessGenerator::new(state, *params::NTXS, block_sender, *params::VERBOSE);
+        let mut witgen = WitnessGenerator::from_settings(&settings, block_sender);
 
         println!("genesis root {}", witgen.root());
 
-        let mut processor = msg_processor::Processor::default();
+        let mut processor = msg_processor::Processor::new(settings.token_registry()?, settings.market_registry()?);
 
         let timing = Instant::now();
-        for msg in msg_receiver.iter() {
-            match msg {
-                WrappedMessage::BALANCE(balance) => {
-                    processor.handle_balance_msg(&mut witgen, balance);
-                }
+        // messages breaking the configured token and market rules are reported and skipped
+        let mut rejected = 0;
+        for (msg, _) in msg_receiver.iter() {
+            let result = match msg {
+                WrappedMessage::BALANCE(balance) => processor.handle_balance_msg(&mut witgen, balance),
                 WrappedMessage::TRADE(trade) => {
                     let trade_id = trade.id;
-                    processor.handle_trade_msg(&mut witgen, trade);
-                    println!("trade {} test done", trade_id);
-                }
-                WrappedMessage::ORDER(order) => {
-                    processor.handle_order_msg(&mut witgen, order);
-                }
-                _ => {
-                    //other msg is omitted
+                    let result = processor.handle_trade_msg(&mut witgen, trade);
+                    if result.is_ok() {
+                        println!("trade {} test done", trade_id);
+                    }
+                    result
                 }
+                WrappedMessage::ORDER(order) => processor.handle_order_msg(&mut witgen, order),
+                WrappedMessage::TOKEN(token) => processor.handle_token_msg(&witgen, token),
+            };
+            if let Err(e) = result {
+                eprintln!("reject message: {}", e);
+                rejected += 1;
             }
         }
-        witgen.flush_with_nop();
+        witgen.force_seal();
         let block_num = witgen.get_block_generate_num();
         if let Ok(path) = std::env::var("SLED_DB_PATH") {
             let db = sled::open(&path).unwrap();
             witgen.dump_to_sled(&db);
         }
         println!(
-            "genesis {} blocks (TPS: {})",
+            "genesis {} blocks (TPS: {}), {} messages rejected",
             block_num,
-            (*params::NTXS * block_num) as f32 / timing.elapsed().as_secs_f32()
+            witgen.get_tx_generate_num() as f32 / timing.elapsed().as_secs_f32(),
+            rejected
         );
         Ok(())
     }))
 }
 
 pub fn run(src: &str) -> Result<()> {
+    let settings = Settings::from_env()?;
     let circuit_repo = fs::canonicalize(PathBuf::from("circuits")).expect("invalid circuits repo path");
     let filepath = PathBuf::from(src);
     let (msg_sender, msg_receiver) = crossbeam_channel::unbounded();
     let (blk_sender, blk_receiver) = crossbeam_channel::unbounded();
 
-    let loader_thread = msg_loader::load_msgs_from_file(&filepath.to_str().unwrap(), msg_sender);
+    let source = msg_source::LineSource::open(filepath.to_str().unwrap())?;
+    let loader_thread = msg_source::spawn_source(Box::new(source), msg_sender);
 
-    let replay_thread = replay_msgs(msg_receiver, blk_sender);
+    let replay_thread = replay_msgs(msg_receiver, blk_sender, settings.clone());
 
     let blocks: Vec<_> = blk_receiver.iter().collect();
 
-    loader_thread.map(|h| h.join().expect("loader thread failed"));
+    loader_thread.join().expect("loader thread failed")?;
     replay_thread.map(|h| h.join().expect("replay thread failed"));
 
     let component = test_utils::circuit::CircuitSource {
         src: String::from("src/block.circom"),
         main: format!(
             "Block({}, {}, {}, {})",
-            *params::NTXS,
-            *params::BALANCELEVELS,
-            *params::ORDERLEVELS,
-            *params::ACCOUNTLEVELS
+            settings.max_block_size(),
+            settings.balance_levels,
+            settings.order_levels,
+            settings.account_levels
         ),
     };
 